@@ -0,0 +1,104 @@
+//! Benchmark of backend selection by many threads at once.
+//!
+//! Compares the lock-free `LoadBalancer` with the previous implementation, which selected
+//! backends in round-robin behind mutexes. The previous implementation is reproduced here
+//! without its logging, as neither logs on the path of every request anymore.
+//!
+//! Run with `cargo bench --bench load_balancer`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use flusso::proxy::load_balancer::LoadBalancer;
+use flusso::proxy::strategy::LoadBalancing;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Threads selecting backends at the same time.
+const THREADS: usize = 16;
+
+/// Backends in the benchmarked pool.
+const BACKENDS: u16 = 8;
+
+/// Round-robin selection behind mutexes, as done before selection became lock-free.
+struct MutexLoadBalancer {
+    backends: Mutex<Vec<SocketAddr>>,
+    current_index: Mutex<usize>,
+    down: Mutex<HashSet<SocketAddr>>,
+    ejected_until: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl MutexLoadBalancer {
+    fn new(backends: Vec<SocketAddr>) -> Self {
+        Self {
+            backends: Mutex::new(backends),
+            current_index: Mutex::new(0),
+            down: Mutex::new(HashSet::new()),
+            ejected_until: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn select_backend(&self) -> Option<SocketAddr> {
+        let backends = self.backends.lock().unwrap();
+        let down = self.down.lock().unwrap();
+        let ejected_until = self.ejected_until.lock().unwrap();
+        let now = Instant::now();
+        let available = |backend: &SocketAddr| {
+            !down.contains(backend) && ejected_until.get(backend).is_none_or(|until| now >= *until)
+        };
+        let mut index = self.current_index.lock().unwrap();
+        let offset = (0..backends.len()).find(|offset| available(&backends[(*index + offset) % backends.len()]))?;
+        let position = (*index + offset) % backends.len();
+        *index = (position + 1) % backends.len();
+        Some(backends[position])
+    }
+}
+
+fn backends() -> Vec<SocketAddr> {
+    (1..=BACKENDS).map(|port| SocketAddr::from(([10, 0, 0, 1], port))).collect()
+}
+
+/// Runs `select` `iters` times on each of the threads at once.
+///
+/// # Returns
+/// The time from the start of the threads to the end of the last one.
+fn concurrently(iters: u64, select: impl Fn() -> Option<SocketAddr> + Sync) -> Duration {
+    let barrier = Barrier::new(THREADS + 1);
+    let started = thread::scope(|scope| {
+        for _ in 0..THREADS {
+            scope.spawn(|| {
+                barrier.wait();
+                for _ in 0..iters {
+                    black_box(select());
+                }
+            });
+        }
+        barrier.wait();
+        Instant::now()
+    });
+    started.elapsed()
+}
+
+fn select_backend(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("select_backend/{}_threads", THREADS));
+    group.throughput(Throughput::Elements(THREADS as u64));
+
+    let mutex = MutexLoadBalancer::new(backends());
+    group.bench_function("mutex_round_robin", |b| b.iter_custom(|iters| concurrently(iters, || mutex.select_backend())));
+
+    let pool = LoadBalancer::new(backends());
+    group.bench_function("round_robin", |b| b.iter_custom(|iters| concurrently(iters, || pool.select_backend())));
+    for (name, load_balancing) in [
+        ("weighted_round_robin", LoadBalancing::WeightedRoundRobin),
+        ("least_requests", LoadBalancing::LeastRequests),
+        ("ewma", LoadBalancing::Ewma),
+        ("random", LoadBalancing::Random),
+    ] {
+        group.bench_function(name, |b| b.iter_custom(|iters| concurrently(iters, || pool.select(load_balancing))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, select_backend);
+criterion_main!(benches);
@@ -4,13 +4,39 @@ use serde::Deserialize;
 use config::{Config, ConfigError, Environment};
 use dotenv::dotenv;
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct Settings {
     pub server_addr: String,
     pub gui_port: Option<u16>,
     pub tls_enabled: bool,
+    /// Address of the HTTPS listener, used when `tls_enabled` is set.
+    pub https_addr: Option<String>,
     pub tls_cert_path: Option<String>,
     pub tls_key_path: Option<String>,
+    /// Service whose load balancer address is published into Ingress status, as `namespace/name`.
+    pub publish_service: Option<String>,
+    /// Comma-separated IPs or hostnames published into Ingress status instead of `publish_service`.
+    pub publish_status_address: Option<String>,
+    /// Name of the Lease used to elect the replica that writes to the Kubernetes API.
+    pub leader_election_lease: Option<String>,
+    /// Maximum size of a request body in bytes; larger requests are answered with 413.
+    pub max_body_size: Option<u64>,
+    /// Comma-separated IPs and CIDR networks of the proxies whose forwarding headers are trusted.
+    pub trusted_proxies: Option<String>,
+    /// Seconds without traffic after which an upgraded (WebSocket) connection is closed.
+    pub upgrade_idle_timeout: Option<u64>,
+    /// Server errors or refused connections in a row ejecting a backend; `0` disables ejection.
+    pub outlier_consecutive_errors: Option<u32>,
+    /// Seconds a backend is ejected for the first time, doubled at each new ejection.
+    pub outlier_base_ejection_time: Option<u64>,
+    /// Share of the backends of a pool that can be ejected at a time, in percent.
+    pub outlier_max_ejection_percent: Option<u8>,
+    /// Port serving the Prometheus metrics on `/metrics`.
+    pub metrics_port: Option<u16>,
+    /// Service port receiving requests that match no Ingress, as `namespace/service:port`.
+    pub default_backend: Option<String>,
+    /// Most bytes of backend responses kept by the cache of the Ingresses enabling it.
+    pub cache_size: Option<usize>,
 }
 
 impl Settings {
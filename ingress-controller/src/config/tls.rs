@@ -2,7 +2,7 @@
 
 use std::fs::File;
 use std::io::{self, BufReader};
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs1KeyDer};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use rustls_pemfile::{certs, rsa_private_keys};
 
 pub struct TlsConfig<'a> {
@@ -27,7 +27,7 @@ fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
     let certs = certs(&mut reader).collect::<Result<Vec<_>, _>>()
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid certificate"))?;
     
-    Ok(certs.into_iter().map(CertificateDer::from).collect())
+    Ok(certs)
 }
 
 
@@ -40,8 +40,7 @@ fn load_private_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid private key"))?;
     
     if let Some(key) = keys.into_iter().next() {
-        let pkcs1_key = PrivatePkcs1KeyDer::from(key);
-        Ok(PrivateKeyDer::from(pkcs1_key))
+        Ok(PrivateKeyDer::from(key))
     } else {
         Err(io::Error::new(io::ErrorKind::InvalidData, "No private key found"))
     }
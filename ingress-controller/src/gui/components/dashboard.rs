@@ -1,6 +1,5 @@
 use yew::prelude::*;
 use reqwest::Client;
-use serde_json::json;
 
 #[function_component(Dashboard)]
 pub fn dashboard() -> Html {
@@ -1,10 +1,9 @@
 use yew::prelude::*;
 use reqwest::Client;
-use serde_json::json;
 
 #[function_component(Routes)]
 pub fn routes() -> Html {
-    let routes = use_state(|| Vec::new()); // Usamos use_state para mantener las rutas
+    let routes = use_state(Vec::new); // Usamos use_state para mantener las rutas
 
     {
         let routes = routes.clone();
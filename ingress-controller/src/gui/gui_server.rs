@@ -1,16 +1,21 @@
 use actix_web::{web, App, HttpServer, HttpResponse, Responder};
-use std::sync::Arc;
-use crate::proxy::load_balancer::LoadBalancer;
+use crate::proxy::router::SharedRouter;
 use serde_json::json;
+use tokio::sync::watch;
 
 /// Endpoint para obtener la lista de Ingresses
-/// En este caso, utilizamos `get_backends()` para obtener la lista de backends y los tratamos como Ingresses.
-async fn get_ingresses(data: web::Data<Arc<LoadBalancer>>) -> impl Responder {
-    // Usar `get_backends()` para obtener los datos
-    let ingresses = data.get_ref().get_backends().iter().map(|backend| {
+/// Se obtienen a partir de las rutas registradas en la tabla de enrutamiento.
+async fn get_ingresses(data: web::Data<SharedRouter>) -> impl Responder {
+    let router = data.load();
+    let mut keys = router.routes().map(|route| route.ingress.clone()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+
+    let ingresses = keys.iter().map(|key| {
+        let (namespace, name) = key.split_once('/').unwrap_or(("default", key));
         json!( {
-            "name": backend.to_string(),  // Convertimos el `SocketAddr` a cadena para mostrarlo
-            "namespace": "default" // Asumimos un namespace por defecto
+            "name": name,
+            "namespace": namespace
         })
     }).collect::<Vec<_>>();
     
@@ -19,13 +24,19 @@ async fn get_ingresses(data: web::Data<Arc<LoadBalancer>>) -> impl Responder {
 }
 
 /// Endpoint para obtener la lista de Routes
-/// Usamos `get_backends()` y tratamos los backends como rutas.
-async fn get_routes(data: web::Data<Arc<LoadBalancer>>) -> impl Responder {
-    let routes = data.get_ref().get_backends().iter().map(|backend| {
+/// Cada ruta incluye su host, su path y los backends de su pool.
+async fn get_routes(data: web::Data<SharedRouter>) -> impl Responder {
+    let router = data.load();
+    let routes = router.routes().map(|route| {
+        let backends = router.get_pool(&route.backend)
+            .map(|pool| pool.get_backends().iter().map(|b| b.to_string()).collect::<Vec<_>>())
+            .unwrap_or_default();
         json!( {
-            "name": backend.to_string(),  // Convertimos el `SocketAddr` a cadena
-            "protocol": "HTTP",  // Asumimos el protocolo como HTTP
-            "port": 80  // Usamos el puerto 80 como ejemplo
+            "name": format!("{}{}", route.host.as_deref().unwrap_or("*"), route.path),
+            "ingress": route.ingress,
+            "backend": route.backend,
+            "backends": backends,
+            "protocol": "HTTP"  // Asumimos el protocolo como HTTP
         })
     }).collect::<Vec<_>>();
     
@@ -41,13 +52,31 @@ async fn index() -> impl Responder {
         .body(include_str!("./static/index.html"))  // Asegúrate de tener el archivo index.html en la ruta correcta.
 }
 
+/// Endpoint de liveness: responde siempre 200 mientras el proceso atiende peticiones.
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+/// Endpoint de readiness: responde 503 hasta que la sincronización inicial con el clúster
+/// termina y las rutas de los Ingresses existentes están aplicadas.
+async fn readyz(ready: web::Data<watch::Receiver<bool>>) -> impl Responder {
+    if *ready.borrow() {
+        HttpResponse::Ok().body("ok")
+    } else {
+        HttpResponse::ServiceUnavailable().body("initial sync in progress")
+    }
+}
+
 /// Iniciar el servidor GUI con los endpoints adecuados
 /// Aquí el servidor usa Actix Web y se configura con las rutas para Ingresses, Routes y archivos estáticos.
-pub async fn start_gui_server(load_balancer: Arc<LoadBalancer>, port: u16) -> std::io::Result<()> {
+pub async fn start_gui_server(router: SharedRouter, ready: watch::Receiver<bool>, port: u16) -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(load_balancer.clone()))  // Pasa la instancia compartida de LoadBalancer
+            .app_data(web::Data::new(router.clone()))  // Pasa la tabla de enrutamiento compartida
+            .app_data(web::Data::new(ready.clone()))  // Estado de readiness del controlador
             .route("/", web::get().to(index))  // Página principal con el Dashboard
+            .route("/healthz", web::get().to(healthz))  // Liveness probe
+            .route("/readyz", web::get().to(readyz))  // Readiness probe
             .route("/api/ingresses", web::get().to(get_ingresses))  // Endpoint para obtener los Ingresses
             .route("/api/routes", web::get().to(get_routes))  // Endpoint para obtener los Routes
             .service(actix_files::Files::new("/static", "./static").show_files_listing())  // Archivos estáticos (CSS, JS, imágenes)
@@ -7,9 +7,31 @@ use tokio::time::timeout;
 use log::{info, error};
 
 /// Configuración para los parámetros de health check de los backends.
+#[derive(Clone, Debug, PartialEq)]
 pub struct HealthCheckConfig {
+    /// Ruta que se solicita a cada backend, como `/healthz`.
+    pub path: String,
+    /// Tiempo entre dos comprobaciones de un mismo backend.
+    pub interval: Duration,
     pub timeout_duration: Duration,
     pub retry_count: u8,
+    /// Comprobaciones exitosas seguidas para volver a marcar sano un backend caído.
+    pub healthy_threshold: u32,
+    /// Comprobaciones fallidas seguidas para marcar caído un backend.
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            interval: Duration::from_secs(10),
+            timeout_duration: Duration::from_secs(2),
+            retry_count: 1,
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
 }
 
 /// Realiza una comprobación de salud en un backend especificado por su URL.
@@ -2,5 +2,7 @@
 // src/health_check/mod.rs
 
 pub mod backend_checker;
+pub mod scheduler;
 
-pub use backend_checker::check_backend_health;
+pub use backend_checker::{check_backend_health, HealthCheckConfig};
+pub use scheduler::HealthChecker;
@@ -0,0 +1,200 @@
+//! Scheduler running the active health checks of backend pools.
+//!
+//! The `HealthChecker` follows the routing table: every address of a pool whose Ingress
+//! enables a health check gets a probe task, requesting the health check path at the
+//! configured interval. An address failing `unhealthy_threshold` checks in a row is marked
+//! down in its `LoadBalancer`, and marked up again after `healthy_threshold` successful
+//! checks in a row. Probes stop when the address leaves the pool or the check is disabled.
+
+use crate::health_check::backend_checker::{check_backend_health, HealthCheckConfig};
+use crate::proxy::load_balancer::LoadBalancer;
+use crate::proxy::router::SharedRouter;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+/// Interval between two comparisons of the running probes with the routing table.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A running probe of a backend address.
+struct Probe {
+    pool: Arc<LoadBalancer>,
+    config: HealthCheckConfig,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Probe {
+    /// Stops probing the address.
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Runs the active health checks of the backend pools in the routing table.
+pub struct HealthChecker {
+    router: SharedRouter,
+    client: Client,
+    /// Running probes, keyed by backend key and address.
+    probes: HashMap<(String, SocketAddr), Probe>,
+}
+
+impl HealthChecker {
+    /// Creates a health checker following a routing table.
+    ///
+    /// # Parameters
+    /// - `router`: The routing table holding the backend pools and their health checks.
+    pub fn new(router: SharedRouter) -> Self {
+        Self {
+            router,
+            client: Client::new(),
+            probes: HashMap::new(),
+        }
+    }
+
+    /// Keeps the probes in line with the routing table, forever.
+    pub async fn run(mut self) {
+        let mut ticks = interval(RECONCILE_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.reconcile();
+        }
+    }
+
+    /// Starts the probes of new addresses and stops those of addresses that left their pool
+    /// or whose health check was disabled or changed.
+    fn reconcile(&mut self) {
+        let mut wanted = HashMap::new();
+        for (backend, pool, config) in self.router.load().health_checks() {
+            for addr in pool.get_backends() {
+                wanted.insert((backend.clone(), addr), (pool.clone(), config.clone()));
+            }
+        }
+
+        self.probes.retain(|(backend, addr), probe| {
+            let keep = wanted.get(&(backend.clone(), *addr)).is_some_and(|(_, config)| *config == probe.config);
+            if !keep && probe.pool.mark_up(*addr) {
+                println!("Backend {} of {} is no longer health checked, marked up", addr, backend);
+            }
+            keep
+        });
+
+        for (key, (pool, config)) in wanted {
+            if self.probes.contains_key(&key) {
+                continue;
+            }
+            let (backend, addr) = key.clone();
+            println!("Health checking backend {} of {} on {}", addr, backend, config.path);
+            let task = tokio::spawn(probe(self.client.clone(), pool.clone(), backend, addr, config.clone()));
+            self.probes.insert(key, Probe { pool, config, task });
+        }
+    }
+}
+
+/// Checks a backend address at the configured interval, marking it down and up in its pool.
+async fn probe(client: Client, pool: Arc<LoadBalancer>, backend: String, addr: SocketAddr, config: HealthCheckConfig) {
+    let url = format!("http://{}{}", addr, config.path);
+    let mut ticks = interval(config.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let (mut successes, mut failures) = (0, 0);
+
+    loop {
+        ticks.tick().await;
+        if check_backend_health(&client, &url, &config).await {
+            successes += 1;
+            failures = 0;
+            if successes >= config.healthy_threshold && pool.mark_up(addr) {
+                println!("Backend {} of {} passed {} health checks, marked up", addr, backend, successes);
+            }
+        } else {
+            failures += 1;
+            successes = 0;
+            if failures >= config.unhealthy_threshold && pool.mark_down(addr) {
+                println!("Backend {} of {} failed {} health checks, marked down", addr, backend, failures);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::router::{IngressOptions, PathType, Route, Router};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Starts a backend answering 200 while `healthy` is set, and 503 otherwise.
+    async fn backend(healthy: Arc<AtomicBool>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buffer = vec![0; 4096];
+                let _ = socket.read(&mut buffer).await;
+                let response: &[u8] = match healthy.load(Ordering::Relaxed) {
+                    true => b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    false => b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                };
+                let _ = socket.write_all(response).await;
+            }
+        });
+        addr
+    }
+
+    /// Waits until a backend is marked down or up, failing after a second.
+    async fn wait_until_up(pool: &LoadBalancer, addr: SocketAddr, up: bool) {
+        for _ in 0..100 {
+            if pool.is_up(&addr) == up {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("backend {} never became {}", addr, if up { "up" } else { "down" });
+    }
+
+    #[tokio::test]
+    async fn test_marks_backends_down_and_up() {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let addr = backend(healthy.clone()).await;
+        let mut router = Router::new();
+        router.set_ingress_routes("default/web", vec![Route {
+            ingress: "default/web".to_string(),
+            host: None,
+            path: "/".to_string(),
+            path_type: PathType::Prefix,
+            backend: "default/web:80".to_string(),
+        }], None);
+        let pool = router.pool("default/web:80");
+        pool.set_backends(vec![addr]);
+        let config = HealthCheckConfig {
+            path: "/healthz".to_string(),
+            interval: Duration::from_millis(10),
+            healthy_threshold: 2,
+            unhealthy_threshold: 2,
+            ..Default::default()
+        };
+        router.set_ingress_options("default/web", IngressOptions { health_check: Some(config), ..Default::default() });
+        let router = SharedRouter::new(router);
+        let mut checker = HealthChecker::new(router.clone());
+
+        checker.reconcile();
+        wait_until_up(&pool, addr, false).await;
+        assert_eq!(pool.select_backend(), None);
+
+        healthy.store(true, Ordering::Relaxed);
+        wait_until_up(&pool, addr, true).await;
+        assert_eq!(pool.select_backend(), Some(addr));
+
+        // Disabling the check stops the probe and leaves the backend up
+        healthy.store(false, Ordering::Relaxed);
+        wait_until_up(&pool, addr, false).await;
+        router.update(|router| router.set_ingress_options("default/web", IngressOptions::default()));
+        checker.reconcile();
+        assert!(checker.probes.is_empty());
+        assert!(pool.is_up(&addr));
+    }
+}
@@ -0,0 +1,375 @@
+//! Annotations module reading the proxy options of an Ingress from its annotations.
+//!
+//! Annotations under the `flusso.io/` prefix tune how the requests routed by an Ingress
+//! are proxied, and enable active health checks of their backends. Invalid values are
+//! reported and the default is used instead.
+
+use k8s_openapi::api::networking::v1::Ingress;
+use crate::health_check::HealthCheckConfig;
+use crate::proxy::affinity::{AffinityCookie, HashKey, SessionAffinity};
+use crate::proxy::router::{IngressOptions, UpstreamProtocol};
+use crate::proxy::strategy::LoadBalancing;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Annotation choosing whether backends receive the client's `Host` header (`"true"`,
+/// the default) or the backend address (`"false"`).
+pub const PRESERVE_HOST_ANNOTATION: &str = "flusso.io/preserve-host";
+
+/// Annotation choosing the protocol spoken to backends: `HTTP` (HTTP/1.1, the default),
+/// `HTTP2` or `GRPC` (both HTTP/2 over cleartext).
+pub const BACKEND_PROTOCOL_ANNOTATION: &str = "flusso.io/backend-protocol";
+
+/// Annotation choosing how requests are balanced across backends: `round-robin` (the
+/// default), `weighted-round-robin`, `least-requests`, `ewma` (the best of two random
+/// backends by latency and requests in flight) or `random`.
+pub const LOAD_BALANCE_ANNOTATION: &str = "flusso.io/load-balance";
+
+/// Annotation weighing the backends of zones under `weighted-round-robin`, as
+/// `zone=weight` pairs separated by commas. Backends of other zones weigh `1`.
+pub const ZONE_WEIGHTS_ANNOTATION: &str = "flusso.io/zone-weights";
+
+/// Annotation setting the seconds backends added to the Service take to ramp up from a
+/// tenth to their full share of the requests.
+pub const SLOW_START_ANNOTATION: &str = "flusso.io/slow-start";
+
+/// Annotation keeping clients on the backend named by an affinity cookie the proxy sets,
+/// when set to `cookie`.
+pub const AFFINITY_ANNOTATION: &str = "flusso.io/affinity";
+
+/// Annotation setting the name of the affinity cookie.
+pub const SESSION_COOKIE_NAME_ANNOTATION: &str = "flusso.io/session-cookie-name";
+
+/// Annotation setting the seconds the affinity cookie lasts, instead of the browser session.
+pub const SESSION_COOKIE_MAX_AGE_ANNOTATION: &str = "flusso.io/session-cookie-max-age";
+
+/// Annotation keeping clients on a backend by consistent hashing of `client-ip`,
+/// `header:<name>` or `cookie:<name>`.
+pub const UPSTREAM_HASH_BY_ANNOTATION: &str = "flusso.io/upstream-hash-by";
+
+/// Annotation answering requests from the shared cache of responses when set to `"true"`,
+/// storing the responses of the backends there as their `Cache-Control` headers allow.
+pub const CACHE_ANNOTATION: &str = "flusso.io/cache";
+
+/// Annotation setting the seconds cached responses can be served stale, while revalidated
+/// or when the backend fails, unless their `Cache-Control` header tells.
+pub const CACHE_STALE_ANNOTATION: &str = "flusso.io/cache-stale";
+
+/// Annotation enabling active health checks of the backends, requesting this path.
+pub const HEALTH_CHECK_PATH_ANNOTATION: &str = "flusso.io/health-check-path";
+
+/// Annotation setting the seconds between two health checks of a backend.
+pub const HEALTH_CHECK_INTERVAL_ANNOTATION: &str = "flusso.io/health-check-interval";
+
+/// Annotation setting the seconds a health check waits for the backend response.
+pub const HEALTH_CHECK_TIMEOUT_ANNOTATION: &str = "flusso.io/health-check-timeout";
+
+/// Annotation setting the successful health checks in a row marking a backend up again.
+pub const HEALTH_CHECK_HEALTHY_THRESHOLD_ANNOTATION: &str = "flusso.io/health-check-healthy-threshold";
+
+/// Annotation setting the failed health checks in a row marking a backend down.
+pub const HEALTH_CHECK_UNHEALTHY_THRESHOLD_ANNOTATION: &str = "flusso.io/health-check-unhealthy-threshold";
+
+/// Reads the proxy options of an Ingress from its annotations.
+///
+/// # Returns
+/// The options, along with a description of every invalid annotation.
+pub fn ingress_options(ingress: &Ingress) -> (IngressOptions, Vec<String>) {
+    let mut options = IngressOptions::default();
+    let mut invalid = Vec::new();
+    let annotation = |name: &str| {
+        ingress.metadata.annotations.as_ref().and_then(|annotations| annotations.get(name))
+    };
+
+    if let Some(value) = annotation(PRESERVE_HOST_ANNOTATION) {
+        match value.parse::<bool>() {
+            Ok(preserve_host) => options.preserve_host = preserve_host,
+            Err(_) => invalid.push(invalid_value(PRESERVE_HOST_ANNOTATION, value, "true or false")),
+        }
+    }
+
+    if let Some(value) = annotation(BACKEND_PROTOCOL_ANNOTATION) {
+        match value.to_ascii_uppercase().as_str() {
+            "HTTP" => options.upstream_protocol = UpstreamProtocol::Http1,
+            "HTTP2" | "GRPC" => options.upstream_protocol = UpstreamProtocol::Http2,
+            _ => invalid.push(invalid_value(BACKEND_PROTOCOL_ANNOTATION, value, "HTTP, HTTP2 or GRPC")),
+        }
+    }
+
+    if let Some(value) = annotation(LOAD_BALANCE_ANNOTATION) {
+        match value.to_ascii_lowercase().as_str() {
+            "round-robin" => options.load_balancing = LoadBalancing::RoundRobin,
+            "weighted-round-robin" => options.load_balancing = LoadBalancing::WeightedRoundRobin,
+            "least-requests" => options.load_balancing = LoadBalancing::LeastRequests,
+            "ewma" => options.load_balancing = LoadBalancing::Ewma,
+            "random" => options.load_balancing = LoadBalancing::Random,
+            _ => invalid.push(invalid_value(
+                LOAD_BALANCE_ANNOTATION,
+                value,
+                "round-robin, weighted-round-robin, least-requests, ewma or random",
+            )),
+        }
+    }
+
+    if let Some(value) = annotation(ZONE_WEIGHTS_ANNOTATION) {
+        match zone_weights(value) {
+            Some(weights) => options.zone_weights = weights,
+            None => invalid.push(invalid_value(ZONE_WEIGHTS_ANNOTATION, value, "zone=weight pairs with positive weights")),
+        }
+    }
+
+    if let Some(value) = annotation(SLOW_START_ANNOTATION) {
+        match value.parse::<u32>() {
+            Ok(seconds) if seconds > 0 => options.slow_start = Some(Duration::from_secs(seconds.into())),
+            _ => invalid.push(invalid_value(SLOW_START_ANNOTATION, value, "a positive number of seconds")),
+        }
+    }
+
+    if let Some(value) = annotation(CACHE_ANNOTATION) {
+        match value.parse::<bool>() {
+            Ok(cache) => options.cache = cache,
+            Err(_) => invalid.push(invalid_value(CACHE_ANNOTATION, value, "true or false")),
+        }
+    }
+
+    if let Some(value) = annotation(CACHE_STALE_ANNOTATION) {
+        match value.parse::<u32>() {
+            Ok(seconds) if seconds > 0 => options.cache_stale = Some(Duration::from_secs(seconds.into())),
+            _ => invalid.push(invalid_value(CACHE_STALE_ANNOTATION, value, "a positive number of seconds")),
+        }
+    }
+
+    let mut affinity_cookie = AffinityCookie::default();
+    if let Some(name) = annotation(SESSION_COOKIE_NAME_ANNOTATION) {
+        match is_token(name) {
+            true => affinity_cookie.name = name.clone(),
+            false => invalid.push(invalid_value(SESSION_COOKIE_NAME_ANNOTATION, name, "a cookie name")),
+        }
+    }
+    if let Some(value) = annotation(SESSION_COOKIE_MAX_AGE_ANNOTATION) {
+        match value.parse::<u32>() {
+            Ok(seconds) if seconds > 0 => affinity_cookie.max_age = Some(Duration::from_secs(seconds.into())),
+            _ => invalid.push(invalid_value(SESSION_COOKIE_MAX_AGE_ANNOTATION, value, "a positive number of seconds")),
+        }
+    }
+    if let Some(value) = annotation(AFFINITY_ANNOTATION) {
+        match value.eq_ignore_ascii_case("cookie") {
+            true => options.affinity = Some(SessionAffinity::Cookie(affinity_cookie)),
+            false => invalid.push(invalid_value(AFFINITY_ANNOTATION, value, "cookie")),
+        }
+    }
+    if let Some(value) = annotation(UPSTREAM_HASH_BY_ANNOTATION) {
+        let key = match value.split_once(':') {
+            None if value.eq_ignore_ascii_case("client-ip") => Some(HashKey::ClientIp),
+            Some((kind, name)) if kind.eq_ignore_ascii_case("header") && is_token(name) => {
+                Some(HashKey::Header(name.to_ascii_lowercase()))
+            }
+            Some((kind, name)) if kind.eq_ignore_ascii_case("cookie") && is_token(name) => {
+                Some(HashKey::Cookie(name.to_string()))
+            }
+            _ => None,
+        };
+        match (key, &options.affinity) {
+            (Some(_), Some(_)) => invalid.push(format!(
+                "Annotation {} is ignored, as {} already keeps clients on their backend",
+                UPSTREAM_HASH_BY_ANNOTATION, AFFINITY_ANNOTATION,
+            )),
+            (Some(key), None) => options.affinity = Some(SessionAffinity::Hash(key)),
+            (None, _) => invalid.push(invalid_value(
+                UPSTREAM_HASH_BY_ANNOTATION,
+                value,
+                "client-ip, header:<name> or cookie:<name>",
+            )),
+        }
+    }
+
+    let mut health_check = HealthCheckConfig::default();
+    let mut positive = |name: &str, expected: &str| -> Option<u32> {
+        let value = annotation(name)?;
+        let parsed = value.parse::<u32>().ok().filter(|parsed| *parsed > 0);
+        if parsed.is_none() {
+            invalid.push(invalid_value(name, value, expected));
+        }
+        parsed
+    };
+    if let Some(seconds) = positive(HEALTH_CHECK_INTERVAL_ANNOTATION, "a positive number of seconds") {
+        health_check.interval = Duration::from_secs(seconds.into());
+    }
+    if let Some(seconds) = positive(HEALTH_CHECK_TIMEOUT_ANNOTATION, "a positive number of seconds") {
+        health_check.timeout_duration = Duration::from_secs(seconds.into());
+    }
+    if let Some(threshold) = positive(HEALTH_CHECK_HEALTHY_THRESHOLD_ANNOTATION, "a positive number") {
+        health_check.healthy_threshold = threshold;
+    }
+    if let Some(threshold) = positive(HEALTH_CHECK_UNHEALTHY_THRESHOLD_ANNOTATION, "a positive number") {
+        health_check.unhealthy_threshold = threshold;
+    }
+    if let Some(path) = annotation(HEALTH_CHECK_PATH_ANNOTATION) {
+        match path.starts_with('/') {
+            true => options.health_check = Some(HealthCheckConfig { path: path.clone(), ..health_check }),
+            false => invalid.push(invalid_value(HEALTH_CHECK_PATH_ANNOTATION, path, "a path starting with /")),
+        }
+    }
+
+    (options, invalid)
+}
+
+/// Returns `true` if a value can name a header or a cookie (an RFC 7230 token).
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Parses `zone=weight` pairs separated by commas, whose weights are positive.
+fn zone_weights(value: &str) -> Option<BTreeMap<String, u32>> {
+    value.split(',')
+        .map(|pair| {
+            let (zone, weight) = pair.split_once('=')?;
+            let weight = weight.trim().parse::<u32>().ok().filter(|weight| *weight > 0)?;
+            Some((zone.trim().to_string(), weight)).filter(|(zone, _)| !zone.is_empty())
+        })
+        .collect()
+}
+
+/// Describes an annotation holding an invalid value.
+fn invalid_value(annotation: &str, value: &str, expected: &str) -> String {
+    format!("Annotation {} has invalid value {:?}, expected {}", annotation, value, expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingress(annotations: &[(&str, &str)]) -> Ingress {
+        let mut ingress = Ingress::default();
+        ingress.metadata.annotations = Some(
+            annotations.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<BTreeMap<_, _>>(),
+        );
+        ingress
+    }
+
+    #[test]
+    fn test_ingress_options() {
+        assert_eq!(ingress_options(&Ingress::default()), (IngressOptions::default(), Vec::new()));
+
+        let (options, invalid) = ingress_options(&ingress(&[(PRESERVE_HOST_ANNOTATION, "false")]));
+        assert!(!options.preserve_host);
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[(PRESERVE_HOST_ANNOTATION, "no")]));
+        assert!(options.preserve_host);
+        assert_eq!(invalid.len(), 1);
+
+        let (options, invalid) = ingress_options(&ingress(&[(BACKEND_PROTOCOL_ANNOTATION, "grpc")]));
+        assert_eq!(options.upstream_protocol, UpstreamProtocol::Http2);
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[
+            (BACKEND_PROTOCOL_ANNOTATION, "FCGI"),
+            (PRESERVE_HOST_ANNOTATION, "false"),
+        ]));
+        assert_eq!(options, IngressOptions { preserve_host: false, ..Default::default() });
+        assert_eq!(invalid.len(), 1);
+
+        let (options, invalid) = ingress_options(&ingress(&[(LOAD_BALANCE_ANNOTATION, "EWMA")]));
+        assert_eq!(options.load_balancing, LoadBalancing::Ewma);
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[(LOAD_BALANCE_ANNOTATION, "fastest")]));
+        assert_eq!(options.load_balancing, LoadBalancing::RoundRobin);
+        assert_eq!(invalid.len(), 1);
+
+        let (options, invalid) = ingress_options(&ingress(&[(SLOW_START_ANNOTATION, "30")]));
+        assert_eq!(options.slow_start, Some(Duration::from_secs(30)));
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[(CACHE_ANNOTATION, "true")]));
+        assert!(options.cache);
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[(CACHE_STALE_ANNOTATION, "120")]));
+        assert_eq!(options.cache_stale, Some(Duration::from_secs(120)));
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[(SLOW_START_ANNOTATION, "0")]));
+        assert_eq!(options.slow_start, None);
+        assert_eq!(invalid.len(), 1);
+
+        let (options, invalid) = ingress_options(&ingress(&[(ZONE_WEIGHTS_ANNOTATION, "zone-a=3, zone-b=1")]));
+        assert_eq!(options.zone_weights, BTreeMap::from([("zone-a".to_string(), 3), ("zone-b".to_string(), 1)]));
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[(ZONE_WEIGHTS_ANNOTATION, "zone-a=3, zone-b=0")]));
+        assert!(options.zone_weights.is_empty());
+        assert_eq!(invalid.len(), 1);
+    }
+
+    #[test]
+    fn test_affinity_options() {
+        let (options, invalid) = ingress_options(&ingress(&[
+            (AFFINITY_ANNOTATION, "cookie"),
+            (SESSION_COOKIE_NAME_ANNOTATION, "route"),
+            (SESSION_COOKIE_MAX_AGE_ANNOTATION, "3600"),
+        ]));
+        assert_eq!(options.affinity, Some(SessionAffinity::Cookie(AffinityCookie {
+            name: "route".to_string(),
+            max_age: Some(Duration::from_secs(3600)),
+        })));
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[(UPSTREAM_HASH_BY_ANNOTATION, "header:X-User")]));
+        assert_eq!(options.affinity, Some(SessionAffinity::Hash(HashKey::Header("x-user".to_string()))));
+        assert!(invalid.is_empty());
+
+        let (options, _) = ingress_options(&ingress(&[(UPSTREAM_HASH_BY_ANNOTATION, "client-ip")]));
+        assert_eq!(options.affinity, Some(SessionAffinity::Hash(HashKey::ClientIp)));
+
+        // Invalid cookie names fall back to the default, and hashing is ignored with cookie affinity
+        let (options, invalid) = ingress_options(&ingress(&[
+            (AFFINITY_ANNOTATION, "cookie"),
+            (SESSION_COOKIE_NAME_ANNOTATION, "a;b"),
+            (UPSTREAM_HASH_BY_ANNOTATION, "cookie:session"),
+        ]));
+        assert_eq!(options.affinity, Some(SessionAffinity::Cookie(AffinityCookie::default())));
+        assert_eq!(invalid.len(), 2);
+
+        let (options, invalid) = ingress_options(&ingress(&[
+            (AFFINITY_ANNOTATION, "ip"),
+            (UPSTREAM_HASH_BY_ANNOTATION, "header:"),
+        ]));
+        assert_eq!(options.affinity, None);
+        assert_eq!(invalid.len(), 2);
+    }
+
+    #[test]
+    fn test_health_check_options() {
+        let (options, invalid) = ingress_options(&ingress(&[
+            (HEALTH_CHECK_PATH_ANNOTATION, "/healthz"),
+            (HEALTH_CHECK_INTERVAL_ANNOTATION, "5"),
+            (HEALTH_CHECK_UNHEALTHY_THRESHOLD_ANNOTATION, "1"),
+        ]));
+        assert_eq!(options.health_check, Some(HealthCheckConfig {
+            path: "/healthz".to_string(),
+            interval: Duration::from_secs(5),
+            unhealthy_threshold: 1,
+            ..Default::default()
+        }));
+        assert!(invalid.is_empty());
+
+        // Health checks are only enabled by a path
+        let (options, invalid) = ingress_options(&ingress(&[(HEALTH_CHECK_INTERVAL_ANNOTATION, "5")]));
+        assert_eq!(options.health_check, None);
+        assert!(invalid.is_empty());
+
+        let (options, invalid) = ingress_options(&ingress(&[
+            (HEALTH_CHECK_PATH_ANNOTATION, "/healthz"),
+            (HEALTH_CHECK_TIMEOUT_ANNOTATION, "0"),
+            (HEALTH_CHECK_HEALTHY_THRESHOLD_ANNOTATION, "often"),
+        ]));
+        assert_eq!(options.health_check, Some(HealthCheckConfig { path: "/healthz".to_string(), ..Default::default() }));
+        assert_eq!(invalid.len(), 2);
+
+        let (options, invalid) = ingress_options(&ingress(&[(HEALTH_CHECK_PATH_ANNOTATION, "healthz")]));
+        assert_eq!(options.health_check, None);
+        assert_eq!(invalid.len(), 1);
+    }
+}
@@ -0,0 +1,353 @@
+//! Endpoints module tracking the pods behind the Services referenced by Ingresses.
+//!
+//! The `Endpoints` struct keeps the `EndpointSlice` objects of every Service, and
+//! resolves the ready pod addresses serving a given Service port, so that traffic is
+//! balanced across pods directly instead of going through the Service ClusterIP. The
+//! zone of each pod address is also kept, to weigh the pods by zone.
+
+use k8s_openapi::api::core::v1::{Service, ServicePort};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use k8s_openapi::api::networking::v1::{Ingress, IngressServiceBackend, ServiceBackendPort};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+
+/// Label set by Kubernetes on an `EndpointSlice` to name the Service it belongs to.
+pub const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+/// A Service port referenced by an Ingress backend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackendRef {
+    /// Key of the backend pool fed by this Service port, as `namespace/service:port`.
+    pub key: String,
+    pub namespace: String,
+    pub service: String,
+    /// Name of the Service port, `None` for the unnamed port of a single-port Service.
+    pub port_name: Option<String>,
+}
+
+/// The `EndpointSlice` objects of every Service, kept up to date from watch events.
+#[derive(Clone, Debug, Default)]
+pub struct Endpoints {
+    /// Slices keyed by `namespace/service`, then by slice name.
+    slices: Arc<RwLock<HashMap<String, HashMap<String, EndpointSlice>>>>,
+}
+
+impl Endpoints {
+    /// Creates an empty endpoints cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an added or updated `EndpointSlice`.
+    ///
+    /// # Returns
+    /// The `namespace/service` key of the Service the slice belongs to, if any.
+    pub fn apply(&self, slice: &EndpointSlice) -> Option<String> {
+        let (service_key, slice_name) = slice_keys(slice)?;
+        self.slices.write().unwrap()
+            .entry(service_key.clone())
+            .or_default()
+            .insert(slice_name, slice.clone());
+        Some(service_key)
+    }
+
+    /// Forgets a deleted `EndpointSlice`.
+    ///
+    /// # Returns
+    /// The `namespace/service` key of the Service the slice belonged to, if any.
+    pub fn delete(&self, slice: &EndpointSlice) -> Option<String> {
+        let (service_key, slice_name) = slice_keys(slice)?;
+        let mut slices = self.slices.write().unwrap();
+        if let Some(service_slices) = slices.get_mut(&service_key) {
+            service_slices.remove(&slice_name);
+            if service_slices.is_empty() {
+                slices.remove(&service_key);
+            }
+        }
+        Some(service_key)
+    }
+
+    /// Replaces every known `EndpointSlice`, e.g. after the watch re-listed them.
+    pub fn reset<'a>(&self, slices: impl IntoIterator<Item = &'a EndpointSlice>) {
+        self.slices.write().unwrap().clear();
+        for slice in slices {
+            self.apply(slice);
+        }
+    }
+
+    /// Resolves the addresses of the ready endpoints serving a Service port.
+    ///
+    /// The port number of each address is the target port of the pods, taken from the
+    /// slice port named like the Service port.
+    pub fn resolve(&self, backend: &BackendRef) -> Vec<SocketAddr> {
+        let slices = self.slices.read().unwrap();
+        let Some(service_slices) = slices.get(&service_key(&backend.namespace, &backend.service)) else {
+            return Vec::new();
+        };
+
+        let mut addresses: Vec<SocketAddr> = service_slices
+            .values()
+            .flat_map(|slice| slice_addresses(slice, backend.port_name.as_deref()))
+            .map(|(address, _)| address)
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+
+    /// Returns the zone of the ready endpoints serving a Service port, for those
+    /// reporting one.
+    pub fn zones(&self, backend: &BackendRef) -> HashMap<SocketAddr, String> {
+        let slices = self.slices.read().unwrap();
+        let Some(service_slices) = slices.get(&service_key(&backend.namespace, &backend.service)) else {
+            return HashMap::new();
+        };
+
+        service_slices
+            .values()
+            .flat_map(|slice| slice_addresses(slice, backend.port_name.as_deref()))
+            .filter_map(|(address, zone)| Some((address, zone?.to_string())))
+            .collect()
+    }
+}
+
+/// Finds the port of a Service referenced by an Ingress backend port, by name or number.
+pub fn find_service_port<'a>(service: &'a Service, port: &ServiceBackendPort) -> Option<&'a ServicePort> {
+    let ports = service.spec.as_ref()?.ports.as_ref()?;
+    match (&port.name, port.number) {
+        (Some(name), _) => ports.iter().find(|service_port| service_port.name.as_ref() == Some(name)),
+        (None, Some(number)) => ports.iter().find(|service_port| service_port.port == number),
+        (None, None) => None,
+    }
+}
+
+/// Parses a Service port written as `namespace/service:port`, the port being a name or a number.
+///
+/// # Returns
+/// The namespace and the Service port as an Ingress backend, or `None` if malformed.
+pub fn parse_service_backend(value: &str) -> Option<(String, IngressServiceBackend)> {
+    let (namespace, service) = value.trim().split_once('/')?;
+    let (name, port) = service.split_once(':')?;
+    if namespace.is_empty() || name.is_empty() || port.is_empty() {
+        return None;
+    }
+
+    let port = match port.parse::<i32>() {
+        Ok(number) => ServiceBackendPort { name: None, number: Some(number) },
+        Err(_) => ServiceBackendPort { name: Some(port.to_string()), number: None },
+    };
+    Some((namespace.to_string(), IngressServiceBackend { name: name.to_string(), port: Some(port) }))
+}
+
+/// Returns the `namespace/service` key of a Service.
+pub fn service_key(namespace: &str, service: &str) -> String {
+    format!("{}/{}", namespace, service)
+}
+
+/// Returns the `namespace/service` keys of the Services an Ingress routes to, from its
+/// rules and its default backend.
+pub fn ingress_services(ingress: &Ingress) -> HashSet<String> {
+    let namespace = ingress.metadata.namespace.as_deref().unwrap_or_default();
+    let Some(spec) = &ingress.spec else { return HashSet::new() };
+    let paths = spec.rules.iter().flatten()
+        .filter_map(|rule| rule.http.as_ref())
+        .flat_map(|http| &http.paths)
+        .map(|path| &path.backend);
+
+    spec.default_backend.iter()
+        .chain(paths)
+        .filter_map(|backend| backend.service.as_ref())
+        .map(|service| service_key(namespace, &service.name))
+        .collect()
+}
+
+/// Returns the Service key and the name of an `EndpointSlice`.
+fn slice_keys(slice: &EndpointSlice) -> Option<(String, String)> {
+    let namespace = slice.metadata.namespace.as_deref().unwrap_or_default();
+    let service = slice.metadata.labels.as_ref()?.get(SERVICE_NAME_LABEL)?;
+    Some((service_key(namespace, service), slice.metadata.name.clone()?))
+}
+
+/// Returns the addresses of the ready endpoints of a slice for a Service port name, along
+/// with their zone.
+fn slice_addresses<'a>(slice: &'a EndpointSlice, port_name: Option<&str>) -> Vec<(SocketAddr, Option<&'a str>)> {
+    // Unnamed ports may be reported with either no name or an empty one
+    let port = slice.ports.iter().flatten()
+        .find(|port| port.name.as_deref().filter(|name| !name.is_empty()) == port_name)
+        .and_then(|port| port.port)
+        .and_then(|port| u16::try_from(port).ok());
+    let Some(port) = port else { return Vec::new() };
+
+    slice.endpoints.iter()
+        // A missing ready condition must be interpreted as ready
+        .filter(|endpoint| endpoint.conditions.as_ref().and_then(|c| c.ready).unwrap_or(true))
+        .flat_map(|endpoint| endpoint.addresses.iter().map(|address| (address, endpoint.zone.as_deref())))
+        .filter_map(|(address, zone)| Some((SocketAddr::new(address.parse::<IpAddr>().ok()?, port), zone)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::core::v1::ServiceSpec;
+    use k8s_openapi::api::discovery::v1::{Endpoint, EndpointConditions, EndpointPort};
+    use k8s_openapi::api::networking::v1::{
+        HTTPIngressPath, HTTPIngressRuleValue, IngressBackend, IngressRule, IngressSpec,
+    };
+    use std::collections::BTreeMap;
+
+    fn slice(name: &str, ports: &[(Option<&str>, i32)], endpoints: &[(&str, Option<bool>)]) -> EndpointSlice {
+        let mut slice = EndpointSlice {
+            address_type: "IPv4".to_string(),
+            endpoints: endpoints.iter().map(|(address, ready)| Endpoint {
+                addresses: vec![address.to_string()],
+                conditions: Some(EndpointConditions { ready: *ready, ..Default::default() }),
+                ..Default::default()
+            }).collect(),
+            ports: Some(ports.iter().map(|(name, port)| EndpointPort {
+                name: name.map(str::to_string),
+                port: Some(*port),
+                ..Default::default()
+            }).collect()),
+            ..Default::default()
+        };
+        slice.metadata.name = Some(name.to_string());
+        slice.metadata.namespace = Some("default".to_string());
+        slice.metadata.labels = Some(BTreeMap::from([
+            (SERVICE_NAME_LABEL.to_string(), "web".to_string()),
+        ]));
+        slice
+    }
+
+    fn backend(port_name: Option<&str>) -> BackendRef {
+        BackendRef {
+            key: "default/web:80".to_string(),
+            namespace: "default".to_string(),
+            service: "web".to_string(),
+            port_name: port_name.map(str::to_string),
+        }
+    }
+
+    fn addrs(addresses: &[&str]) -> Vec<SocketAddr> {
+        addresses.iter().map(|address| address.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_resolves_ready_endpoints_by_port_name() {
+        let endpoints = Endpoints::new();
+        let service = endpoints.apply(&slice(
+            "web-abc",
+            &[(Some("http"), 8080), (Some("metrics"), 9090)],
+            &[("10.0.0.1", Some(true)), ("10.0.0.2", None), ("10.0.0.3", Some(false))],
+        ));
+        endpoints.apply(&slice("web-def", &[(Some("http"), 8080)], &[("10.0.1.1", Some(true))]));
+
+        assert_eq!(service.as_deref(), Some("default/web"));
+        assert_eq!(
+            endpoints.resolve(&backend(Some("http"))),
+            addrs(&["10.0.0.1:8080", "10.0.0.2:8080", "10.0.1.1:8080"])
+        );
+        assert_eq!(endpoints.resolve(&backend(Some("metrics"))), addrs(&["10.0.0.1:9090", "10.0.0.2:9090"]));
+        assert!(endpoints.resolve(&backend(Some("grpc"))).is_empty());
+    }
+
+    #[test]
+    fn test_reports_the_zones_of_endpoints() {
+        let endpoints = Endpoints::new();
+        let mut web = slice("web-abc", &[(Some("http"), 8080)], &[("10.0.0.1", Some(true)), ("10.0.0.2", Some(true))]);
+        web.endpoints[0].zone = Some("zone-a".to_string());
+        endpoints.apply(&web);
+
+        let zones = endpoints.zones(&backend(Some("http")));
+        assert_eq!(zones, HashMap::from([("10.0.0.1:8080".parse().unwrap(), "zone-a".to_string())]));
+    }
+
+    #[test]
+    fn test_resolves_unnamed_port_and_deleted_slices() {
+        let endpoints = Endpoints::new();
+        let web = slice("web-abc", &[(Some(""), 3000)], &[("10.0.0.1", Some(true))]);
+        endpoints.apply(&web);
+
+        assert_eq!(endpoints.resolve(&backend(None)), addrs(&["10.0.0.1:3000"]));
+
+        endpoints.delete(&web);
+        assert!(endpoints.resolve(&backend(None)).is_empty());
+    }
+
+    #[test]
+    fn test_reset_drops_vanished_slices() {
+        let endpoints = Endpoints::new();
+        endpoints.apply(&slice("web-abc", &[(Some("http"), 8080)], &[("10.0.0.1", Some(true))]));
+
+        endpoints.reset(&[slice("web-def", &[(Some("http"), 8080)], &[("10.0.1.1", Some(true))])]);
+        assert_eq!(endpoints.resolve(&backend(Some("http"))), addrs(&["10.0.1.1:8080"]));
+    }
+
+    #[test]
+    fn test_finds_service_port_by_name_or_number() {
+        let service = Service {
+            spec: Some(ServiceSpec {
+                ports: Some(vec![
+                    ServicePort { name: Some("http".to_string()), port: 8080, ..Default::default() },
+                    ServicePort { name: Some("metrics".to_string()), port: 9090, ..Default::default() },
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let by_name = |name: &str| ServiceBackendPort { name: Some(name.to_string()), number: None };
+        let by_number = |number: i32| ServiceBackendPort { name: None, number: Some(number) };
+
+        assert_eq!(find_service_port(&service, &by_name("http")).map(|port| port.port), Some(8080));
+        assert_eq!(find_service_port(&service, &by_number(9090)).and_then(|port| port.name.as_deref()), Some("metrics"));
+        assert!(find_service_port(&service, &by_name("grpc")).is_none());
+        assert!(find_service_port(&service, &by_number(80)).is_none());
+    }
+
+    #[test]
+    fn test_parses_service_backend() {
+        let (namespace, backend) = parse_service_backend("flusso/fallback:8080").unwrap();
+        assert_eq!(namespace, "flusso");
+        assert_eq!(backend.name, "fallback");
+        assert_eq!(backend.port, Some(ServiceBackendPort { name: None, number: Some(8080) }));
+
+        let (_, backend) = parse_service_backend("flusso/fallback:http").unwrap();
+        assert_eq!(backend.port, Some(ServiceBackendPort { name: Some("http".to_string()), number: None }));
+
+        assert!(parse_service_backend("fallback:80").is_none());
+        assert!(parse_service_backend("flusso/fallback").is_none());
+        assert!(parse_service_backend("flusso/:80").is_none());
+    }
+
+    #[test]
+    fn test_lists_the_services_of_an_ingress() {
+        let backend = |name: &str| IngressBackend {
+            service: Some(IngressServiceBackend { name: name.to_string(), port: None }),
+            ..Default::default()
+        };
+        let path = |name: &str| HTTPIngressPath { backend: backend(name), path_type: "Prefix".to_string(), ..Default::default() };
+        let mut ingress = Ingress {
+            spec: Some(IngressSpec {
+                default_backend: Some(backend("fallback")),
+                rules: Some(vec![
+                    IngressRule {
+                        http: Some(HTTPIngressRuleValue { paths: vec![path("web"), path("api")] }),
+                        ..Default::default()
+                    },
+                    IngressRule {
+                        http: Some(HTTPIngressRuleValue { paths: vec![path("web")] }),
+                        ..Default::default()
+                    },
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        ingress.metadata.namespace = Some("shop".to_string());
+
+        let expected = ["shop/fallback", "shop/web", "shop/api"].map(str::to_string);
+        assert_eq!(ingress_services(&ingress), HashSet::from(expected));
+        assert!(ingress_services(&Ingress::default()).is_empty());
+    }
+}
@@ -1,156 +1,744 @@
 //! Event listener module for watching Kubernetes Ingress resources.
 //!
 //! The `EventListener` struct monitors Ingress resources in a Kubernetes cluster,
-//! listening for additions and removals of Ingresses, and updating the load balancer accordingly.
+//! listening for additions and removals of Ingresses, and translating their rules into
+//! routing table updates sent to the `IngressProcessor`. It also watches `IngressClass`
+//! objects to decide which Ingresses belong to flusso, `Service` objects to resolve the
+//! ports Ingresses reference, `EndpointSlice` objects to keep the backend pools filled
+//! with the ready pods of each referenced Service, and `kubernetes.io/tls` Secrets to serve
+//! the certificates named in Ingress `spec.tls`.
+//!
+//! Each kind is watched through a reflector store, with backoff when the watch fails.
+//! Ingresses are only routed once every store completed its initial list, and whenever a
+//! watch re-lists its objects, the full state is reconciled: routes of Ingresses deleted in
+//! the meantime are removed. The Ingresses referencing a Service are reprocessed when it
+//! changes, and all Ingresses are also reprocessed periodically. Problems with an Ingress
+//! are published as `Warning` Events when they appear, not again at every reprocessing.
 
-use kube::{api::{Api, ListParams}, Client};
+use kube::{api::Api, Client};
+use kube_runtime::reflector::{self, ObjectRef, Store};
 use kube_runtime::watcher::{watcher, Config, Event as KubeEvent};
-use tokio::sync::mpsc;
+use kube_runtime::WatchStreamExt;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use k8s_openapi::api::core::v1::{Secret, Service};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use k8s_openapi::api::networking::v1::{Ingress, IngressClass, IngressServiceBackend};
+use crate::ingress_controller::annotations::ingress_options;
+use crate::ingress_controller::endpoints::{
+    self, find_service_port, ingress_services, BackendRef, Endpoints, SERVICE_NAME_LABEL,
+};
+use crate::ingress_controller::events::{
+    EventRecorder, REASON_INVALID_ANNOTATION, REASON_PORT_NOT_FOUND, REASON_SECRET_NOT_FOUND,
+    REASON_SERVICE_NOT_FOUND,
+};
+use crate::ingress_controller::ingress_class::IngressClasses;
 use crate::ingress_controller::ingress_processor::IngressEvent;
-use crate::proxy::load_balancer::LoadBalancer;
-use futures_util::{StreamExt, pin_mut};
+use crate::ingress_controller::status::StatusPublisher;
+use crate::metrics::Metrics;
+use crate::proxy::router::{PathType, Route};
+use crate::tls::sni::{SniResolver, TLS_SECRET_TYPE};
+use futures_util::{stream, Stream, StreamExt, pin_mut};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+/// Interval between two full reprocessings of every Ingress.
+const RESYNC_PERIOD: Duration = Duration::from_secs(300);
+
+/// A problem with an Ingress: the reason and note of its `Warning` Event.
+type Problem = (&'static str, String);
+
+/// An event from one of the watched resource kinds.
+#[allow(clippy::large_enum_variant)]
+enum WatchEvent {
+    Ingress(kube_runtime::watcher::Result<KubeEvent<Ingress>>),
+    IngressClass(kube_runtime::watcher::Result<KubeEvent<IngressClass>>),
+    Service(kube_runtime::watcher::Result<KubeEvent<Service>>),
+    EndpointSlice(kube_runtime::watcher::Result<KubeEvent<EndpointSlice>>),
+    Secret(kube_runtime::watcher::Result<KubeEvent<Secret>>),
+    /// This replica gained (`true`) or lost (`false`) leadership.
+    Leadership(bool),
+    /// The periodic resync is due.
+    Resync,
+}
+
+/// The reflector stores of the watched resource kinds.
+struct Stores {
+    ingresses: Store<Ingress>,
+    ingress_classes: Store<IngressClass>,
+    services: Store<Service>,
+    endpoint_slices: Store<EndpointSlice>,
+    secrets: Store<Secret>,
+}
+
+/// The resource kinds whose watch completed its initial list.
+#[derive(Default)]
+struct InitialSync {
+    ingresses: bool,
+    ingress_classes: bool,
+    services: bool,
+    endpoint_slices: bool,
+    secrets: bool,
+}
 
-/// Listens for Kubernetes Ingress events and sends updates to the load balancer.
-#[derive(Clone, Debug)]
+impl InitialSync {
+    /// Returns `true` once every watch completed its initial list.
+    fn done(&self) -> bool {
+        self.ingresses && self.ingress_classes && self.services && self.endpoint_slices && self.secrets
+    }
+}
+
+/// Listens for Kubernetes Ingress events and sends routing table updates.
+#[derive(Clone)]
 pub struct EventListener {
     pub event_channel: mpsc::Sender<IngressEvent>,
-    pub load_balancer: Arc<LoadBalancer>,
+    client: Client,
+    recorder: EventRecorder,
+    status: StatusPublisher,
+    /// Leadership state of this replica.
+    leader: watch::Receiver<bool>,
+    /// Ingress classes handled by flusso.
+    pub ingress_classes: IngressClasses,
+    /// Endpoint slices of the Services in the cluster.
+    pub endpoints: Endpoints,
+    /// Certificates of the TLS Secrets, selected by SNI on the HTTPS listener.
+    sni: Arc<SniResolver>,
+    /// Service ports referenced by each claimed Ingress, keyed by `namespace/name`.
+    backends: Arc<Mutex<HashMap<String, Vec<BackendRef>>>>,
+    /// Namespace and Service port of the controller-wide default backend, as configured.
+    default_backend_spec: Option<(String, IngressServiceBackend)>,
+    /// The controller-wide default backend, once resolved.
+    default_backend: Arc<Mutex<Option<BackendRef>>>,
+    /// Counters of reconciliations and watch failures.
+    metrics: Arc<Metrics>,
+    /// Problems last reported for each claimed Ingress, keyed by `namespace/name`.
+    reported: Arc<Mutex<HashMap<String, HashSet<Problem>>>>,
 }
 
 impl EventListener {
     /// Creates a new `EventListener` instance and returns it along with an event receiver.
     ///
     /// # Parameters
-    /// - `load_balancer`: Shared `LoadBalancer` to manage backend distribution.
+    /// - `client`: Kubernetes client used to watch resources and publish Events.
+    /// - `status`: Publisher of the controller address into Ingress status.
+    /// - `leader`: Leadership state of this replica.
+    /// - `default_backend`: Namespace and Service port of the controller-wide default backend.
+    /// - `sni`: Certificate resolver of the HTTPS listener, fed from Ingress `spec.tls`.
+    /// - `metrics`: Metrics counting reconciliations and watch failures.
     ///
     /// # Returns
     /// A tuple with `EventListener` and a receiver for `IngressEvent`s.
-    pub fn new(load_balancer: Arc<LoadBalancer>) -> (Self, mpsc::Receiver<IngressEvent>) {
+    pub fn new(
+        client: Client,
+        status: StatusPublisher,
+        leader: watch::Receiver<bool>,
+        default_backend: Option<(String, IngressServiceBackend)>,
+        sni: Arc<SniResolver>,
+        metrics: Arc<Metrics>,
+    ) -> (Self, mpsc::Receiver<IngressEvent>) {
         let (tx, rx) = mpsc::channel(32);
         (
             Self {
                 event_channel: tx,
-                load_balancer,
+                recorder: EventRecorder::new(client.clone(), leader.clone()),
+                status,
+                leader,
+                client,
+                ingress_classes: IngressClasses::new(),
+                endpoints: Endpoints::new(),
+                sni,
+                backends: Arc::new(Mutex::new(HashMap::new())),
+                default_backend_spec: default_backend,
+                default_backend: Arc::new(Mutex::new(None)),
+                metrics,
+                reported: Arc::new(Mutex::new(HashMap::new())),
             },
             rx,
         )
     }
 
-    /// Starts listening for Kubernetes Ingress events, updating the load balancer.
+    /// Starts listening for Kubernetes Ingress events, updating the routing table.
+    ///
+    /// Watch failures are retried with backoff and errors processing a single object are
+    /// logged, so the listener only stops when the `IngressProcessor` is gone.
     ///
     /// # Returns
-    /// - `Ok(())` if the listener starts successfully.
-    /// - `Err` if there are issues during listening or processing.
+    /// - `Ok(())` if the watch streams end.
+    /// - `Err` if events can no longer be sent to the processor.
     pub async fn start_listening(&self) -> Result<(), Box<dyn Error>> {
-        let client = Client::try_default().await.expect("Failed to create Kubernetes client");
-        let ingresses: Api<k8s_openapi::api::networking::v1::Ingress> = Api::all(client);
+        let ingresses: Api<Ingress> = Api::all(self.client.clone());
+        let ingress_classes: Api<IngressClass> = Api::all(self.client.clone());
+        let services: Api<Service> = Api::all(self.client.clone());
+        let endpoint_slices: Api<EndpointSlice> = Api::all(self.client.clone());
+        let secrets: Api<Secret> = Api::all(self.client.clone());
+        let slice_config = Config::default().labels(SERVICE_NAME_LABEL);
+        let secret_config = Config::default().fields(&format!("type={}", TLS_SECRET_TYPE));
 
-        // Load existing Ingresses at startup
-        if let Ok(ingress_list) = ingresses.list(&ListParams::default()).await {
-            for ingress in ingress_list {
-                self.process_ingress(ingress).await?;
-            }
-        }
+        let (ingress_store, ingress_writer) = reflector::store();
+        let (class_store, class_writer) = reflector::store();
+        let (service_store, service_writer) = reflector::store();
+        let (slice_store, slice_writer) = reflector::store();
+        let (secret_store, secret_writer) = reflector::store();
+        let stores = Stores {
+            ingresses: ingress_store,
+            ingress_classes: class_store,
+            services: service_store,
+            endpoint_slices: slice_store,
+            secrets: secret_store,
+        };
 
-        // Continuous listening for changes in Ingress
-        let config = Config::default();
-        let watcher_stream = watcher(ingresses, config);
+        // Continuous listening for changes in Ingress, IngressClass, Service, EndpointSlice and Secret
+        let watcher_stream = stream::select_all([
+            watcher(ingresses, Config::default())
+                .default_backoff()
+                .reflect(ingress_writer)
+                .map(WatchEvent::Ingress)
+                .boxed(),
+            watcher(ingress_classes, Config::default())
+                .default_backoff()
+                .reflect(class_writer)
+                .map(WatchEvent::IngressClass)
+                .boxed(),
+            watcher(services, Config::default())
+                .default_backoff()
+                .reflect(service_writer)
+                .map(WatchEvent::Service)
+                .boxed(),
+            watcher(endpoint_slices, slice_config)
+                .default_backoff()
+                .reflect(slice_writer)
+                .map(WatchEvent::EndpointSlice)
+                .boxed(),
+            watcher(secrets, secret_config)
+                .default_backoff()
+                .reflect(secret_writer)
+                .map(WatchEvent::Secret)
+                .boxed(),
+            leadership_changes(self.leader.clone()).boxed(),
+            resync_ticks().boxed(),
+        ]);
         pin_mut!(watcher_stream);
 
+        let mut initial_sync = InitialSync::default();
         while let Some(event) = watcher_stream.next().await {
-            match event {
-                Ok(KubeEvent::Apply(ingress)) => self.process_ingress(ingress).await?,
-                Ok(KubeEvent::Delete(ingress)) => self.remove_ingress(ingress).await?,
-                _ => println!("Other event received, ignored"),
+            if let Err(e) = self.handle_event(event, &stores, &mut initial_sync).await {
+                if self.event_channel.is_closed() {
+                    return Err(e);
+                }
+                eprintln!("Error handling watch event: {}", e);
             }
         }
 
         Ok(())
     }
 
-    /// Processes an Ingress event, adding the backend to the load balancer if it meets criteria.
-    async fn process_ingress(&self, ingress: k8s_openapi::api::networking::v1::Ingress) -> Result<(), Box<dyn Error>> {
-        if let Some(annotations) = &ingress.metadata.annotations {
-            if let Some(class) = annotations.get("kubernetes.io/ingress.class") {
-                if class == "flusso" {
-                    if let Some(host) = ingress.spec.as_ref().and_then(|spec| {
-                        spec.rules.as_ref()?.get(0)?.host.clone()
-                    }) {
-                        let host = host.to_string();
-
-                        if let Some(service_name) = ingress.spec.as_ref().and_then(|spec| {
-                            Some(spec.rules.as_ref()?.get(0)?
-                                .http.as_ref()?.paths.get(0)?
-                                .backend.service.as_ref()?.name.clone())
-                        }) {
-                            let service_namespace = ingress.metadata.namespace.clone().unwrap_or_default();
-                            let service_key = format!("{}:{}", service_name, service_namespace);
-                            println!("Detected host '{}', associated with service: {}", host, service_key);
-
-                            // Resolve service IP and register it with the LoadBalancer
-                            if let Ok(service_ip) = self.resolve_service_ip(&service_name, &service_namespace).await {
-                                let backend_addr = format!("{}:80", service_ip).parse().unwrap();
-                                self.load_balancer.add_backend(backend_addr);
-                                println!("Backend registered for {}: {}", host, backend_addr);
-                            } else {
-                                eprintln!("Failed to resolve IP for service {}", service_key);
-                            }
-                        }
-                    }
+    /// Applies a single watch event to the caches and the routing table.
+    ///
+    /// Ingress changes are ignored until every watch completed its initial list, at which
+    /// point all Ingresses are reconciled at once and the processor is told the routing
+    /// table is complete.
+    async fn handle_event(
+        &self,
+        event: WatchEvent,
+        stores: &Stores,
+        initial_sync: &mut InitialSync,
+    ) -> Result<(), Box<dyn Error>> {
+        let synced = initial_sync.done();
+        let relisted = matches!(
+            event,
+            WatchEvent::Ingress(Ok(KubeEvent::InitDone))
+                | WatchEvent::IngressClass(Ok(KubeEvent::InitDone))
+                | WatchEvent::Service(Ok(KubeEvent::InitDone))
+                | WatchEvent::EndpointSlice(Ok(KubeEvent::InitDone))
+                | WatchEvent::Secret(Ok(KubeEvent::InitDone))
+        );
+
+        match event {
+            WatchEvent::Ingress(Ok(KubeEvent::Apply(ingress))) if synced => {
+                let result = self.process_ingress(ingress, &stores.services).await;
+                self.metrics.reconciled(result.is_ok());
+                result?
+            }
+            WatchEvent::Ingress(Ok(KubeEvent::Delete(ingress))) if synced => self.remove_ingress(ingress_key(&ingress)).await?,
+            WatchEvent::Ingress(Ok(KubeEvent::InitDone)) => {
+                println!("Ingresses listed: {}", stores.ingresses.state().len());
+                initial_sync.ingresses = true;
+            }
+            WatchEvent::IngressClass(Ok(KubeEvent::Apply(class))) => {
+                let changed = self.ingress_classes.apply(&class);
+                if changed && synced {
+                    self.reconcile(stores).await?;
+                }
+            }
+            WatchEvent::IngressClass(Ok(KubeEvent::Delete(class))) => {
+                let changed = self.ingress_classes.delete(&class);
+                if changed && synced {
+                    self.reconcile(stores).await?;
+                }
+            }
+            WatchEvent::IngressClass(Ok(KubeEvent::InitDone)) => {
+                let classes = stores.ingress_classes.state();
+                println!("IngressClasses listed: {}", classes.len());
+                self.ingress_classes.reset(classes.iter().map(Arc::as_ref));
+                initial_sync.ingress_classes = true;
+            }
+            WatchEvent::Service(Ok(KubeEvent::Apply(service) | KubeEvent::Delete(service))) if synced => {
+                self.refresh_ingresses(&service, stores).await?;
+            }
+            WatchEvent::Service(Ok(KubeEvent::InitDone)) => {
+                println!("Services listed: {}", stores.services.state().len());
+                initial_sync.services = true;
+            }
+            WatchEvent::EndpointSlice(Ok(KubeEvent::Apply(slice))) => {
+                if let Some(service_key) = self.endpoints.apply(&slice) {
+                    self.refresh_service(&service_key).await?;
+                }
+            }
+            WatchEvent::EndpointSlice(Ok(KubeEvent::Delete(slice))) => {
+                if let Some(service_key) = self.endpoints.delete(&slice) {
+                    self.refresh_service(&service_key).await?;
                 }
             }
+            WatchEvent::EndpointSlice(Ok(KubeEvent::InitDone)) => {
+                let slices = stores.endpoint_slices.state();
+                println!("EndpointSlices listed: {}", slices.len());
+                self.endpoints.reset(slices.iter().map(Arc::as_ref));
+                initial_sync.endpoint_slices = true;
+            }
+            WatchEvent::Secret(Ok(KubeEvent::Apply(secret))) => {
+                // Handshakes pick up the new certificate right away
+                if let Err(e) = self.sni.apply_secret(&secret) {
+                    let namespace = secret.metadata.namespace.as_deref().unwrap_or_default();
+                    let name = secret.metadata.name.as_deref().unwrap_or_default();
+                    eprintln!("Ignoring TLS secret {}/{}: {}", namespace, name, e);
+                }
+            }
+            WatchEvent::Secret(Ok(KubeEvent::Delete(secret))) => self.sni.delete_secret(&secret),
+            WatchEvent::Secret(Ok(KubeEvent::InitDone)) => {
+                let secrets = stores.secrets.state();
+                println!("TLS Secrets listed: {}", secrets.len());
+                self.sni.reset_secrets(secrets.iter().map(Arc::as_ref));
+                initial_sync.secrets = true;
+            }
+            WatchEvent::Ingress(Err(e)) => self.watch_failed("Ingress", e),
+            WatchEvent::IngressClass(Err(e)) => self.watch_failed("IngressClass", e),
+            WatchEvent::Service(Err(e)) => self.watch_failed("Service", e),
+            WatchEvent::EndpointSlice(Err(e)) => self.watch_failed("EndpointSlice", e),
+            WatchEvent::Secret(Err(e)) => self.watch_failed("Secret", e),
+            WatchEvent::Leadership(true) if synced => {
+                // Statuses and Events were left untouched while following; publish them now
+                self.reported.lock().unwrap().clear();
+                self.reconcile(stores).await?;
+            }
+            WatchEvent::Resync if synced => {
+                println!("Resyncing all ingresses");
+                self.reconcile(stores).await?;
+            }
+            // Ingress and Service events before the initial sync, objects of an ongoing
+            // re-list and losing leadership need no action
+            _ => {}
+        }
+
+        // A watch finished listing: either the initial sync is complete, or a watch
+        // reconnected and objects may have changed or vanished in the meantime
+        if relisted && initial_sync.done() {
+            self.reconcile(stores).await?;
+            if !synced {
+                println!("Initial sync completed");
+                self.event_channel.send(IngressEvent::Synced).await?;
+            }
         }
         Ok(())
     }
 
-    /// Removes an Ingress event, deregistering the backend from the load balancer.
-    async fn remove_ingress(&self, ingress: k8s_openapi::api::networking::v1::Ingress) -> Result<(), Box<dyn Error>> {
-        if let Some(annotations) = &ingress.metadata.annotations {
-            if let Some(class) = annotations.get("kubernetes.io/ingress.class") {
-                if class == "flusso" {
-                    if let Some(host) = ingress.spec.as_ref().and_then(|spec| {
-                        spec.rules.as_ref()?.get(0)?.host.clone()
-                    }) {
-                        let host = host.to_string();
-
-                        if let Some(service_name) = ingress.spec.as_ref().and_then(|spec| {
-                            Some(spec.rules.as_ref()?.get(0)?
-                                .http.as_ref()?.paths.get(0)?
-                                .backend.service.as_ref()?.name.clone())
-                        }) {
-                            let service_namespace = ingress.metadata.namespace.clone().unwrap_or_default();
-
-                            if let Ok(service_ip) = self.resolve_service_ip(&service_name, &service_namespace).await {
-                                let backend_addr = format!("{}:80", service_ip).parse().unwrap();
-                                self.load_balancer.remove_backend(&backend_addr);
-                                println!("Backend removed for {}: {}", host, backend_addr);
-                            }
-                        }
-                    }
+    /// Logs and counts a failure of the watch on a resource kind, which is retried with backoff.
+    fn watch_failed(&self, resource: &str, error: kube_runtime::watcher::Error) {
+        eprintln!("{} watch failed: {}", resource, error);
+        self.metrics.watch_failed(resource);
+    }
+
+    /// Re-evaluates every Ingress in the store, e.g. after the set of flusso ingress
+    /// classes changed, and removes the routes of Ingresses that no longer exist.
+    ///
+    /// A failure with one Ingress does not prevent the others from being processed.
+    async fn reconcile(&self, stores: &Stores) -> Result<(), Box<dyn Error>> {
+        self.sync_default_backend(&stores.services).await?;
+
+        let ingresses = stores.ingresses.state();
+        let existing: HashSet<String> = ingresses.iter().map(|ingress| ingress_key(ingress)).collect();
+
+        let vanished: Vec<String> = self.backends.lock().unwrap()
+            .keys()
+            .filter(|key| !existing.contains(*key))
+            .cloned()
+            .collect();
+        for ingress_key in vanished {
+            self.remove_ingress(ingress_key).await?;
+        }
+
+        self.process_ingresses(ingresses, &stores.services).await
+    }
+
+    /// Reprocesses the Ingresses referencing a Service after it changed or was deleted,
+    /// along with the controller-wide default backend if it is one of its ports.
+    ///
+    /// All Ingresses are reprocessed when the Service is the publish Service, whose address
+    /// goes into their status.
+    async fn refresh_ingresses(&self, service: &Service, stores: &Stores) -> Result<(), Box<dyn Error>> {
+        let namespace = service.metadata.namespace.as_deref().unwrap_or_default();
+        let service_key = endpoints::service_key(namespace, service.metadata.name.as_deref().unwrap_or_default());
+        if self.status.publishes_service(&service_key) {
+            return self.reconcile(stores).await;
+        }
+
+        let default_backend = self.default_backend_spec.as_ref()
+            .map(|(namespace, backend)| endpoints::service_key(namespace, &backend.name));
+        if default_backend.as_ref() == Some(&service_key) {
+            self.sync_default_backend(&stores.services).await?;
+        }
+
+        let ingresses = stores.ingresses.state().into_iter()
+            .filter(|ingress| ingress_services(ingress).contains(&service_key))
+            .collect();
+        self.process_ingresses(ingresses, &stores.services).await
+    }
+
+    /// Processes Ingresses one after the other.
+    ///
+    /// A failure with one Ingress does not prevent the others from being processed.
+    async fn process_ingresses(&self, ingresses: Vec<Arc<Ingress>>, services: &Store<Service>) -> Result<(), Box<dyn Error>> {
+        for ingress in ingresses {
+            let ingress_key = ingress_key(&ingress);
+            let result = self.process_ingress(Ingress::clone(&ingress), services).await;
+            self.metrics.reconciled(result.is_ok());
+            if let Err(e) = result {
+                if self.event_channel.is_closed() {
+                    return Err(e);
                 }
+                eprintln!("Failed to process ingress {}: {}", ingress_key, e);
             }
         }
         Ok(())
     }
 
-    /// Resolves the IP address of a Kubernetes service by name and namespace.
+    /// Processes an Ingress event, translating every rule and path into routes.
     ///
-    /// # Parameters
-    /// - `service_name`: The name of the service.
-    /// - `namespace`: The namespace in which the service is located.
+    /// Each path backend is resolved to a backend pool keyed by its service, and the
+    /// full set of routes declared by the Ingress replaces the previous one.
+    /// Ingresses that are not claimed by flusso, or no longer are, have their routes removed.
+    async fn process_ingress(&self, ingress: Ingress, services: &Store<Service>) -> Result<(), Box<dyn Error>> {
+        let ingress_key = ingress_key(&ingress);
+
+        if !self.ingress_classes.claims(&ingress) {
+            self.sni.remove_ingress(&ingress_key);
+            self.backends.lock().unwrap().remove(&ingress_key);
+            self.reported.lock().unwrap().remove(&ingress_key);
+            self.event_channel
+                .send(IngressEvent::Delete { ingress: ingress_key.clone() })
+                .await?;
+
+            // Released to another class: withdraw the address flusso published, if it did,
+            // and leave any other status to the controller that wrote it
+            if let Err(e) = self.status.clear(&ingress).await {
+                eprintln!("Failed to clear status of ingress {}: {}", ingress_key, e);
+            }
+            return Ok(());
+        }
+
+        let mut problems = Vec::new();
+
+        // Certificates are served for the hosts of every `spec.tls` entry
+        let namespace = ingress.metadata.namespace.clone().unwrap_or_default();
+        let tls = ingress.spec.as_ref().and_then(|spec| spec.tls.as_deref()).unwrap_or_default();
+        for secret in self.sni.set_ingress_tls(&ingress_key, &namespace, tls) {
+            if !self.sni.has_secret(&secret) {
+                let note = format!("Secret {} does not exist or holds no valid certificate", secret);
+                problems.push((REASON_SECRET_NOT_FOUND, note));
+            }
+        }
+
+        let (options, invalid) = ingress_options(&ingress);
+        problems.extend(invalid.into_iter().map(|note| (REASON_INVALID_ANNOTATION, note)));
+
+        let mut routes = Vec::new();
+        let mut backends = Vec::new();
+        let mut resolved: HashMap<String, String> = HashMap::new();
+
+        let rules = ingress.spec.as_ref().and_then(|spec| spec.rules.as_ref());
+        for rule in rules.into_iter().flatten() {
+            let Some(http) = &rule.http else { continue };
+
+            for path in &http.paths {
+                let Some(service) = &path.backend.service else { continue };
+                let backend_key = self.register_backend(&ingress, service, services, &mut resolved, &mut backends, &mut problems).await?;
+
+                let route = Route {
+                    ingress: ingress_key.clone(),
+                    host: rule.host.clone(),
+                    path: path.path.clone().unwrap_or_else(|| "/".to_string()),
+                    path_type: PathType::from(path.path_type.as_str()),
+                    backend: backend_key,
+                };
+                println!("Detected route {:?} for {}", route, ingress_key);
+                routes.push(route);
+            }
+        }
+
+        let default_service = ingress.spec.as_ref()
+            .and_then(|spec| spec.default_backend.as_ref())
+            .and_then(|backend| backend.service.as_ref());
+        let default_backend = match default_service {
+            Some(service) => {
+                let backend_key = self.register_backend(&ingress, service, services, &mut resolved, &mut backends, &mut problems).await?;
+                println!("Detected default backend {} for {}", backend_key, ingress_key);
+                Some(backend_key)
+            }
+            None => None,
+        };
+        self.report(&ingress, problems).await;
+
+        self.backends.lock().unwrap().insert(ingress_key.clone(), backends);
+        self.event_channel
+            .send(IngressEvent::Apply { ingress: ingress_key.clone(), routes, default_backend, options })
+            .await?;
+
+        if let Err(e) = self.status.publish(&ingress, services).await {
+            eprintln!("Failed to publish status of ingress {}: {}", ingress_key, e);
+        }
+        Ok(())
+    }
+
+    /// Resolves a Service port referenced by an Ingress and registers its pool endpoints.
+    ///
+    /// Each Service port is resolved once per Ingress: `resolved` maps the backends as
+    /// written to their backend keys, `backends` collects the resolved ones and `problems`
+    /// the reason and note of those that could not be.
+    ///
+    /// # Returns
+    /// The key of the backend pool, which stays empty if the backend could not be resolved.
+    async fn register_backend(
+        &self,
+        ingress: &Ingress,
+        service: &IngressServiceBackend,
+        services: &Store<Service>,
+        resolved: &mut HashMap<String, String>,
+        backends: &mut Vec<BackendRef>,
+        problems: &mut Vec<Problem>,
+    ) -> Result<String, Box<dyn Error>> {
+        let namespace = ingress.metadata.namespace.clone().unwrap_or_default();
+        let spec_key = backend_spec_key(&namespace, service);
+        if let Some(backend_key) = resolved.get(&spec_key) {
+            return Ok(backend_key.clone());
+        }
+
+        let backend_key = match self.resolve_backend(ingress, service, services, problems) {
+            Some(backend) => {
+                self.send_backend(&backend).await?;
+                let backend_key = backend.key.clone();
+                backends.push(backend);
+                backend_key
+            }
+            None => spec_key.clone(),
+        };
+        resolved.insert(spec_key, backend_key.clone());
+        Ok(backend_key)
+    }
+
+    /// Resolves the controller-wide default backend and sends it to the processor.
+    async fn sync_default_backend(&self, services: &Store<Service>) -> Result<(), Box<dyn Error>> {
+        let Some((namespace, service)) = &self.default_backend_spec else { return Ok(()) };
+
+        let backend = match find_backend(services, namespace, service) {
+            Ok(backend) => Some(backend),
+            Err((reason, note)) => {
+                eprintln!("Default backend: {}: {}", reason, note);
+                None
+            }
+        };
+        if let Some(backend) = &backend {
+            self.send_backend(backend).await?;
+        }
+
+        let backend_key = backend.as_ref().map(|backend| backend.key.clone());
+        *self.default_backend.lock().unwrap() = backend;
+        self.event_channel
+            .send(IngressEvent::DefaultBackend { backend: backend_key })
+            .await?;
+        Ok(())
+    }
+
+    /// Removes an Ingress (`namespace/name`), deregistering all of its routes.
+    async fn remove_ingress(&self, ingress_key: String) -> Result<(), Box<dyn Error>> {
+        self.backends.lock().unwrap().remove(&ingress_key);
+        self.reported.lock().unwrap().remove(&ingress_key);
+        self.sni.remove_ingress(&ingress_key);
+
+        println!("Removing routes for {}", ingress_key);
+        self.event_channel
+            .send(IngressEvent::Delete { ingress: ingress_key })
+            .await?;
+        Ok(())
+    }
+
+    /// Sends the current endpoints of a backend to the processor.
+    async fn send_backend(&self, backend: &BackendRef) -> Result<(), Box<dyn Error>> {
+        let endpoints = self.endpoints.resolve(backend);
+        let zones = self.endpoints.zones(backend);
+        println!("Endpoints for backend {}: {:?}", backend.key, endpoints);
+        self.event_channel
+            .send(IngressEvent::Backend { backend: backend.key.clone(), endpoints, zones })
+            .await?;
+        Ok(())
+    }
+
+    /// Updates the pools of every backend referencing a Service after its endpoints changed.
+    async fn refresh_service(&self, service_key: &str) -> Result<(), Box<dyn Error>> {
+        let default_backend = self.default_backend.lock().unwrap().clone();
+        let mut backends: Vec<BackendRef> = self.backends.lock().unwrap()
+            .values()
+            .flatten()
+            .cloned()
+            .chain(default_backend)
+            .filter(|backend| endpoints::service_key(&backend.namespace, &backend.service) == service_key)
+            .collect();
+        backends.sort_by(|a, b| a.key.cmp(&b.key));
+        backends.dedup_by(|a, b| a.key == b.key);
+
+        for backend in backends {
+            self.send_backend(&backend).await?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the Service port referenced by an Ingress backend.
+    ///
+    /// A problem is added to `problems` when the Service or the port does not exist.
     ///
     /// # Returns
-    /// A `Result` with the IP address of the service or an error if it could not be resolved.
-    async fn resolve_service_ip(&self, service_name: &str, namespace: &str) -> Result<String, Box<dyn Error>> {
-        let client = Client::try_default().await?;
-        let services: Api<k8s_openapi::api::core::v1::Service> = Api::namespaced(client, namespace);
-        if let Some(service) = services.get(service_name).await.ok() {
-            if let Some(cluster_ip) = service.spec.and_then(|spec| spec.cluster_ip) {
-                return Ok(cluster_ip);
+    /// The resolved backend, or `None` if it could not be resolved.
+    fn resolve_backend(
+        &self,
+        ingress: &Ingress,
+        backend: &IngressServiceBackend,
+        services: &Store<Service>,
+        problems: &mut Vec<Problem>,
+    ) -> Option<BackendRef> {
+        let namespace = ingress.metadata.namespace.clone().unwrap_or_default();
+        match find_backend(services, &namespace, backend) {
+            Ok(backend) => Some(backend),
+            Err(problem) => {
+                problems.push(problem);
+                None
             }
         }
-        Err(Box::from("Failed to resolve service IP"))
     }
+
+    /// Logs the problems with an Ingress and publishes them as `Warning` Events, unless
+    /// they were already reported the last time the Ingress was processed.
+    ///
+    /// # Parameters
+    /// - `ingress`: The Ingress the problems are about.
+    /// - `problems`: The reason and note of every current problem with the Ingress.
+    async fn report(&self, ingress: &Ingress, problems: Vec<Problem>) {
+        let ingress_key = ingress_key(ingress);
+        let previous = self.reported.lock().unwrap().remove(&ingress_key).unwrap_or_default();
+
+        let mut reported = HashSet::new();
+        for problem in problems {
+            if reported.contains(&problem) {
+                continue;
+            }
+            if !previous.contains(&problem) {
+                let (reason, note) = &problem;
+                eprintln!("{}: {}: {}", ingress_key, reason, note);
+                // Problems failing to publish are retried the next time
+                if let Err(e) = self.recorder.warning(ingress, reason, note).await {
+                    eprintln!("Failed to publish event for {}: {}", ingress_key, e);
+                    continue;
+                }
+            }
+            reported.insert(problem);
+        }
+
+        if !reported.is_empty() {
+            self.reported.lock().unwrap().insert(ingress_key, reported);
+        }
+    }
+}
+
+/// Turns leadership changes into a stream of watch events.
+fn leadership_changes(leader: watch::Receiver<bool>) -> impl Stream<Item = WatchEvent> {
+    stream::unfold(leader, |mut leader| async move {
+        leader.changed().await.ok()?;
+        let leading = *leader.borrow_and_update();
+        Some((WatchEvent::Leadership(leading), leader))
+    })
+}
+
+/// Produces a resync event every resync period.
+fn resync_ticks() -> impl Stream<Item = WatchEvent> {
+    let mut ticks = interval(RESYNC_PERIOD);
+    // The first tick completes immediately, before the initial sync
+    ticks.reset();
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    stream::unfold(ticks, |mut ticks| async move {
+        ticks.tick().await;
+        Some((WatchEvent::Resync, ticks))
+    })
+}
+
+/// Looks up the Service port referenced by a backend in a namespace, in the Service store.
+///
+/// Ports may be referenced by name or by number; both resolve to the same backend,
+/// keyed by the Service port number.
+///
+/// # Returns
+/// The resolved backend, or the reason and note of the Event to publish.
+fn find_backend(
+    services: &Store<Service>,
+    namespace: &str,
+    backend: &IngressServiceBackend,
+) -> Result<BackendRef, Problem> {
+    let Some(service) = services.get(&ObjectRef::new(&backend.name).within(namespace)) else {
+        let note = format!("Service {}/{} does not exist", namespace, backend.name);
+        return Err((REASON_SERVICE_NOT_FOUND, note));
+    };
+
+    let port = backend.port.as_ref().and_then(|port| find_service_port(&service, port));
+    let Some(port) = port else {
+        let note = format!(
+            "Service {}/{} has no port {}",
+            namespace, backend.name, backend_spec_port(backend)
+        );
+        return Err((REASON_PORT_NOT_FOUND, note));
+    };
+
+    Ok(BackendRef {
+        key: format!("{}/{}:{}", namespace, backend.name, port.port),
+        namespace: namespace.to_string(),
+        service: backend.name.clone(),
+        port_name: port.name.clone(),
+    })
+}
+
+/// Returns the namespaced name of an Ingress, as `namespace/name`.
+fn ingress_key(ingress: &Ingress) -> String {
+    format!(
+        "{}/{}",
+        ingress.metadata.namespace.clone().unwrap_or_default(),
+        ingress.metadata.name.clone().unwrap_or_default()
+    )
+}
+
+/// Returns the service port of an Ingress backend as written, by name or number.
+fn backend_spec_port(backend: &IngressServiceBackend) -> String {
+    backend.port.as_ref()
+        .and_then(|port| port.name.clone().or_else(|| port.number.map(|number| number.to_string())))
+        .unwrap_or_default()
+}
+
+/// Returns the backend key of an Ingress backend as written, before resolving its port.
+fn backend_spec_key(namespace: &str, backend: &IngressServiceBackend) -> String {
+    format!("{}/{}:{}", namespace, backend.name, backend_spec_port(backend))
 }
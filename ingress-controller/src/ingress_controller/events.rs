@@ -0,0 +1,87 @@
+//! Events module for reporting problems with Ingresses as Kubernetes Events.
+//!
+//! The `EventRecorder` struct publishes `events.k8s.io/v1` Events regarding Ingress
+//! objects, so that configuration errors show up in `kubectl describe ingress`. Only the
+//! leader replica publishes Events.
+
+use k8s_openapi::api::events::v1::Event;
+use k8s_openapi::api::networking::v1::Ingress;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::Utc;
+use kube::api::{Api, PostParams};
+use kube::{Client, Resource};
+use tokio::sync::watch;
+use crate::ingress_controller::ingress_class::CONTROLLER_NAME;
+
+/// Reason of the Event emitted when an Ingress references a missing Service.
+pub const REASON_SERVICE_NOT_FOUND: &str = "ServiceNotFound";
+
+/// Reason of the Event emitted when an Ingress references a port its Service lacks.
+pub const REASON_PORT_NOT_FOUND: &str = "PortNotFound";
+
+/// Reason of the Event emitted when an Ingress references a missing or invalid TLS Secret.
+pub const REASON_SECRET_NOT_FOUND: &str = "SecretNotFound";
+
+/// Reason of the Event emitted when an Ingress annotation holds an invalid value.
+pub const REASON_INVALID_ANNOTATION: &str = "InvalidAnnotation";
+
+/// Publishes Kubernetes Events regarding Ingress objects.
+#[derive(Clone)]
+pub struct EventRecorder {
+    client: Client,
+    /// Name of this controller replica, taken from the pod hostname.
+    instance: String,
+    /// Whether this replica is the leader.
+    leader: watch::Receiver<bool>,
+}
+
+impl EventRecorder {
+    /// Creates a new `EventRecorder` using a Kubernetes client.
+    ///
+    /// # Parameters
+    /// - `client`: Kubernetes client used to create Events.
+    /// - `leader`: Leadership state of this replica; Events are dropped while not leader.
+    pub fn new(client: Client, leader: watch::Receiver<bool>) -> Self {
+        let instance = std::env::var("HOSTNAME").unwrap_or_else(|_| "flusso".to_string());
+        Self { client, instance, leader }
+    }
+
+    /// Publishes a `Warning` Event regarding an Ingress.
+    ///
+    /// # Parameters
+    /// - `ingress`: The Ingress the Event is about.
+    /// - `reason`: A short, machine-readable reason in UpperCamelCase.
+    /// - `note`: A human-readable description of the problem.
+    pub async fn warning(&self, ingress: &Ingress, reason: &str, note: &str) -> kube::Result<()> {
+        self.publish(ingress, "Warning", reason, note).await
+    }
+
+    /// Creates the Event in the namespace of the Ingress.
+    async fn publish(&self, ingress: &Ingress, type_: &str, reason: &str, note: &str) -> kube::Result<()> {
+        if !*self.leader.borrow() {
+            return Ok(());
+        }
+
+        let namespace = ingress.metadata.namespace.clone().unwrap_or_default();
+        let event = Event {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("{}.", ingress.metadata.name.clone().unwrap_or_default())),
+                namespace: Some(namespace.clone()),
+                ..Default::default()
+            },
+            action: Some("Sync".to_string()),
+            event_time: Some(MicroTime(Utc::now())),
+            note: Some(note.to_string()),
+            reason: Some(reason.to_string()),
+            regarding: Some(ingress.object_ref(&())),
+            reporting_controller: Some(CONTROLLER_NAME.to_string()),
+            reporting_instance: Some(self.instance.clone()),
+            type_: Some(type_.to_string()),
+            ..Default::default()
+        };
+
+        let events: Api<Event> = Api::namespaced(self.client.clone(), &namespace);
+        events.create(&PostParams::default(), &event).await?;
+        Ok(())
+    }
+}
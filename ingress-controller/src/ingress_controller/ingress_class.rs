@@ -0,0 +1,186 @@
+//! Ingress class module deciding which Ingresses are handled by flusso.
+//!
+//! An Ingress is claimed when its `spec.ingressClassName` names an `IngressClass` whose
+//! controller is flusso's, when it has no class and one of flusso's classes is marked as
+//! the cluster default, or, as a fallback, when its deprecated `kubernetes.io/ingress.class`
+//! annotation names flusso.
+
+use k8s_openapi::api::networking::v1::{Ingress, IngressClass};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Controller name that `IngressClass` objects set in `spec.controller` to select flusso.
+pub const CONTROLLER_NAME: &str = "flusso.io/ingress-controller";
+
+/// Deprecated annotation naming the class of an Ingress.
+pub const INGRESS_CLASS_ANNOTATION: &str = "kubernetes.io/ingress.class";
+
+/// Annotation marking an `IngressClass` as the default for Ingresses without a class.
+pub const DEFAULT_CLASS_ANNOTATION: &str = "ingressclass.kubernetes.io/is-default-class";
+
+/// Class name accepted in the deprecated annotation even without an `IngressClass` object.
+pub const ANNOTATION_CLASS_NAME: &str = "flusso";
+
+/// The set of `IngressClass` objects handled by flusso, kept up to date from watch events.
+#[derive(Clone, Debug, Default)]
+pub struct IngressClasses {
+    /// Whether each class is marked as default, keyed by class name.
+    classes: Arc<RwLock<HashMap<String, bool>>>,
+}
+
+impl IngressClasses {
+    /// Creates an empty set of ingress classes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an added or updated `IngressClass`, forgetting it if it is not flusso's.
+    ///
+    /// # Returns
+    /// `true` if the set of classes changed.
+    pub fn apply(&self, class: &IngressClass) -> bool {
+        let Some(name) = class.metadata.name.clone() else { return false };
+        let mut classes = self.classes.write().unwrap();
+
+        let controller = class.spec.as_ref().and_then(|spec| spec.controller.as_deref());
+        if controller != Some(CONTROLLER_NAME) {
+            return classes.remove(&name).is_some();
+        }
+
+        let is_default = class.metadata.annotations.as_ref()
+            .and_then(|annotations| annotations.get(DEFAULT_CLASS_ANNOTATION))
+            .is_some_and(|value| value == "true");
+        classes.insert(name, is_default) != Some(is_default)
+    }
+
+    /// Forgets a deleted `IngressClass`.
+    ///
+    /// # Returns
+    /// `true` if the class was one of flusso's.
+    pub fn delete(&self, class: &IngressClass) -> bool {
+        let Some(name) = &class.metadata.name else { return false };
+        self.classes.write().unwrap().remove(name).is_some()
+    }
+
+    /// Replaces the whole set of classes, e.g. after the watch re-listed them.
+    ///
+    /// # Returns
+    /// `true` if the set of classes changed.
+    pub fn reset<'a>(&self, classes: impl IntoIterator<Item = &'a IngressClass>) -> bool {
+        let previous = std::mem::take(&mut *self.classes.write().unwrap());
+        for class in classes {
+            self.apply(class);
+        }
+        *self.classes.read().unwrap() != previous
+    }
+
+    /// Returns `true` if flusso should handle the Ingress.
+    pub fn claims(&self, ingress: &Ingress) -> bool {
+        let classes = self.classes.read().unwrap();
+
+        let class_name = ingress.spec.as_ref().and_then(|spec| spec.ingress_class_name.as_ref());
+        if let Some(class_name) = class_name {
+            return classes.contains_key(class_name);
+        }
+
+        let annotation = ingress.metadata.annotations.as_ref()
+            .and_then(|annotations| annotations.get(INGRESS_CLASS_ANNOTATION));
+        if let Some(class_name) = annotation {
+            return class_name == ANNOTATION_CLASS_NAME || classes.contains_key(class_name);
+        }
+
+        classes.values().any(|is_default| *is_default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::networking::v1::{IngressClassSpec, IngressSpec};
+    use std::collections::BTreeMap;
+
+    fn class(name: &str, controller: &str, is_default: bool) -> IngressClass {
+        let mut class = IngressClass {
+            spec: Some(IngressClassSpec {
+                controller: Some(controller.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        class.metadata.name = Some(name.to_string());
+        if is_default {
+            class.metadata.annotations = Some(BTreeMap::from([
+                (DEFAULT_CLASS_ANNOTATION.to_string(), "true".to_string()),
+            ]));
+        }
+        class
+    }
+
+    fn ingress(class_name: Option<&str>, annotation: Option<&str>) -> Ingress {
+        let mut ingress = Ingress {
+            spec: Some(IngressSpec {
+                ingress_class_name: class_name.map(str::to_string),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        ingress.metadata.annotations = annotation.map(|class_name| {
+            BTreeMap::from([(INGRESS_CLASS_ANNOTATION.to_string(), class_name.to_string())])
+        });
+        ingress
+    }
+
+    #[test]
+    fn test_claims_by_class_name() {
+        let classes = IngressClasses::new();
+        classes.apply(&class("public", CONTROLLER_NAME, false));
+        classes.apply(&class("nginx", "k8s.io/ingress-nginx", false));
+
+        assert!(classes.claims(&ingress(Some("public"), None)));
+        assert!(!classes.claims(&ingress(Some("nginx"), None)));
+        assert!(!classes.claims(&ingress(Some("missing"), None)));
+        // The class name takes precedence over the deprecated annotation
+        assert!(!classes.claims(&ingress(Some("nginx"), Some("flusso"))));
+    }
+
+    #[test]
+    fn test_claims_by_annotation() {
+        let classes = IngressClasses::new();
+        classes.apply(&class("public", CONTROLLER_NAME, false));
+
+        assert!(classes.claims(&ingress(None, Some("flusso"))));
+        assert!(classes.claims(&ingress(None, Some("public"))));
+        assert!(!classes.claims(&ingress(None, Some("nginx"))));
+    }
+
+    #[test]
+    fn test_claims_by_default_class() {
+        let classes = IngressClasses::new();
+        classes.apply(&class("public", CONTROLLER_NAME, false));
+        assert!(!classes.claims(&ingress(None, None)));
+
+        assert!(classes.apply(&class("public", CONTROLLER_NAME, true)));
+        assert!(classes.claims(&ingress(None, None)));
+        assert!(!classes.claims(&ingress(None, Some("nginx"))));
+
+        // Handing the class over to another controller releases its Ingresses
+        assert!(classes.apply(&class("public", "k8s.io/ingress-nginx", true)));
+        assert!(!classes.claims(&ingress(None, None)));
+        assert!(!classes.claims(&ingress(Some("public"), None)));
+    }
+
+    #[test]
+    fn test_reset_replaces_classes() {
+        let classes = IngressClasses::new();
+        classes.apply(&class("public", CONTROLLER_NAME, false));
+        classes.apply(&class("internal", CONTROLLER_NAME, false));
+
+        let relisted = [class("public", CONTROLLER_NAME, false)];
+        assert!(classes.reset(&relisted));
+        assert!(classes.claims(&ingress(Some("public"), None)));
+        // Classes deleted while the watch was down are forgotten
+        assert!(!classes.claims(&ingress(Some("internal"), None)));
+
+        assert!(!classes.reset(&relisted));
+    }
+}
@@ -1,54 +1,92 @@
-//! Ingress processor module for managing backend servers based on Ingress events.
+//! Ingress processor module for managing the routing table based on Ingress events.
 //!
-//! The `IngressProcessor` struct processes Ingress events, adding or removing
-//! backend servers from the load balancer based on these events.
+//! The `IngressProcessor` struct processes Ingress events, updating the routes and
+//! backend pools of the shared `Router` based on these events. Once the routes of every
+//! existing Ingress are applied, it marks the controller as ready.
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::sync::mpsc;
-use crate::proxy::load_balancer::LoadBalancer;
-use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use crate::proxy::router::{IngressOptions, Route, SharedRouter};
 
-/// Represents an Ingress event that either adds or removes a backend server.
+/// Represents a change to the routing table.
 #[derive(Debug)]
 pub enum IngressEvent {
-    Add(SocketAddr),
-    Remove(SocketAddr),
+    /// Sets the addresses of a backend pool, identified by its backend key, and the zone
+    /// of those reporting one.
+    Backend { backend: String, endpoints: Vec<SocketAddr>, zones: HashMap<SocketAddr, String> },
+    /// Replaces every route, the default backend key and the proxy options of an Ingress (`namespace/name`).
+    Apply { ingress: String, routes: Vec<Route>, default_backend: Option<String>, options: IngressOptions },
+    /// Removes every route declared by an Ingress (`namespace/name`).
+    Delete { ingress: String },
+    /// Sets the backend key of the controller-wide default backend.
+    DefaultBackend { backend: Option<String> },
+    /// Every existing Ingress was sent after the initial sync.
+    Synced,
 }
 
-/// Processes `IngressEvent`s to update the load balancer.
+/// Processes `IngressEvent`s to update the routing table.
 pub struct IngressProcessor {
-    load_balancer: Arc<LoadBalancer>,
+    router: SharedRouter,
     event_receiver: mpsc::Receiver<IngressEvent>,
+    /// Set once the routing table reflects the initial state of the cluster.
+    ready: watch::Sender<bool>,
 }
 
 impl IngressProcessor {
-    /// Creates a new `IngressProcessor` with a load balancer and event receiver.
+    /// Creates a new `IngressProcessor` with a router and event receiver.
     ///
     /// # Parameters
-    /// - `load_balancer`: Shared `LoadBalancer` for backend management.
+    /// - `router`: Shared `Router` holding routes and backend pools.
     /// - `event_receiver`: Receiver channel for `IngressEvent`s.
-    pub fn new(load_balancer: Arc<LoadBalancer>, event_receiver: mpsc::Receiver<IngressEvent>) -> Self {
+    /// - `ready`: Readiness flag, set once the initial sync is applied.
+    pub fn new(
+        router: SharedRouter,
+        event_receiver: mpsc::Receiver<IngressEvent>,
+        ready: watch::Sender<bool>,
+    ) -> Self {
         Self {
-            load_balancer,
+            router,
             event_receiver,
+            ready,
         }
     }
 
-    /// Processes incoming events to update the backend list in the load balancer.
+    /// Processes incoming events to update the routing table.
     ///
     /// This function listens for `IngressEvent`s from the receiver and updates the
-    /// load balancer by adding or removing backend addresses as needed.
+    /// routes and backend pools of the router as needed.
     pub async fn process_events(&mut self) {
         while let Some(event) = self.event_receiver.recv().await {
             println!("Event received in IngressProcessor: {:?}", event);
             match event {
-                IngressEvent::Add(addr) => {
-                    self.load_balancer.add_backend(addr);
-                    println!("Backend {} added.", addr);
+                IngressEvent::Backend { backend, endpoints, zones } => {
+                    // Pools are shared by every version of the table, which only changes
+                    // when the pool is new
+                    let pool = self.router.load().get_pool(&backend);
+                    let pool = pool.unwrap_or_else(|| self.router.update(|router| router.pool(&backend)));
+                    pool.set_backends(endpoints);
+                    pool.set_zones(zones);
+                    println!("Backend pool {} updated.", backend);
                 }
-                IngressEvent::Remove(addr) => {
-                    self.load_balancer.remove_backend(&addr);
-                    println!("Backend {} removed.", addr);
+                IngressEvent::Apply { ingress, routes, default_backend, options } => {
+                    self.router.update(|router| {
+                        router.set_ingress_routes(&ingress, routes, default_backend);
+                        router.set_ingress_options(&ingress, options);
+                    });
+                    println!("Routes for {} applied.", ingress);
+                }
+                IngressEvent::Delete { ingress } => {
+                    self.router.update(|router| router.remove_ingress(&ingress));
+                    println!("Routes for {} removed.", ingress);
+                }
+                IngressEvent::DefaultBackend { backend } => {
+                    println!("Default backend set to {:?}.", backend);
+                    self.router.update(|router| router.set_default_backend(backend));
+                }
+                IngressEvent::Synced => {
+                    self.ready.send_replace(true);
+                    println!("Routing table synced, ready to serve.");
                 }
             }
         }
@@ -0,0 +1,217 @@
+//! Leader election module for running several controller replicas.
+//!
+//! Every replica serves traffic, but only one of them, the leader, writes to the
+//! Kubernetes API (Ingress status and Events). The leader is elected through a
+//! `coordination.k8s.io/v1` Lease: the replica holding it renews it periodically, and the
+//! others take it over once it has not been renewed for the lease duration. A leader
+//! shutting down releases the Lease so that another replica takes over right away.
+
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{DateTime, Duration as ChronoDuration, Utc};
+use kube::api::{Api, PostParams};
+use kube::Client;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::sleep;
+
+/// Name of the Lease used when none is configured.
+pub const DEFAULT_LEASE_NAME: &str = "flusso-ingress-controller";
+
+/// How long a Lease is valid after its last renewal.
+const LEASE_DURATION: Duration = Duration::from_secs(15);
+
+/// How long the leader keeps acting as such when it fails to renew the Lease.
+const RENEW_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Interval between attempts to acquire or renew the Lease.
+const RETRY_PERIOD: Duration = Duration::from_secs(2);
+
+/// Takes part in the election of the replica allowed to write to the Kubernetes API.
+#[derive(Clone)]
+pub struct LeaderElection {
+    leases: Api<Lease>,
+    lease_name: String,
+    /// Identity of this replica, taken from the pod hostname.
+    identity: String,
+    state: Arc<watch::Sender<bool>>,
+}
+
+impl LeaderElection {
+    /// Creates a new `LeaderElection` using a Lease in the client's default namespace.
+    ///
+    /// # Parameters
+    /// - `client`: Kubernetes client used to read and update the Lease.
+    /// - `lease_name`: Name of the Lease shared by all replicas.
+    pub fn new(client: Client, lease_name: &str) -> Self {
+        let leases = Api::namespaced(client.clone(), client.default_namespace());
+        let identity = std::env::var("HOSTNAME").unwrap_or_else(|_| "flusso".to_string());
+        let (state, _) = watch::channel(false);
+        Self {
+            leases,
+            lease_name: lease_name.to_string(),
+            identity,
+            state: Arc::new(state),
+        }
+    }
+
+    /// Returns `true` if this replica is currently the leader.
+    pub fn is_leader(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    /// Returns a receiver notified every time this replica gains or loses leadership.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.state.subscribe()
+    }
+
+    /// Runs the election until the controller shuts down.
+    ///
+    /// The Lease is acquired or renewed every retry period. On shutdown, the Lease is
+    /// released if this replica holds it.
+    ///
+    /// # Parameters
+    /// - `shutdown`: Flag set when the controller shuts down.
+    pub async fn run(&self, mut shutdown: watch::Receiver<bool>) {
+        let mut last_renew: Option<Instant> = None;
+
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => last_renew = Some(Instant::now()),
+                Ok(false) => last_renew = None,
+                Err(e) => eprintln!("Failed to acquire or renew lease {}: {}", self.lease_name, e),
+            }
+            self.set_leader(last_renew.is_some_and(|renewed| renewed.elapsed() < RENEW_DEADLINE));
+
+            tokio::select! {
+                _ = sleep(RETRY_PERIOD) => {}
+                Ok(_) = shutdown.wait_for(|shutdown| *shutdown) => break,
+            }
+        }
+
+        if self.is_leader() {
+            self.set_leader(false);
+            if let Err(e) = self.release().await {
+                eprintln!("Failed to release lease {}: {}", self.lease_name, e);
+            }
+        }
+    }
+
+    /// Updates the leadership state, logging transitions.
+    fn set_leader(&self, leading: bool) {
+        self.state.send_if_modified(|state| {
+            if *state == leading {
+                return false;
+            }
+            match leading {
+                true => println!("{} became the leader", self.identity),
+                false => println!("{} is no longer the leader", self.identity),
+            }
+            *state = leading;
+            true
+        });
+    }
+
+    /// Acquires the Lease if it is free or expired, or renews it if already held.
+    ///
+    /// # Returns
+    /// `true` if this replica holds the Lease afterwards.
+    async fn try_acquire_or_renew(&self) -> kube::Result<bool> {
+        let now = Utc::now();
+
+        let Some(mut lease) = self.leases.get_opt(&self.lease_name).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta { name: Some(self.lease_name.clone()), ..Default::default() },
+                spec: Some(self.lease_spec(now, now, 0)),
+            };
+            return ignore_conflict(self.leases.create(&PostParams::default(), &lease).await);
+        };
+
+        let spec = lease.spec.clone().unwrap_or_default();
+        if !can_acquire(&spec, &self.identity, now) {
+            return Ok(false);
+        }
+
+        // Keep the acquire time while renewing, count a transition when taking over
+        let transitions = spec.lease_transitions.unwrap_or_default();
+        lease.spec = Some(match spec.holder_identity.as_deref() == Some(self.identity.as_str()) {
+            true => self.lease_spec(spec.acquire_time.map_or(now, |time| time.0), now, transitions),
+            false => self.lease_spec(now, now, transitions + 1),
+        });
+        ignore_conflict(self.leases.replace(&self.lease_name, &PostParams::default(), &lease).await)
+    }
+
+    /// Gives up the Lease, letting another replica acquire it on its next attempt.
+    async fn release(&self) -> kube::Result<()> {
+        let Some(mut lease) = self.leases.get_opt(&self.lease_name).await? else { return Ok(()) };
+        let Some(spec) = lease.spec.as_mut() else { return Ok(()) };
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+
+        spec.holder_identity = None;
+        spec.lease_duration_seconds = Some(1);
+        spec.renew_time = Some(MicroTime(Utc::now()));
+        self.leases.replace(&self.lease_name, &PostParams::default(), &lease).await?;
+        println!("{} released lease {}", self.identity, self.lease_name);
+        Ok(())
+    }
+
+    /// Builds the spec of a Lease held by this replica.
+    fn lease_spec(&self, acquire_time: DateTime<Utc>, renew_time: DateTime<Utc>, transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(LEASE_DURATION.as_secs() as i32),
+            acquire_time: Some(MicroTime(acquire_time)),
+            renew_time: Some(MicroTime(renew_time)),
+            lease_transitions: Some(transitions),
+        }
+    }
+}
+
+/// Returns `true` if a replica may take or keep a Lease: it already holds it, the Lease
+/// has no holder, or the holder did not renew it within the lease duration.
+fn can_acquire(spec: &LeaseSpec, identity: &str, now: DateTime<Utc>) -> bool {
+    let holder = spec.holder_identity.as_deref().unwrap_or_default();
+    if holder.is_empty() || holder == identity {
+        return true;
+    }
+
+    let duration = ChronoDuration::seconds(spec.lease_duration_seconds.unwrap_or_default().into());
+    spec.renew_time.as_ref().is_none_or(|renew_time| renew_time.0 + duration < now)
+}
+
+/// Treats a conflict, caused by another replica updating the Lease first, as a lost race.
+fn ignore_conflict<T>(result: kube::Result<T>) -> kube::Result<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(holder: Option<&str>, renewed_secs_ago: i64) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: holder.map(str::to_string),
+            lease_duration_seconds: Some(15),
+            renew_time: Some(MicroTime(Utc::now() - ChronoDuration::seconds(renewed_secs_ago))),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_can_acquire() {
+        let now = Utc::now();
+        assert!(can_acquire(&spec(None, 0), "flusso-a", now));
+        assert!(can_acquire(&spec(Some(""), 0), "flusso-a", now));
+        assert!(can_acquire(&spec(Some("flusso-a"), 0), "flusso-a", now));
+        assert!(!can_acquire(&spec(Some("flusso-b"), 5), "flusso-a", now));
+        assert!(can_acquire(&spec(Some("flusso-b"), 30), "flusso-a", now));
+        assert!(can_acquire(&LeaseSpec { holder_identity: Some("flusso-b".to_string()), ..Default::default() }, "flusso-a", now));
+    }
+}
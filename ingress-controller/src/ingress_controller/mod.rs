@@ -3,84 +3,142 @@
 //! This module manages the ingress controller for the Flusso application,
 //! orchestrating events related to Kubernetes ingress resources and directing traffic to backends.
 //! The module includes functionalities for setting up an HTTP proxy, processing ingress events,
-//! and managing backend load balancing.
+//! and managing the routing table that maps hosts and paths to backend pools.
 
+pub mod annotations;
+pub mod endpoints;
 pub mod event_listener;
+pub mod events;
+pub mod ingress_class;
 pub mod ingress_processor;
+pub mod leader_election;
+pub mod status;
 
-use crate::proxy::{HttpProxy, load_balancer::LoadBalancer};
+use crate::config::settings::Settings;
+use crate::config::tls::TlsConfig as TlsFiles;
+use crate::health_check::HealthChecker;
+use crate::metrics::Metrics;
+use crate::proxy::{server, HttpProxy, ProxyConfig, router::SharedRouter};
+use crate::proxy::headers::TrustedProxies;
+use crate::proxy::cache::{DEFAULT_CACHE_SIZE, SWEEP_INTERVAL};
+use crate::proxy::http::DEFAULT_MAX_BODY_SIZE;
+use crate::proxy::outlier::{
+    OutlierDetection, DEFAULT_BASE_EJECTION_TIME, DEFAULT_CONSECUTIVE_ERRORS, DEFAULT_MAX_EJECTION_PERCENT,
+};
+use crate::proxy::upgrade::DEFAULT_UPGRADE_IDLE_TIMEOUT;
+use crate::tls::{sni::SniResolver, TlsConfig};
+use endpoints::parse_service_backend;
 use event_listener::EventListener;
+use leader_election::{LeaderElection, DEFAULT_LEASE_NAME};
+use status::{AddressSource, StatusPublisher};
+use kube::Client;
 use ingress_processor::IngressProcessor;
 use std::sync::Arc;
-use tokio::task::LocalSet;
-use actix_web::{web, App, HttpServer, HttpRequest, HttpResponse};
-use bytes::Bytes;  // For handling request bodies as bytes in forward_request
-use reqwest::Method;  // Import HTTP methods from reqwest
-use reqwest::header::{HeaderMap as ReqwestHeaderMap, HeaderName};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
 use futures_util::TryFutureExt;
+use std::time::Duration;
 
 /// The main struct for the Ingress Controller, which manages events from Kubernetes
 /// and forwards incoming HTTP requests to the appropriate backend.
 pub struct IngressController {
     event_listener: EventListener,
+    leader_election: LeaderElection,
     ingress_processor: IngressProcessor,
-    proxy: HttpProxy,
+    /// Proxy shared by the HTTP and HTTPS listeners.
+    proxy: Arc<HttpProxy>,
+    /// Certificates of the HTTPS listener, selected by SNI.
+    sni: Arc<SniResolver>,
 }
 
 impl IngressController {
     /// Creates a new instance of `IngressController`.
     ///
     /// # Parameters
-    /// - `load_balancer`: An `Arc` reference to a shared `LoadBalancer` instance.
+    /// - `router`: The shared routing table updated from Ingress events.
+    /// - `client`: The Kubernetes client used to watch resources.
+    /// - `settings`: The application settings.
+    /// - `ready`: Readiness flag, set once the routes of existing Ingresses are applied.
+    /// - `metrics`: Metrics of the proxy and the controller.
     ///
     /// # Returns
     /// An instance of `IngressController` initialized with an event listener, an ingress processor,
     /// and an HTTP proxy.
-    pub fn new(load_balancer: Arc<LoadBalancer>) -> Self {
+    pub fn new(
+        router: SharedRouter,
+        client: Client,
+        settings: &Settings,
+        ready: watch::Sender<bool>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         println!("Initializing IngressController...");
 
-        // Initialize EventListener with the load balancer and obtain the sender/receiver channel.
-        let (event_listener, rx) = EventListener::new(load_balancer.clone());
+        // Only the elected leader writes Ingress status and Events.
+        let lease_name = settings.leader_election_lease.as_deref().unwrap_or(DEFAULT_LEASE_NAME);
+        let leader_election = LeaderElection::new(client.clone(), lease_name);
+        println!("LeaderElection initialized.");
+
+        // Initialize EventListener and obtain the sender/receiver channel.
+        let address_source = AddressSource::from_settings(
+            settings.publish_service.as_deref(),
+            settings.publish_status_address.as_deref(),
+        );
+        let status = StatusPublisher::new(client.clone(), address_source, leader_election.subscribe());
+        let default_backend = settings.default_backend.as_deref()
+            .filter(|value| !value.is_empty())
+            .and_then(|value| {
+                let parsed = parse_service_backend(value);
+                if parsed.is_none() {
+                    eprintln!("Ignoring malformed default backend {}, expected namespace/service:port", value);
+                }
+                parsed
+            });
+
+        // Certificates come from Ingress `spec.tls` Secrets, falling back to the configured files.
+        let sni = Arc::new(SniResolver::new());
+        if let (Some(cert_path), Some(key_path)) = (&settings.tls_cert_path, &settings.tls_key_path) {
+            let loaded = TlsFiles::load(cert_path, key_path)
+                .map_err(|e| e.into())
+                .and_then(|files| sni.set_fallback(files.certs, &files.key));
+            if let Err(e) = loaded {
+                eprintln!("Failed to load the default certificate from {}: {}", cert_path, e);
+            }
+        }
+
+        let (event_listener, rx) = EventListener::new(
+            client,
+            status,
+            leader_election.subscribe(),
+            default_backend,
+            sni.clone(),
+            metrics.clone(),
+        );
         println!("EventListener initialized.");
 
         // Create IngressProcessor and pass the receiver channel for event processing.
-        let ingress_processor = IngressProcessor::new(load_balancer.clone(), rx);
+        let ingress_processor = IngressProcessor::new(router.clone(), rx, ready);
         println!("IngressProcessor initialized.");
 
-        // Initialize HTTP Proxy with the load balancer.
-        let proxy = HttpProxy::new(load_balancer);
+        // Initialize HTTP Proxy with the routing table.
+        let proxy = Arc::new(HttpProxy::new(router, proxy_config(settings), metrics));
         println!("HttpProxy initialized.");
 
         println!("IngressController fully initialized.");
 
         Self {
             event_listener,
+            leader_election,
             ingress_processor,
             proxy,
+            sni,
         }
     }
 
-    /// Starts the EventListener to listen to Kubernetes events.
-    ///
-    /// Spawns a background task to continuously listen for ingress-related events and updates.
-    pub async fn start(&self) {
-        println!("Starting to listen to events with EventListener...");
-        let listener = self.event_listener.clone();
-    
-        let _ = tokio::spawn(async move {
-            let result = listener.start_listening().await;
-            if let Err(e) = result {
-                eprintln!("Error in EventListener: {:?}", e);
-            }
-        });
-    
-        println!("EventListener is actively listening to events.");
-    }
-    
-    
     /// Processes ingress events with the IngressProcessor.
     ///
-    /// Continuously listens for events and updates the load balancer's backend list accordingly.
+    /// Continuously listens for events and updates the routes and backend pools accordingly.
     pub async fn process_events(&mut self) {
         self.ingress_processor.process_events().await;
     }
@@ -92,21 +150,45 @@ impl IngressController {
 /// background tasks for event listening and processing. It handles any initialization or runtime errors
 /// by printing detailed messages to the console.
 ///
+/// On a termination signal the servers stop accepting connections and drain the open ones,
+/// the Lease is released if held, and the function returns once they are done.
+///
 /// # Parameters
-/// - `load_balancer`: Shared `LoadBalancer` instance for managing backend traffic.
-/// - `server_addr`: The address on which the HTTP server will listen.
+/// - `router`: Shared routing table mapping hosts and paths to backend pools.
+/// - `settings`: The application settings, including the address the HTTP server listens on.
+/// - `ready`: Readiness flag, set once the routes of existing Ingresses are applied.
+/// - `metrics`: Metrics of the proxy and the controller, exported to Prometheus.
 ///
 /// # Returns
 /// A `Result<(), Box<dyn std::error::Error + Send + Sync>>` indicating success or error.
 pub async fn start_ingress_controller(
-    load_balancer: Arc<LoadBalancer>,
-    server_addr: &str,
+    router: SharedRouter,
+    settings: &Settings,
+    ready: watch::Sender<bool>,
+    metrics: Arc<Metrics>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    println!("Starting ingress controller on {}", server_addr);
+    println!("Starting ingress controller on {}", settings.server_addr);
+
+    let server_addr = settings.server_addr.clone();
+    let terminate = signal(SignalKind::terminate())?;
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let client = Client::try_default().await?;
+    let health_checker = HealthChecker::new(router.clone());
+    let mut controller = IngressController::new(router, client, settings, ready, metrics);
 
-    let server_addr = server_addr.to_string();
-    let load_balancer_clone = load_balancer.clone();
-    let mut controller = IngressController::new(load_balancer);
+    let proxy = controller.proxy.clone();
+    let http_listener = TcpListener::bind(&server_addr).await?;
+
+    // Terminate TLS on a second listener when enabled
+    let https = match settings.tls_enabled {
+        true => {
+            let https_addr = settings.https_addr.clone().unwrap_or_else(|| "0.0.0.0:8443".to_string());
+            println!("Serving HTTPS on {}", https_addr);
+            let tls = TlsAcceptor::from(TlsConfig::new(controller.sni.clone()).config);
+            Some((TcpListener::bind(&https_addr).await?, tls))
+        }
+        false => None,
+    };
 
     // Start listening for events in a background task
     let start_task = tokio::spawn({
@@ -116,86 +198,87 @@ pub async fn start_ingress_controller(
                 eprintln!("Error in EventListener: {:?}", e);
             }
         }
+    });
+
+    // Take part in the leader election in another background task
+    let election_task = tokio::spawn({
+        let leader_election = controller.leader_election.clone();
+        let shutdown = shutdown_rx.clone();
+        async move { leader_election.run(shutdown).await }
     })
     .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>);
 
     // Start processing events in another background task
     let process_task = tokio::spawn(async move {
         controller.process_events().await;
+    });
+
+    // Probe the backends of the Ingresses enabling health checks
+    let health_check_task = tokio::spawn(health_checker.run());
+
+    // Drop the cached responses that can no longer be used
+    let cache_sweep_task = tokio::spawn(proxy.cache().clone().run_sweeper(SWEEP_INTERVAL));
+
+    // Serve HTTP/1.1 and HTTP/2 clients on both listeners
+    let http_server_task = tokio::spawn(server::serve(http_listener, None, proxy.clone(), shutdown_rx.clone()))
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>);
+    let https_server_task = tokio::spawn(async move {
+        if let Some((https_listener, tls)) = https {
+            server::serve(https_listener, Some(tls), proxy, shutdown_rx).await;
+        }
     })
     .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>);
 
-    // Creates a persistent LocalSet instance for the HTTP server
-    let local_set = LocalSet::new();
-    let server_addr_clone = server_addr.clone();
-
-    let http_server_task = local_set
-        .run_until(async move {
-            HttpServer::new(move || {
-                let http_proxy = HttpProxy::new(load_balancer_clone.clone());
-                App::new()
-                    .app_data(web::Data::new(http_proxy))
-                    .default_service(web::route().to(forward_request))
-            })
-            .bind(server_addr_clone)?
-            .run()
-            .await
-        })
-        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>);
+    // Tell the election and the servers to stop on a termination signal
+    tokio::spawn(signal_shutdown(terminate, shutdown));
 
-    // Run all tasks concurrently and print completion message on success
-    tokio::try_join!(start_task, process_task, http_server_task)?;
+    // Run until the election and the servers are done, then stop the background tasks
+    tokio::try_join!(election_task, http_server_task, https_server_task)?;
+    for task in [start_task, process_task, health_check_task, cache_sweep_task] {
+        task.abort();
+    }
 
-    println!("Ingress controller started successfully on {}", server_addr);
+    println!("Ingress controller on {} shut down", server_addr);
     Ok(())
 }
 
-/// Forwards incoming HTTP requests to the backend using `HttpProxy`.
-///
-/// # Parameters
-/// - `req`: The incoming HTTP request.
-/// - `body`: The request body as bytes.
-/// - `proxy`: A data reference to the `HttpProxy` instance.
-///
-/// # Returns
-/// A `HttpResponse` containing the response from the backend server, or an internal error if the forwarding fails.
-async fn forward_request(
-    req: HttpRequest,
-    body: Bytes,
-    proxy: web::Data<HttpProxy>,
-) -> HttpResponse {
-    let path = req.uri().path().to_string();
-    let method = match req.method() {
-        &actix_web::http::Method::GET => Method::GET,
-        &actix_web::http::Method::POST => Method::POST,
-        &actix_web::http::Method::PUT => Method::PUT,
-        &actix_web::http::Method::DELETE => Method::DELETE,
-        _ => Method::GET, // Default method
-    };
-
-    // Convert Actix-web headers to Reqwest headers
-    let mut headers = ReqwestHeaderMap::new();
-    for (key, value) in req.headers().iter() {
-        if let Ok(header_name) = HeaderName::from_bytes(key.as_str().as_bytes()) {
-            if let Ok(header_value_str) = value.to_str() {
-                if let Ok(header_value) = reqwest::header::HeaderValue::from_str(header_value_str) {
-                    headers.insert(header_name, header_value);
-                }
-            }
-        }
+/// Sets the shutdown flag once the process receives a termination signal or Ctrl-C.
+async fn signal_shutdown(mut terminate: Signal, shutdown: watch::Sender<bool>) {
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
     }
+    println!("Shutting down the ingress controller...");
+    shutdown.send_replace(true);
+}
 
-    println!("Forwarding request to path: {}", path);
-
-    // Forward the request to the backend through HttpProxy
-    match proxy.forward_request(&path, method, headers, Some(body)).await {
-        Ok(response) => {
-            // Convert `reqwest` status code to `actix_web` status code
-            let status = actix_web::http::StatusCode::from_u16(response.status().as_u16())
-                .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
-            let body = response.text().await.unwrap_or_default();
-            HttpResponse::build(status).body(body)
+/// Builds the proxy settings, trusting no proxy if the trusted proxy list is invalid.
+fn proxy_config(settings: &Settings) -> ProxyConfig {
+    let trusted_proxies = match TrustedProxies::parse(settings.trusted_proxies.as_deref().unwrap_or_default()) {
+        Ok(trusted_proxies) => trusted_proxies,
+        Err(e) => {
+            eprintln!("Ignoring trusted proxies: {}", e);
+            TrustedProxies::default()
         }
-        Err(_) => HttpResponse::InternalServerError().body("Error forwarding request"),
+    };
+    ProxyConfig {
+        max_body_size: settings.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+        trusted_proxies,
+        upgrade_idle_timeout: settings.upgrade_idle_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_UPGRADE_IDLE_TIMEOUT),
+        outlier_detection: match settings.outlier_consecutive_errors.unwrap_or(DEFAULT_CONSECUTIVE_ERRORS) {
+            0 => None,
+            consecutive_errors => Some(OutlierDetection {
+                consecutive_errors,
+                base_ejection_time: settings.outlier_base_ejection_time
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_BASE_EJECTION_TIME),
+                max_ejection_percent: settings.outlier_max_ejection_percent
+                    .unwrap_or(DEFAULT_MAX_EJECTION_PERCENT)
+                    .min(100),
+            }),
+        },
+        cache_size: settings.cache_size.unwrap_or(DEFAULT_CACHE_SIZE),
     }
 }
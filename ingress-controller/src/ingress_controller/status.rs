@@ -0,0 +1,266 @@
+//! Status module publishing the controller address into Ingress status.
+//!
+//! The `StatusPublisher` struct fills `status.loadBalancer.ingress` of the Ingresses
+//! handled by flusso, so that tools like external-dns and `kubectl get ingress` see the
+//! address traffic should be sent to. The address is either a static list from the
+//! settings or the load balancer address of a publish Service, read from the Service
+//! store of the event listener. Only the leader replica writes Ingress status.
+//!
+//! The status is written with server-side apply under the `flusso` field manager, so the
+//! `managedFields` of an Ingress tell whether flusso published its address there. Only
+//! such statuses are cleared when flusso no longer claims the Ingress, which also drops
+//! the ownership: a status written by another controller is never touched.
+
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::api::networking::v1::{Ingress, IngressLoadBalancerIngress};
+use kube::api::{Api, Patch, PatchParams};
+use kube::Client;
+use kube_runtime::reflector::{ObjectRef, Store};
+use serde_json::json;
+use std::error::Error;
+use std::net::IpAddr;
+use tokio::sync::watch;
+
+/// Field manager owning the Ingress status fields written by flusso.
+pub const FIELD_MANAGER: &str = "flusso";
+
+/// Where the address published into Ingress status comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AddressSource {
+    /// A static list of IPs and hostnames.
+    Static(Vec<String>),
+    /// The load balancer address of a Service, as `namespace/name`.
+    Service(String),
+    /// No address is published.
+    None,
+}
+
+impl AddressSource {
+    /// Builds the address source from the settings values.
+    ///
+    /// # Parameters
+    /// - `publish_service`: The publish Service, as `namespace/name`.
+    /// - `publish_status_address`: A comma-separated list of IPs and hostnames, which takes
+    ///   precedence over the publish Service.
+    pub fn from_settings(publish_service: Option<&str>, publish_status_address: Option<&str>) -> Self {
+        let addresses: Vec<String> = publish_status_address.unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        match publish_service {
+            _ if !addresses.is_empty() => AddressSource::Static(addresses),
+            Some(service) if !service.is_empty() => AddressSource::Service(service.to_string()),
+            _ => AddressSource::None,
+        }
+    }
+}
+
+/// Writes the controller address into the status of Ingresses.
+#[derive(Clone)]
+pub struct StatusPublisher {
+    client: Client,
+    source: AddressSource,
+    /// Whether this replica is the leader.
+    leader: watch::Receiver<bool>,
+}
+
+impl StatusPublisher {
+    /// Creates a new `StatusPublisher` for an address source.
+    ///
+    /// # Parameters
+    /// - `client`: Kubernetes client used to patch Ingress status.
+    /// - `source`: Where the published address comes from.
+    /// - `leader`: Leadership state of this replica; status is left untouched while not leader.
+    pub fn new(client: Client, source: AddressSource, leader: watch::Receiver<bool>) -> Self {
+        Self { client, source, leader }
+    }
+
+    /// Returns `true` if the addresses come from the given Service, as `namespace/name`.
+    pub fn publishes_service(&self, service_key: &str) -> bool {
+        matches!(&self.source, AddressSource::Service(key) if publish_service_ref(key) == publish_service_ref(service_key))
+    }
+
+    /// Publishes the controller address into the status of an Ingress claimed by flusso.
+    ///
+    /// The status is only written when it differs from the current one or is not owned by
+    /// flusso yet. Without any address to publish, the status is cleared instead.
+    ///
+    /// # Parameters
+    /// - `ingress`: The Ingress to publish the address of.
+    /// - `services`: The Service store, holding the publish Service.
+    pub async fn publish(&self, ingress: &Ingress, services: &Store<Service>) -> Result<(), Box<dyn Error>> {
+        if self.source == AddressSource::None || !*self.leader.borrow() {
+            return Ok(());
+        }
+        let addresses = self.addresses(services)?;
+        if addresses.is_empty() {
+            return Ok(self.clear(ingress).await?);
+        }
+
+        let current = ingress.status.as_ref()
+            .and_then(|status| status.load_balancer.as_ref())
+            .and_then(|load_balancer| load_balancer.ingress.clone())
+            .unwrap_or_default();
+        if current == addresses && owns_status(ingress) {
+            return Ok(());
+        }
+
+        self.apply(ingress, json!({ "loadBalancer": { "ingress": addresses } })).await?;
+        Ok(())
+    }
+
+    /// Clears the status of an Ingress that is no longer claimed by flusso, if flusso
+    /// published its address there.
+    ///
+    /// Clearing drops the ownership of flusso over the status, so it happens only once.
+    pub async fn clear(&self, ingress: &Ingress) -> kube::Result<()> {
+        if !*self.leader.borrow() || !owns_status(ingress) {
+            return Ok(());
+        }
+        self.apply(ingress, json!({})).await
+    }
+
+    /// Resolves the addresses to publish.
+    fn addresses(&self, services: &Store<Service>) -> Result<Vec<IngressLoadBalancerIngress>, String> {
+        match &self.source {
+            AddressSource::Static(addresses) => Ok(addresses.iter().map(|address| to_status(address)).collect()),
+            AddressSource::Service(key) => match services.get(&publish_service_ref(key)) {
+                Some(service) => Ok(service_addresses(&service)),
+                None => Err(format!("Publish service {} does not exist", key)),
+            },
+            AddressSource::None => Ok(Vec::new()),
+        }
+    }
+
+    /// Applies the status fields owned by flusso on an Ingress; fields left out are removed.
+    async fn apply(&self, ingress: &Ingress, status: serde_json::Value) -> kube::Result<()> {
+        let namespace = ingress.metadata.namespace.clone().unwrap_or_default();
+        let name = ingress.metadata.name.clone().unwrap_or_default();
+        let ingresses: Api<Ingress> = Api::namespaced(self.client.clone(), &namespace);
+        let patch = json!({
+            "apiVersion": "networking.k8s.io/v1",
+            "kind": "Ingress",
+            "metadata": { "name": name, "namespace": namespace },
+            "status": status,
+        });
+
+        println!("Updating status of ingress {}/{}", namespace, name);
+        ingresses.patch_status(&name, &PatchParams::apply(FIELD_MANAGER).force(), &Patch::Apply(&patch)).await?;
+        Ok(())
+    }
+}
+
+/// Returns `true` if flusso owns fields of the status of an Ingress, which it then wrote.
+fn owns_status(ingress: &Ingress) -> bool {
+    ingress.metadata.managed_fields.iter().flatten().any(|entry| {
+        entry.manager.as_deref() == Some(FIELD_MANAGER) && entry.subresource.as_deref() == Some("status")
+    })
+}
+
+/// Returns the reference of the publish Service, written as `namespace/name` or `name`
+/// in the `default` namespace.
+fn publish_service_ref(key: &str) -> ObjectRef<Service> {
+    let (namespace, name) = key.split_once('/').unwrap_or(("default", key));
+    ObjectRef::new(name).within(namespace)
+}
+
+/// Builds a status entry from an address, as an IP if it parses as one or a hostname.
+fn to_status(address: &str) -> IngressLoadBalancerIngress {
+    match address.parse::<IpAddr>() {
+        Ok(_) => IngressLoadBalancerIngress { ip: Some(address.to_string()), ..Default::default() },
+        Err(_) => IngressLoadBalancerIngress { hostname: Some(address.to_string()), ..Default::default() },
+    }
+}
+
+/// Returns the addresses of a Service: its load balancer ingress, or its external IPs.
+fn service_addresses(service: &Service) -> Vec<IngressLoadBalancerIngress> {
+    let load_balancer = service.status.as_ref()
+        .and_then(|status| status.load_balancer.as_ref())
+        .and_then(|load_balancer| load_balancer.ingress.as_ref());
+
+    match load_balancer {
+        Some(entries) if !entries.is_empty() => entries.iter()
+            .map(|entry| IngressLoadBalancerIngress {
+                ip: entry.ip.clone(),
+                hostname: entry.hostname.clone(),
+                ..Default::default()
+            })
+            .collect(),
+        _ => service.spec.as_ref()
+            .and_then(|spec| spec.external_ips.as_ref())
+            .map(|ips| ips.iter().map(|ip| to_status(ip)).collect())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::core::v1::{LoadBalancerIngress, LoadBalancerStatus, ServiceSpec, ServiceStatus};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ManagedFieldsEntry;
+
+    #[test]
+    fn test_address_source_from_settings() {
+        assert_eq!(
+            AddressSource::from_settings(Some("ingress/flusso"), Some("10.0.0.1, lb.example.com")),
+            AddressSource::Static(vec!["10.0.0.1".to_string(), "lb.example.com".to_string()])
+        );
+        assert_eq!(
+            AddressSource::from_settings(Some("ingress/flusso"), Some("")),
+            AddressSource::Service("ingress/flusso".to_string())
+        );
+        assert_eq!(AddressSource::from_settings(None, None), AddressSource::None);
+    }
+
+    #[test]
+    fn test_service_addresses() {
+        let mut service = Service {
+            spec: Some(ServiceSpec {
+                external_ips: Some(vec!["192.0.2.10".to_string()]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(service_addresses(&service), vec![to_status("192.0.2.10")]);
+
+        service.status = Some(ServiceStatus {
+            load_balancer: Some(LoadBalancerStatus {
+                ingress: Some(vec![LoadBalancerIngress {
+                    hostname: Some("lb.example.com".to_string()),
+                    ..Default::default()
+                }]),
+            }),
+            ..Default::default()
+        });
+        assert_eq!(service_addresses(&service), vec![to_status("lb.example.com")]);
+    }
+
+    #[test]
+    fn test_owns_status() {
+        let entry = |manager: &str, subresource: Option<&str>| ManagedFieldsEntry {
+            manager: Some(manager.to_string()),
+            subresource: subresource.map(str::to_string),
+            ..Default::default()
+        };
+        let mut ingress = Ingress::default();
+        assert!(!owns_status(&ingress));
+
+        ingress.metadata.managed_fields = Some(vec![entry("kubectl", None), entry(FIELD_MANAGER, None)]);
+        assert!(!owns_status(&ingress));
+
+        ingress.metadata.managed_fields = Some(vec![entry("other-controller", Some("status"))]);
+        assert!(!owns_status(&ingress));
+
+        ingress.metadata.managed_fields = Some(vec![entry("kubectl", None), entry(FIELD_MANAGER, Some("status"))]);
+        assert!(owns_status(&ingress));
+    }
+
+    #[test]
+    fn test_publish_service_ref() {
+        assert_eq!(publish_service_ref("ingress/flusso"), ObjectRef::new("flusso").within("ingress"));
+        assert_eq!(publish_service_ref("flusso"), ObjectRef::new("flusso").within("default"));
+    }
+}
@@ -1,38 +1,41 @@
 //! Main entry point for the Flusso ingress controller application.
 //!
-//! This program initializes and starts both the ingress controller and the GUI server
+//! This program initializes and starts the ingress controller, the GUI server and the metrics server
 //! concurrently, using asynchronous execution. Configuration settings are loaded from
-//! a configuration file, and a load balancer is set up to manage backend services.
+//! a configuration file, and a routing table is set up to manage backend services.
 //!
 //! # Modules and Functions
 //! - `Settings`: Manages application configuration settings.
-//! - `LoadBalancer`: Handles backend service distribution for requests.
+//! - `Router`: Maps request hosts and paths to backend pools built from Ingress rules.
 //! - `start_ingress_controller`: Starts the ingress controller to handle incoming requests.
 //! - `start_gui_server`: Launches a GUI server for managing and monitoring backend services.
+//! - `start_metrics_server`: Serves the proxy and controller metrics to Prometheus.
 
 use std::sync::Arc;
 use std::error::Error;
 use flusso::config::settings::Settings;
 use flusso::gui::gui_server::start_gui_server;
-use flusso::proxy::load_balancer::LoadBalancer;
+use flusso::metrics::{start_metrics_server, Metrics, DEFAULT_METRICS_PORT};
+use flusso::proxy::router::{Router, SharedRouter};
 use flusso::ingress_controller::start_ingress_controller;
 
 use futures_util::TryFutureExt;
 use rustls::crypto;
+use tokio::sync::watch;
 
 /// Main function of the Flusso application.
 /// 
-/// Initializes the cryptographic provider, loads application settings, creates a load balancer,
-/// and starts both the ingress controller and the GUI server concurrently.
+/// Initializes the cryptographic provider, loads application settings, creates a routing table,
+/// and starts the ingress controller, the GUI server and the metrics server concurrently.
 /// 
 /// # Returns
-/// - `Ok(())` if both the ingress controller and GUI server start successfully.
+/// - `Ok(())` if the ingress controller, GUI server and metrics server start successfully.
 /// - `Err(Box<dyn Error + Send + Sync>)` if there is an error during initialization or runtime.
 /// 
 /// # Errors
 /// - Returns an error if there are issues with setting the default cryptographic provider,
-///   loading configuration settings, or running either of the main tasks (ingress controller
-///   or GUI server).
+///   loading configuration settings, or running either of the main tasks (ingress controller,
+///   GUI server or metrics server).
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     // Set up the default cryptographic provider at the process level.
@@ -44,34 +47,50 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let settings = Settings::new().expect("Failed to load configuration");
     println!("Configuration loaded: {:?}", settings);
 
-    // Initialize the load balancer with an empty list of backends.
-    // The load balancer will manage request distribution across backends.
-    let load_balancer = Arc::new(LoadBalancer::new(Vec::new()));
-    println!("Load balancer initialized.");
+    // Initialize an empty routing table.
+    // The ingress controller fills it with routes and backend pools from Ingress rules.
+    let router = SharedRouter::new(Router::new());
+    println!("Routing table initialized.");
+
+    // Readiness flag, set by the ingress controller once existing Ingresses are routed
+    // and reported by the GUI server on `/readyz`.
+    let (ready_tx, ready_rx) = watch::channel(false);
 
     // Set the GUI server port, defaulting to 8081 if not specified in the settings.
     let gui_port = settings.gui_port.unwrap_or(8081);
     println!("The GUI server will start on port: {}", gui_port);
 
-    // Start both the ingress controller and the GUI server concurrently.
-    // Uses `tokio::try_join!` to run both tasks asynchronously and handle any errors.
+    // Metrics recorded by the proxy and the controller, served on a port of their own.
+    let metrics = Arc::new(Metrics::new());
+    let metrics_port = settings.metrics_port.unwrap_or(DEFAULT_METRICS_PORT);
+    println!("The metrics server will start on port: {}", metrics_port);
+
+    // Start the ingress controller, the GUI server and the metrics server concurrently.
+    // Uses `tokio::try_join!` to run the tasks asynchronously and handle any errors.
     tokio::try_join!(
-        // Start the ingress controller, passing in the load balancer and server address.
-        start_ingress_controller(load_balancer.clone(), &settings.server_addr)
+        // Start the ingress controller, passing in the routing table and settings.
+        start_ingress_controller(router.clone(), &settings, ready_tx, metrics.clone())
             .map_err(|e| {
                 eprintln!("Error in start_ingress_controller: {:?}", e);
-                Box::<dyn std::error::Error + Send + Sync>::from(e)
+                e
             }),
 
-        // Start the GUI server, passing in the load balancer and specified port.
-        start_gui_server(load_balancer.clone(), gui_port)
+        // Start the GUI server, passing in the routing table and specified port.
+        start_gui_server(router.clone(), ready_rx, gui_port)
             .map_err(|e| {
                 eprintln!("Error in start_gui_server: {:?}", e);
                 Box::<dyn std::error::Error + Send + Sync>::from(e)
+            }),
+
+        // Start the metrics server, passing in the metrics and the routing table.
+        start_metrics_server(metrics, router.clone(), metrics_port)
+            .map_err(|e| {
+                eprintln!("Error in start_metrics_server: {:?}", e);
+                Box::<dyn std::error::Error + Send + Sync>::from(e)
             })
     )?;
 
-    println!("Both tasks completed successfully.");
+    println!("All tasks completed successfully.");
 
     Ok(())
 }
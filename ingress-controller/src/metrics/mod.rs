@@ -1,3 +1,8 @@
-// Módulo de métricas
-// src/metrics/mod.rs
+//! Metrics module for monitoring the proxy and the controller.
+//!
+//! The `prometheus` module records the metrics of proxied requests and of the controller,
+//! and serves them to Prometheus on a dedicated port.
 
+pub mod prometheus;
+
+pub use self::prometheus::{start_metrics_server, Metrics, RequestMetrics, RouteLabels, DEFAULT_METRICS_PORT};
@@ -1,4 +1,404 @@
-// Exportador de métricas para Prometheus
-// src/metrics/prometheus.rs
+//! Prometheus exporter for the proxy and controller metrics.
+//!
+//! `Metrics` holds a registry with the metrics of proxied requests, labelled by the
+//! Ingress, namespace, host, path and backend pool each request was routed to, and the
+//! metrics of the controller itself. Hosts and paths are those of the matching Ingress
+//! rule, not of the request, so that clients cannot grow the number of series.
+//!
+//! `start_metrics_server` serves the metrics in the Prometheus text format on `/metrics`,
+//! on a port of its own.
 
+use ::prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use crate::proxy::router::{Route, Router, SharedRouter};
+use std::sync::Arc;
+use std::time::Instant;
 
+/// Port of the metrics listener when none is configured.
+pub const DEFAULT_METRICS_PORT: u16 = 10254;
+
+/// Labels identifying the route of a proxied request.
+const ROUTE_LABELS: [&str; 5] = ["ingress", "namespace", "host", "path", "backend"];
+
+/// Upper bounds of the request duration buckets, in seconds.
+const DURATION_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Upper bounds of the response size buckets, in bytes.
+const SIZE_BUCKETS: [f64; 8] = [100.0, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9];
+
+/// Status recorded for requests whose client went away before a response was sent, as
+/// done by nginx.
+const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+/// Where a request was routed, as reported in the labels of the request metrics.
+///
+/// Every label is empty for requests that match no route.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteLabels {
+    /// Name of the Ingress, empty for the controller default backend.
+    pub ingress: String,
+    /// Namespace of the Ingress.
+    pub namespace: String,
+    /// Host of the matching rule, empty for rules without a host and default backends.
+    pub host: String,
+    /// Path of the matching rule, empty for default backends.
+    pub path: String,
+    /// Backend pool, as `namespace/service:port`.
+    pub backend: String,
+}
+
+impl RouteLabels {
+    /// Builds the labels of a request routed by an Ingress (`namespace/name`) and route.
+    pub fn new(ingress: Option<&str>, route: Option<&Route>, backend: &str) -> Self {
+        let (namespace, ingress) = ingress.and_then(|ingress| ingress.split_once('/')).unwrap_or_default();
+        Self {
+            ingress: ingress.to_string(),
+            namespace: namespace.to_string(),
+            host: route.and_then(|route| route.host.clone()).unwrap_or_default(),
+            path: route.map(|route| route.path.clone()).unwrap_or_default(),
+            backend: backend.to_string(),
+        }
+    }
+
+    fn values(&self) -> [&str; 5] {
+        [&self.ingress, &self.namespace, &self.host, &self.path, &self.backend]
+    }
+}
+
+/// Metrics of the proxy and the controller, registered for export to Prometheus.
+pub struct Metrics {
+    registry: Registry,
+    /// Proxied requests, by route, method and status.
+    requests: IntCounterVec,
+    /// Time from receiving a request to sending the end of its response, by route.
+    request_duration: HistogramVec,
+    /// Size of the response bodies sent to clients, by route.
+    response_size: HistogramVec,
+    /// Failures to connect to a backend, by route.
+    upstream_connect_errors: IntCounterVec,
+    /// Requests being proxied, including upgraded connections being relayed, by route.
+    active_connections: IntGaugeVec,
+    /// Requests of the Ingresses enabling caching, by route and result.
+    cache_requests: IntCounterVec,
+    /// Ingress reconciliations, by result.
+    reconciles: IntCounterVec,
+    /// Failures of the watches on Kubernetes resources, by resource kind.
+    watch_errors: IntCounterVec,
+    /// Number of addresses in each backend pool, updated when scraped.
+    backend_pool_size: IntGaugeVec,
+}
+
+impl Metrics {
+    /// Creates the metrics, registered in a registry of their own.
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let route_with_status: Vec<&str> = ROUTE_LABELS.iter().copied().chain(["method", "status"]).collect();
+
+        let requests = IntCounterVec::new(
+            Opts::new("flusso_requests_total", "Requests proxied, by route, method and status."),
+            &route_with_status,
+        ).unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("flusso_request_duration_seconds", "Time to proxy a request, until the end of its response.")
+                .buckets(DURATION_BUCKETS.to_vec()),
+            &ROUTE_LABELS,
+        ).unwrap();
+        let response_size = HistogramVec::new(
+            HistogramOpts::new("flusso_response_size_bytes", "Size of the response bodies sent to clients.")
+                .buckets(SIZE_BUCKETS.to_vec()),
+            &ROUTE_LABELS,
+        ).unwrap();
+        let upstream_connect_errors = IntCounterVec::new(
+            Opts::new("flusso_upstream_connect_errors_total", "Failures to connect to a backend."),
+            &ROUTE_LABELS,
+        ).unwrap();
+        let active_connections = IntGaugeVec::new(
+            Opts::new("flusso_active_connections", "Requests and upgraded connections being proxied."),
+            &ROUTE_LABELS,
+        ).unwrap();
+        let cache_requests = IntCounterVec::new(
+            Opts::new("flusso_cache_requests_total", "Requests of the Ingresses enabling caching, by route and cache result."),
+            &ROUTE_LABELS.iter().copied().chain(["result"]).collect::<Vec<_>>(),
+        ).unwrap();
+        let reconciles = IntCounterVec::new(
+            Opts::new("flusso_reconcile_total", "Ingress reconciliations, by result."),
+            &["result"],
+        ).unwrap();
+        let watch_errors = IntCounterVec::new(
+            Opts::new("flusso_watch_errors_total", "Failures of the watches on Kubernetes resources."),
+            &["resource"],
+        ).unwrap();
+        let backend_pool_size = IntGaugeVec::new(
+            Opts::new("flusso_backend_pool_size", "Number of addresses in a backend pool."),
+            &["backend"],
+        ).unwrap();
+
+        // Names are unique and constant, so registering cannot fail
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_duration.clone())).unwrap();
+        registry.register(Box::new(response_size.clone())).unwrap();
+        registry.register(Box::new(upstream_connect_errors.clone())).unwrap();
+        registry.register(Box::new(active_connections.clone())).unwrap();
+        registry.register(Box::new(cache_requests.clone())).unwrap();
+        registry.register(Box::new(reconciles.clone())).unwrap();
+        registry.register(Box::new(watch_errors.clone())).unwrap();
+        registry.register(Box::new(backend_pool_size.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            response_size,
+            upstream_connect_errors,
+            active_connections,
+            cache_requests,
+            reconciles,
+            watch_errors,
+            backend_pool_size,
+        }
+    }
+
+    /// Starts recording the metrics of a request.
+    ///
+    /// # Parameters
+    /// - `method`: The method of the request.
+    ///
+    /// # Returns
+    /// The `RequestMetrics` of the request, recorded when dropped.
+    pub fn start_request(self: &Arc<Self>, method: &str) -> RequestMetrics {
+        RequestMetrics {
+            metrics: self.clone(),
+            method: method_label(method),
+            labels: RouteLabels::default(),
+            status: CLIENT_CLOSED_REQUEST,
+            response_size: 0,
+            routed: false,
+            started: Instant::now(),
+        }
+    }
+
+    /// Counts an Ingress reconciliation.
+    pub fn reconciled(&self, success: bool) {
+        let result = if success { "success" } else { "error" };
+        self.reconciles.with_label_values(&[result]).inc();
+    }
+
+    /// Counts a failure of the watch on a resource kind, such as `Ingress`.
+    pub fn watch_failed(&self, resource: &str) {
+        self.watch_errors.with_label_values(&[resource]).inc();
+    }
+
+    /// Encodes every metric in the Prometheus text format.
+    ///
+    /// # Parameters
+    /// - `router`: The routing table, whose backend pool sizes are reported.
+    pub fn encode(&self, router: &Router) -> Result<String, ::prometheus::Error> {
+        // Pools dropped from the routing table disappear from the metrics
+        self.backend_pool_size.reset();
+        for (backend, pool) in router.pools() {
+            self.backend_pool_size.with_label_values(&[backend]).set(pool.get_backends().len() as i64);
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer).unwrap_or_default())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Metrics of a request being proxied, recorded when dropped.
+///
+/// It is kept until the end of the response body was sent, or until an upgraded
+/// connection was closed.
+pub struct RequestMetrics {
+    metrics: Arc<Metrics>,
+    method: String,
+    labels: RouteLabels,
+    status: u16,
+    response_size: u64,
+    /// Whether the request is counted in the active connections of its route.
+    routed: bool,
+    started: Instant,
+}
+
+impl RequestMetrics {
+    /// Records the route a request was sent to, counting it as active until dropped.
+    pub fn routed(&mut self, labels: RouteLabels) {
+        if self.routed {
+            self.metrics.active_connections.with_label_values(&self.labels.values()).dec();
+        }
+        self.metrics.active_connections.with_label_values(&labels.values()).inc();
+        self.labels = labels;
+        self.routed = true;
+    }
+
+    /// Counts a failure to connect to the backend of the request.
+    pub fn connect_failed(&self) {
+        self.metrics.upstream_connect_errors.with_label_values(&self.labels.values()).inc();
+    }
+
+    /// Counts how the cache handled the request: `hit`, `stale`, `miss`, `revalidated` or `bypass`.
+    pub fn cache_result(&self, result: &str) {
+        let mut labels = self.labels.values().to_vec();
+        labels.push(result);
+        self.metrics.cache_requests.with_label_values(&labels).inc();
+    }
+
+    /// Sets the status of the response sent to the client.
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    /// Adds bytes sent to the client to the size of the response.
+    pub fn add_response_bytes(&mut self, bytes: u64) {
+        self.response_size += bytes;
+    }
+}
+
+impl Drop for RequestMetrics {
+    fn drop(&mut self) {
+        let metrics = &self.metrics;
+        let labels = self.labels.values();
+        if self.routed {
+            metrics.active_connections.with_label_values(&labels).dec();
+        }
+
+        let status = self.status.to_string();
+        let mut with_status = labels.to_vec();
+        with_status.extend([self.method.as_str(), status.as_str()]);
+        metrics.requests.with_label_values(&with_status).inc();
+        metrics.request_duration.with_label_values(&labels).observe(self.started.elapsed().as_secs_f64());
+        metrics.response_size.with_label_values(&labels).observe(self.response_size as f64);
+    }
+}
+
+/// Returns the label of a request method, grouping non-standard methods under `OTHER`.
+fn method_label(method: &str) -> String {
+    match method {
+        "GET" | "HEAD" | "POST" | "PUT" | "DELETE" | "CONNECT" | "OPTIONS" | "TRACE" | "PATCH" => method.to_string(),
+        _ => "OTHER".to_string(),
+    }
+}
+
+/// Serves the metrics on `/metrics`.
+async fn metrics(metrics: web::Data<Arc<Metrics>>, router: web::Data<SharedRouter>) -> impl Responder {
+    let encoded = metrics.encode(&router.load());
+    match encoded {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(::prometheus::TEXT_FORMAT)
+            .body(body),
+        Err(e) => {
+            eprintln!("Failed to encode metrics: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Starts the server exposing the metrics to Prometheus.
+///
+/// # Parameters
+/// - `metrics`: The metrics to expose.
+/// - `router`: The routing table, whose backend pool sizes are reported.
+/// - `port`: The port to serve `/metrics` on.
+pub async fn start_metrics_server(metrics: Arc<Metrics>, router: SharedRouter, port: u16) -> std::io::Result<()> {
+    HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(router.clone()))
+            .route("/metrics", web::get().to(self::metrics))
+    })
+    .bind(("0.0.0.0", port))?
+    .run()
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::router::PathType;
+
+    #[test]
+    fn test_route_labels() {
+        let route = Route {
+            ingress: "shop/web".to_string(),
+            host: Some("shop.example.com".to_string()),
+            path: "/api".to_string(),
+            path_type: PathType::Prefix,
+            backend: "shop/api:80".to_string(),
+        };
+        let labels = RouteLabels::new(Some("shop/web"), Some(&route), "shop/api:80");
+        assert_eq!(labels.values(), ["web", "shop", "shop.example.com", "/api", "shop/api:80"]);
+
+        let labels = RouteLabels::new(None, None, "flusso/fallback:80");
+        assert_eq!(labels.values(), ["", "", "", "", "flusso/fallback:80"]);
+    }
+
+    #[test]
+    fn test_records_requests_when_dropped() {
+        let metrics = Arc::new(Metrics::new());
+        let labels = RouteLabels::new(Some("shop/web"), None, "shop/web:80");
+        let route = r#"backend="shop/web:80",host="",ingress="web",namespace="shop",path="""#;
+
+        let mut request = metrics.start_request("GET");
+        request.routed(labels.clone());
+        request.cache_result("hit");
+        request.set_status(200);
+        request.add_response_bytes(1500);
+        let encoded = metrics.encode(&Router::new()).unwrap();
+        assert!(encoded.contains(&format!("flusso_active_connections{{{}}} 1\n", route)));
+        drop(request);
+
+        let mut request = metrics.start_request("BREW");
+        request.routed(labels);
+        request.connect_failed();
+        request.set_status(502);
+        drop(request);
+        // The client of this one went away before it was routed
+        drop(metrics.start_request("GET"));
+
+        let encoded = metrics.encode(&Router::new()).unwrap();
+        assert!(encoded.contains(&format!("flusso_active_connections{{{}}} 0\n", route)));
+        // Labels are sorted by name
+        let requests = |method: &str, status: u16| format!(
+            "flusso_requests_total{{backend=\"shop/web:80\",host=\"\",ingress=\"web\",method=\"{}\",namespace=\"shop\",path=\"\",status=\"{}\"}} 1\n",
+            method, status,
+        );
+        assert!(encoded.contains(&requests("GET", 200)));
+        assert!(encoded.contains(&requests("OTHER", 502)));
+        assert!(encoded.contains(r#"flusso_requests_total{backend="",host="",ingress="",method="GET",namespace="",path="",status="499"} 1"#));
+        assert!(encoded.contains(&format!("flusso_upstream_connect_errors_total{{{}}} 1\n", route)));
+        assert!(encoded.contains(
+            "flusso_cache_requests_total{backend=\"shop/web:80\",host=\"\",ingress=\"web\",namespace=\"shop\",path=\"\",result=\"hit\"} 1\n",
+        ));
+        assert!(encoded.contains(&format!("flusso_response_size_bytes_bucket{{{},le=\"1000\"}} 1\n", route)));
+        assert!(encoded.contains(&format!("flusso_response_size_bytes_bucket{{{},le=\"10000\"}} 2\n", route)));
+        assert!(encoded.contains(&format!("flusso_request_duration_seconds_count{{{}}} 2\n", route)));
+    }
+
+    #[test]
+    fn test_reports_controller_metrics() {
+        let metrics = Metrics::new();
+        let mut router = Router::new();
+        router.pool("shop/web:80").set_backends(vec!["10.0.0.1:80".parse().unwrap(), "10.0.0.2:80".parse().unwrap()]);
+        metrics.reconciled(true);
+        metrics.reconciled(false);
+        metrics.watch_failed("Ingress");
+
+        let encoded = metrics.encode(&router).unwrap();
+        assert!(encoded.contains("flusso_backend_pool_size{backend=\"shop/web:80\"} 2\n"));
+        assert!(encoded.contains("flusso_reconcile_total{result=\"error\"} 1\n"));
+        assert!(encoded.contains("flusso_reconcile_total{result=\"success\"} 1\n"));
+        assert!(encoded.contains("flusso_watch_errors_total{resource=\"Ingress\"} 1\n"));
+
+        // Pools dropped from the routing table are no longer reported
+        let encoded = metrics.encode(&Router::new()).unwrap();
+        assert!(!encoded.contains("flusso_backend_pool_size{"));
+    }
+}
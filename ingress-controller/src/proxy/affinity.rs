@@ -0,0 +1,307 @@
+//! Session affinity module sending the requests of a client to the same backend.
+//!
+//! An Ingress can keep clients on a backend in two ways:
+//! - With an affinity cookie: the first response to a client sets a cookie naming the
+//!   backend that served it, and later requests carrying the cookie go to that backend
+//!   for as long as it is available.
+//! - With consistent hashing: a key read from the request (the client IP address, a header
+//!   or a cookie) is looked up on a `HashRing` of the backends. Adding or removing a
+//!   backend only moves the keys of its share of the ring, and a backend that is down or
+//!   ejected only moves its own keys to the next backends on the ring.
+//!
+//! Requests without a cookie or key are balanced with the strategy of the Ingress.
+
+use reqwest::header::{HeaderMap, HeaderValue, COOKIE};
+use std::net::SocketAddr;
+use std::time::Duration;
+use super::headers::ClientInfo;
+use super::load_balancer::LoadBalancer;
+use super::strategy::LoadBalancing;
+
+/// Name of the affinity cookie, when none is configured.
+pub const DEFAULT_AFFINITY_COOKIE: &str = "FLUSSO_AFFINITY";
+
+/// Points each backend gets on a hash ring, spreading keys evenly across backends.
+const POINTS_PER_BACKEND: usize = 160;
+
+/// How the requests of a client are kept on the same backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionAffinity {
+    /// An affinity cookie set by the proxy names the backend.
+    Cookie(AffinityCookie),
+    /// A key read from the request is hashed to a backend.
+    Hash(HashKey),
+}
+
+/// The cookie naming the backend of a client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AffinityCookie {
+    pub name: String,
+    /// Lifetime of the cookie, or `None` for a cookie lasting until the browser closes.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for AffinityCookie {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_AFFINITY_COOKIE.to_string(),
+            max_age: None,
+        }
+    }
+}
+
+/// Part of a request hashed to choose its backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HashKey {
+    /// The address of the client connection.
+    ClientIp,
+    /// A request header, by lowercase name.
+    Header(String),
+    /// A request cookie, by name.
+    Cookie(String),
+}
+
+impl HashKey {
+    /// Returns the key of a request, if it has one.
+    fn read(&self, headers: &HeaderMap, client: &ClientInfo) -> Option<Vec<u8>> {
+        match self {
+            HashKey::ClientIp => client.addr.map(|addr| addr.to_string().into_bytes()),
+            HashKey::Header(name) => headers.get(name.as_str()).map(|value| value.as_bytes().to_vec()),
+            HashKey::Cookie(name) => cookie(headers, name).map(|value| value.as_bytes().to_vec()),
+        }
+    }
+}
+
+/// The backend chosen for a request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub backend: SocketAddr,
+    /// `Set-Cookie` value to add to the response, pinning the client to the backend.
+    pub set_cookie: Option<HeaderValue>,
+}
+
+/// Selects the backend of a request, keeping clients on their backend if the Ingress
+/// routing the request enables session affinity.
+///
+/// # Parameters
+/// - `pool`: The backend pool serving the request.
+/// - `load_balancing`: The strategy balancing requests without affinity.
+/// - `affinity`: The session affinity of the Ingress, if any.
+/// - `headers`: The headers of the request, holding its cookies.
+/// - `client`: The connection the request was received on.
+///
+/// # Returns
+/// The selected backend, or `None` if the pool has no available backend.
+pub fn select(
+    pool: &LoadBalancer,
+    load_balancing: LoadBalancing,
+    affinity: Option<&SessionAffinity>,
+    headers: &HeaderMap,
+    client: &ClientInfo,
+) -> Option<Selection> {
+    match affinity {
+        Some(SessionAffinity::Cookie(affinity_cookie)) => {
+            let pinned = cookie(headers, &affinity_cookie.name).and_then(|id| pool.select_by_id(id));
+            if let Some(backend) = pinned {
+                return Some(Selection { backend, set_cookie: None });
+            }
+            let backend = pool.select(load_balancing)?;
+            let set_cookie = set_cookie(affinity_cookie, backend, client.secure);
+            Some(Selection { backend, set_cookie })
+        }
+        Some(SessionAffinity::Hash(key)) => {
+            let backend = match key.read(headers, client) {
+                Some(key) => pool.select_by_hash(hash(&key)),
+                None => pool.select(load_balancing),
+            }?;
+            Some(Selection { backend, set_cookie: None })
+        }
+        None => pool.select(load_balancing).map(|backend| Selection { backend, set_cookie: None }),
+    }
+}
+
+/// Returns the identifier of a backend in affinity cookies, which does not reveal its address.
+pub fn backend_id(backend: &SocketAddr) -> String {
+    format!("{:016x}", hash(backend.to_string().as_bytes()))
+}
+
+/// Builds the `Set-Cookie` value pinning a client to a backend.
+fn set_cookie(cookie: &AffinityCookie, backend: SocketAddr, secure: bool) -> Option<HeaderValue> {
+    let mut value = format!("{}={}; Path=/; HttpOnly", cookie.name, backend_id(&backend));
+    if let Some(max_age) = cookie.max_age {
+        value.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+    }
+    if secure {
+        value.push_str("; Secure");
+    }
+    HeaderValue::from_str(&value).ok()
+}
+
+/// Returns the value of a request cookie.
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value)
+}
+
+/// Hashes bytes with 64-bit FNV-1a, mixed so that close inputs land far apart.
+///
+/// Unlike the standard library hasher, the hash is the same in every controller replica
+/// and release, so that clients keep their backend across them.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    // Finalizer of SplitMix64
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Consistent hash ring placing backends at many points, each key going to the backend of
+/// the first point at or after its hash.
+#[derive(Clone, Debug, Default)]
+pub struct HashRing {
+    /// Points of the ring, sorted by hash.
+    points: Vec<(u64, SocketAddr)>,
+}
+
+impl HashRing {
+    /// Builds the ring of a list of backends.
+    pub fn new(backends: &[SocketAddr]) -> Self {
+        let mut points: Vec<_> = backends.iter()
+            .flat_map(|backend| {
+                (0..POINTS_PER_BACKEND).map(move |point| (hash(format!("{}-{}", backend, point).as_bytes()), *backend))
+            })
+            .collect();
+        points.sort_unstable();
+        Self { points }
+    }
+
+    /// Returns the backend of a key, skipping the backends that are not available.
+    ///
+    /// # Parameters
+    /// - `key`: The hash of the key.
+    /// - `available`: Tells whether a backend can serve requests.
+    pub fn get(&self, key: u64, available: impl Fn(&SocketAddr) -> bool) -> Option<SocketAddr> {
+        let start = self.points.partition_point(|(point, _)| *point < key);
+        self.points[start..]
+            .iter()
+            .chain(&self.points[..start])
+            .map(|(_, backend)| *backend)
+            .find(|backend| available(backend))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    /// Maps 10,000 keys to their backend on a ring.
+    fn assignments(ring: &HashRing) -> Vec<SocketAddr> {
+        (0..10_000).map(|key| ring.get(hash(format!("client-{}", key).as_bytes()), |_| true).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_ring_spreads_keys_evenly() {
+        let ring = HashRing::new(&[addr(1), addr(2), addr(3), addr(4)]);
+        let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+        for backend in assignments(&ring) {
+            *counts.entry(backend).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        assert!(counts.values().all(|count| (2_000..=3_000).contains(count)), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_ring_remaps_few_keys_when_backends_change() {
+        let before = assignments(&HashRing::new(&[addr(1), addr(2), addr(3)]));
+
+        // Keys only move to an added backend, about a quarter of them
+        let after = assignments(&HashRing::new(&[addr(1), addr(2), addr(3), addr(4)]));
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(old, new)| old != new).collect();
+        assert!(moved.iter().all(|(_, new)| **new == addr(4)));
+        assert!((1_500..=3_500).contains(&moved.len()), "{} keys moved", moved.len());
+
+        // Only the keys of a removed backend move
+        let after = assignments(&HashRing::new(&[addr(1), addr(3)]));
+        assert!(before.iter().zip(&after).all(|(old, new)| old == new || *old == addr(2)));
+
+        // Unavailable backends only lose their own keys
+        let ring = HashRing::new(&[addr(1), addr(2), addr(3)]);
+        let key = (0..).map(|key: u32| hash(&key.to_be_bytes())).find(|key| ring.get(*key, |_| true) == Some(addr(2))).unwrap();
+        let fallback = ring.get(key, |backend| *backend != addr(2)).unwrap();
+        assert_ne!(fallback, addr(2));
+        assert_eq!(ring.get(key, |_| false), None);
+    }
+
+    #[test]
+    fn test_cookie_affinity() {
+        let pool = LoadBalancer::new(vec![addr(1), addr(2), addr(3)]);
+        let affinity = SessionAffinity::Cookie(AffinityCookie { max_age: Some(Duration::from_secs(3600)), ..Default::default() });
+        let client = ClientInfo { secure: true, ..Default::default() };
+
+        let first = select(&pool, LoadBalancing::RoundRobin, Some(&affinity), &HeaderMap::new(), &client).unwrap();
+        let set_cookie = first.set_cookie.unwrap();
+        assert_eq!(
+            set_cookie.to_str().unwrap(),
+            format!("FLUSSO_AFFINITY={}; Path=/; HttpOnly; Max-Age=3600; Secure", backend_id(&first.backend)),
+        );
+
+        // Requests carrying the cookie stick to the backend, without setting it again
+        let mut headers = HeaderMap::new();
+        let cookie = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        headers.insert(COOKIE, HeaderValue::from_str(&format!("theme=dark; {}", cookie)).unwrap());
+        for _ in 0..5 {
+            let selection = select(&pool, LoadBalancing::RoundRobin, Some(&affinity), &headers, &client).unwrap();
+            assert_eq!(selection, Selection { backend: first.backend, set_cookie: None });
+        }
+
+        // A backend that went down is replaced, and the cookie updated
+        pool.mark_down(first.backend);
+        let selection = select(&pool, LoadBalancing::RoundRobin, Some(&affinity), &headers, &client).unwrap();
+        assert_ne!(selection.backend, first.backend);
+        assert!(selection.set_cookie.is_some());
+    }
+
+    #[test]
+    fn test_hash_affinity() {
+        let pool = LoadBalancer::new(vec![addr(1), addr(2), addr(3)]);
+        let by_header = SessionAffinity::Hash(HashKey::Header("x-user".to_string()));
+        let client = ClientInfo::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user", HeaderValue::from_static("alice"));
+
+        let backend = select(&pool, LoadBalancing::RoundRobin, Some(&by_header), &headers, &client).unwrap().backend;
+        for _ in 0..5 {
+            let selection = select(&pool, LoadBalancing::RoundRobin, Some(&by_header), &headers, &client).unwrap();
+            assert_eq!(selection, Selection { backend, set_cookie: None });
+        }
+
+        let by_ip = SessionAffinity::Hash(HashKey::ClientIp);
+        let client = ClientInfo { addr: Some("192.0.2.7".parse().unwrap()), ..Default::default() };
+        let backend = select(&pool, LoadBalancing::Random, Some(&by_ip), &headers, &client).unwrap().backend;
+        for _ in 0..5 {
+            assert_eq!(select(&pool, LoadBalancing::Random, Some(&by_ip), &headers, &client).unwrap().backend, backend);
+        }
+
+        // Requests without the key are balanced as usual
+        let by_cookie = SessionAffinity::Hash(HashKey::Cookie("session".to_string()));
+        let selected: Vec<_> = (0..3)
+            .map(|_| select(&pool, LoadBalancing::RoundRobin, Some(&by_cookie), &headers, &client).unwrap().backend)
+            .collect();
+        assert_eq!(selected.len(), 3);
+        assert!(selected.contains(&addr(1)) && selected.contains(&addr(2)) && selected.contains(&addr(3)));
+    }
+}
@@ -1,62 +1,1032 @@
-//! Cache module for storing and retrieving HTTP responses.
+//! Cache module storing backend responses, as a shared cache following RFC 9111.
 //!
-//! The `Cache` struct provides a time-limited storage of responses using a
-//! specified time-to-live (TTL) duration.
+//! The `Cache` struct keeps the responses to `GET` requests of the Ingresses enabling
+//! caching, keyed by host and path (with the query). A stored response answers later
+//! requests for the same key while it is fresh:
+//! - Its freshness lifetime comes from the `s-maxage` or `max-age` directives of its
+//!   `Cache-Control` header, or else from its `Expires` header, or else is a tenth of the
+//!   time since it was last modified, for the statuses cacheable by default.
+//! - Its age accounts for the `Age` and `Date` headers of the backend and for the time
+//!   spent in the cache.
+//! - Responses listing request headers in `Vary` are stored once per combination of
+//!   values of these headers.
+//!
+//! Stale responses carrying an `ETag` or `Last-Modified` header are revalidated with a
+//! conditional request, and refreshed when the backend answers `304 Not Modified`.
+//! Within the grace periods of their `stale-while-revalidate` and `stale-if-error`
+//! directives (RFC 5861), or of their Ingress when they have none, stale responses are
+//! still served while revalidated in the background, or when the backend fails.
+//! Responses marked `private` or `no-store`, setting cookies, or answering requests with
+//! `Authorization` (unless explicitly allowed) are not stored, and requests with unsafe
+//! methods invalidate the stored responses for their path.
+//!
+//! The stored responses are bounded by a budget of bytes, evicting the least recently used
+//! keys once exceeded, and those that can be neither served nor revalidated are swept
+//! periodically. Requests missing the cache while another request fetches the same key
+//! from a backend wait for its response rather than reaching the backend as well.
+//!
+//! Responses built by the cache carry an `Age` header and, as every response of a caching
+//! Ingress, a `Cache-Status` header (RFC 9211) telling how the cache handled the request.
 
-use std::collections::HashMap;
+use bytes::Bytes;
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_LOCATION, DATE,
+    ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE, SET_COOKIE, VARY,
+};
+use http::{Method, Response, StatusCode};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::time::{self, interval, MissedTickBehavior};
+
+/// Header telling how the cache handled a request, as defined by RFC 9211.
+pub const CACHE_STATUS: HeaderName = HeaderName::from_static("cache-status");
+
+/// Name of the cache in `Cache-Status` headers.
+const CACHE_NAME: &str = "flusso";
+
+/// Largest response body stored, in bytes.
+pub const MAX_ENTRY_SIZE: usize = 1024 * 1024;
+
+/// Size of the stored responses when no budget is configured, in bytes.
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Interval between two sweeps of the responses that can no longer be used.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest time a request waits for another request fetching the same response.
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest freshness lifetime guessed for responses without an explicit one.
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Statuses whose responses can be stored without explicit freshness (RFC 9110 §15.1).
+const HEURISTICALLY_CACHEABLE: [u16; 10] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 501];
+
+/// Directives of the `Cache-Control` headers of a request or response.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub max_age: Option<Duration>,
+    pub s_maxage: Option<Duration>,
+    pub min_fresh: Option<Duration>,
+    pub stale_while_revalidate: Option<Duration>,
+    pub stale_if_error: Option<Duration>,
+}
+
+impl CacheControl {
+    /// Reads the directives of every `Cache-Control` header, ignoring unknown ones.
+    ///
+    /// Durations that are not a number of seconds are read as zero, so that responses
+    /// with invalid freshness are stale, and overly large ones as the largest duration.
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        let seconds = |value: Option<&str>| {
+            let value = value.unwrap_or_default().trim_matches('"');
+            match !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()) {
+                true => Some(Duration::from_secs(value.parse().unwrap_or(u64::MAX))),
+                false => Some(Duration::ZERO),
+            }
+        };
+        let values = headers.get_all(CACHE_CONTROL).into_iter().filter_map(|value| value.to_str().ok());
+        for directive in values.flat_map(|value| value.split(',')) {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (directive.trim(), None),
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+                "max-age" => directives.max_age = seconds(value),
+                "s-maxage" => directives.s_maxage = seconds(value),
+                "min-fresh" => directives.min_fresh = seconds(value),
+                "stale-while-revalidate" => directives.stale_while_revalidate = seconds(value),
+                "stale-if-error" => directives.stale_if_error = seconds(value),
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+/// A response stored in the cache, along with what is needed to tell its age.
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    status: StatusCode,
+    /// End-to-end headers of the response.
+    headers: HeaderMap,
+    body: Bytes,
+    /// Values of the request headers listed in `Vary`, which later requests must match.
+    vary: Vec<(HeaderName, Option<String>)>,
+    /// Age of the response when it was received (RFC 9111 §4.2.3).
+    initial_age: Duration,
+    /// When the response was received.
+    received: SystemTime,
+    /// Time the response stays fresh for.
+    freshness: Duration,
+    /// Time the response can be served stale while revalidated, once no longer fresh.
+    stale_while_revalidate: Option<Duration>,
+    /// Time the response can be served stale when the backend fails, once no longer fresh.
+    stale_if_error: Option<Duration>,
+}
+
+impl CachedResponse {
+    /// Builds the stored copy of a backend response.
+    ///
+    /// # Parameters
+    /// - `request`: The headers of the request the response answers.
+    /// - `status`, `headers`, `body`: The response, without hop-by-hop headers.
+    /// - `requested`: When the request was sent to the backend.
+    /// - `received`: When the response headers were received.
+    pub fn new(
+        request: &HeaderMap,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        requested: SystemTime,
+        received: SystemTime,
+    ) -> Self {
+        let vary = vary(&headers)
+            .into_iter()
+            .map(|name| {
+                let value = joined(request, &name);
+                (name, value)
+            })
+            .collect();
+        let mut response = Self {
+            status,
+            headers,
+            body,
+            vary,
+            initial_age: Duration::ZERO,
+            received,
+            freshness: Duration::ZERO,
+            stale_while_revalidate: None,
+            stale_if_error: None,
+        };
+        response.update_age(requested, received);
+        response
+    }
+
+    /// Returns the response refreshed by a `304 Not Modified` answer to its revalidation,
+    /// whose end-to-end headers replace the stored ones.
+    pub fn freshen(&self, not_modified: &HeaderMap, requested: SystemTime, received: SystemTime) -> Self {
+        let mut response = self.clone();
+        for name in not_modified.keys() {
+            if *name != CONTENT_LENGTH {
+                response.headers.remove(name);
+                for value in not_modified.get_all(name) {
+                    response.headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        response.received = received;
+        response.update_age(requested, received);
+        response
+    }
+
+    /// Computes the initial age and freshness lifetime of the response from its headers.
+    fn update_age(&mut self, requested: SystemTime, received: SystemTime) {
+        let date = http_date(&self.headers, DATE).unwrap_or(received);
+        let apparent_age = received.duration_since(date).unwrap_or_default();
+        let age_value = self.headers.get(AGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map_or(Duration::ZERO, Duration::from_secs);
+        let response_delay = received.duration_since(requested).unwrap_or_default();
+        self.initial_age = apparent_age.max(age_value.saturating_add(response_delay));
+        self.freshness = freshness_lifetime(self.status, &self.headers, date);
+        let directives = CacheControl::parse(&self.headers);
+        // Responses to revalidate at every use are never served stale
+        let revalidated = (directives.no_cache || directives.must_revalidate).then_some(Duration::ZERO);
+        self.stale_while_revalidate = revalidated.or(directives.stale_while_revalidate);
+        self.stale_if_error = revalidated.or(directives.stale_if_error);
+    }
+
+    /// Returns the response with a grace period during which it can be served stale, for
+    /// the directives its `Cache-Control` header lacks.
+    pub fn with_stale_grace(mut self, grace: Option<Duration>) -> Self {
+        self.stale_while_revalidate = self.stale_while_revalidate.or(grace);
+        self.stale_if_error = self.stale_if_error.or(grace);
+        self
+    }
+
+    /// Returns the age of the response.
+    pub fn age(&self, now: SystemTime) -> Duration {
+        self.initial_age.saturating_add(now.duration_since(self.received).unwrap_or_default())
+    }
+
+    /// Returns `true` while the response can be served without revalidation.
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        self.freshness > self.age(now)
+    }
+
+    /// Returns `true` while the response has been stale for less than `grace`.
+    fn is_stale_within(&self, now: SystemTime, grace: Option<Duration>) -> bool {
+        grace.is_some_and(|grace| self.age(now) < self.freshness.saturating_add(grace))
+    }
+
+    /// Returns `true` while the response can be served stale because the backend failed.
+    pub fn serves_stale_if_error(&self, now: SystemTime) -> bool {
+        self.is_fresh(now) || self.is_stale_within(now, self.stale_if_error)
+    }
+
+    /// Returns `true` once the response can be neither served, even stale, nor revalidated.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        let grace = self.stale_while_revalidate.max(self.stale_if_error);
+        !self.is_fresh(now) && !self.has_validator() && !self.is_stale_within(now, grace)
+    }
+
+    /// Returns `true` if the response has a validator for conditional requests.
+    pub fn has_validator(&self) -> bool {
+        self.headers.contains_key(ETAG) || self.headers.contains_key(LAST_MODIFIED)
+    }
+
+    /// Replaces the conditional headers of a request with the validators of the response,
+    /// if it has any.
+    pub fn add_validators(&self, request: &mut HeaderMap) {
+        if !self.has_validator() {
+            return;
+        }
+        request.remove(IF_NONE_MATCH);
+        request.remove(IF_MODIFIED_SINCE);
+        if let Some(etag) = self.headers.get(ETAG) {
+            request.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(LAST_MODIFIED) {
+            request.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
 
-/// Represents an entry in the cache with response data and a timestamp.
-#[derive(Clone)]
-pub struct CacheEntry {
-    pub response: Vec<u8>,       // Stores response in bytes
-    pub timestamp: Instant,      // Marks the storage time
+    /// Returns `true` if the response was stored for the values of the `Vary` headers of
+    /// a request.
+    fn matches(&self, request: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, value)| joined(request, name) == *value)
+    }
+
+    /// Returns `true` if a conditional request is answered with `304 Not Modified` by this
+    /// response (RFC 9110 §13.2.2).
+    fn is_not_modified_for(&self, request: &HeaderMap) -> bool {
+        if request.contains_key(IF_NONE_MATCH) {
+            let Some(etag) = self.headers.get(ETAG).and_then(|etag| etag.to_str().ok()) else {
+                return false;
+            };
+            return request.get_all(IF_NONE_MATCH)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|tag| tag.trim() == "*" || weak_eq(tag.trim(), etag));
+        }
+        match (http_date(request, IF_MODIFIED_SINCE), http_date(&self.headers, LAST_MODIFIED)) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Builds the response answering a request from the cache.
+    ///
+    /// # Parameters
+    /// - `request`: The headers of the request, whose conditions are evaluated.
+    /// - `head`: Whether the request is a `HEAD` request, answered without body.
+    /// - `now`: The current time, setting the `Age` header.
+    /// - `status`: The parameters of the `Cache-Status` header, such as `hit`.
+    pub fn respond(&self, request: &HeaderMap, head: bool, now: SystemTime, status: &str) -> Response<Bytes> {
+        let mut response = if self.is_not_modified_for(request) {
+            let mut response = Response::new(Bytes::new());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            for name in [CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, VARY] {
+                for value in self.headers.get_all(&name) {
+                    response.headers_mut().append(name.clone(), value.clone());
+                }
+            }
+            response
+        } else {
+            let mut response = Response::new(if head { Bytes::new() } else { self.body.clone() });
+            *response.status_mut() = self.status;
+            *response.headers_mut() = self.headers.clone();
+            if self.status != StatusCode::NO_CONTENT && !self.headers.contains_key(CONTENT_LENGTH) {
+                response.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+            }
+            response
+        };
+        response.headers_mut().insert(AGE, HeaderValue::from(self.age(now).as_secs()));
+        response.headers_mut().insert(CACHE_STATUS, cache_status(status));
+        response
+    }
+
+    /// Returns the size of the response body and headers, in bytes.
+    pub fn size(&self) -> usize {
+        let headers: usize = self.headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+        self.body.len() + headers
+    }
+}
+
+/// What the cache holds for a request.
+#[derive(Debug)]
+pub enum Lookup {
+    /// The request is not answered from the cache, such as a `POST` or a range request.
+    Bypass,
+    /// A stored response fresh enough to answer the request.
+    Hit(Arc<CachedResponse>),
+    /// No stored response for the path (`vary` is `false`) or for the `Vary` headers of
+    /// the request.
+    Miss { vary: bool },
+    /// A stored response that must be revalidated before answering the request.
+    Stale(Arc<CachedResponse>),
+    /// A stale response answering the request while it is revalidated in the background.
+    StaleWhileRevalidate(Arc<CachedResponse>),
+}
+
+/// A response being fetched from a backend for a key, which the other requests for the
+/// key wait for.
+type Fetches = Arc<Mutex<HashMap<String, watch::Receiver<()>>>>;
+
+/// The responses stored for a key.
+#[derive(Debug)]
+struct Entry {
+    /// One response per combination of `Vary` values, the most recent first.
+    variants: Vec<Arc<CachedResponse>>,
+    /// Size of the key and of the responses, in bytes.
+    size: usize,
+    /// Tick of the last use of the entry, ordering the entries in `Entries::recency`.
+    used: u64,
+}
+
+/// The stored responses, evicted least recently used first once they outgrow the budget.
+#[derive(Debug, Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+    /// Keys of the entries by tick of their last use, the least recently used first.
+    recency: BTreeMap<u64, String>,
+    /// Tick of the last use of any entry.
+    tick: u64,
+    /// Size of the stored entries, in bytes.
+    size: usize,
+}
+
+impl Entries {
+    /// Marks an entry as the most recently used one.
+    fn touch(&mut self, key: &str) {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        self.tick += 1;
+        if let Some(key) = self.recency.remove(&entry.used) {
+            self.recency.insert(self.tick, key);
+        }
+        entry.used = self.tick;
+    }
+
+    /// Removes an entry.
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+            self.size -= entry.size;
+        }
+    }
 }
 
-/// Cache for HTTP responses with a time-to-live (TTL) for entries.
-#[derive(Clone)]
+/// Shared cache of backend responses, keyed by host and path.
+///
+/// The size of the stored responses is bounded by a budget: storing a response evicts
+/// the least recently used keys until the cache fits in it again. Clones share the same
+/// responses.
+#[derive(Clone, Debug)]
 pub struct Cache {
-    data: Arc<Mutex<HashMap<String, CacheEntry>>>,
-    ttl: Duration,
+    entries: Arc<Mutex<Entries>>,
+    /// Most bytes stored at a time.
+    capacity: usize,
+    fetches: Fetches,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_SIZE)
+    }
 }
 
 impl Cache {
-    /// Creates a new cache with the specified TTL (in seconds).
-    pub fn new(ttl_seconds: u64) -> Self {
+    /// Creates an empty cache storing at most `capacity` bytes of responses.
+    pub fn new(capacity: usize) -> Self {
         Self {
-            data: Arc::new(Mutex::new(HashMap::new())),
-            ttl: Duration::from_secs(ttl_seconds),
+            entries: Arc::new(Mutex::new(Entries::default())),
+            capacity,
+            fetches: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    /// Stores a response in the cache with a unique key.
-    pub fn store(&self, key: String, response: Vec<u8>) {
-        let entry = CacheEntry {
-            response,
-            timestamp: Instant::now(),
+    /// Finds the stored response answering a request, and marks its key as recently used.
+    ///
+    /// # Parameters
+    /// - `key`: The key of the request, as built by `cache_key`.
+    /// - `method`: The method of the request.
+    /// - `request`: The headers of the request.
+    /// - `now`: The current time, telling whether stored responses are fresh.
+    pub fn lookup(&self, key: &str, method: &Method, request: &HeaderMap, now: SystemTime) -> Lookup {
+        if (method != Method::GET && method != Method::HEAD) || request.contains_key(RANGE) {
+            return Lookup::Bypass;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.touch(key);
+        let Some(entry) = entries.entries.get(key) else {
+            return Lookup::Miss { vary: false };
+        };
+        let Some(response) = entry.variants.iter().find(|response| response.matches(request)) else {
+            return Lookup::Miss { vary: true };
         };
-        let mut data = self.data.lock().unwrap();
-        data.insert(key, entry);
+
+        let directives = CacheControl::parse(request);
+        let age = response.age(now);
+        let old_enough = !directives.no_cache && directives.max_age.is_none_or(|max_age| age <= max_age);
+        let fresh_enough = response.is_fresh(now)
+            && old_enough
+            && directives.min_fresh.is_none_or(|min_fresh| response.freshness.saturating_sub(age) >= min_fresh);
+        if fresh_enough {
+            Lookup::Hit(response.clone())
+        } else if old_enough && directives.min_fresh.is_none() && response.is_stale_within(now, response.stale_while_revalidate) {
+            Lookup::StaleWhileRevalidate(response.clone())
+        } else {
+            Lookup::Stale(response.clone())
+        }
     }
 
-    /// Retrieves a response from the cache if it is still valid.
-    pub fn retrieve(&self, key: &str) -> Option<Vec<u8>> {
-        let mut data = self.data.lock().unwrap();
-        if let Some(entry) = data.get(key) {
-            if entry.timestamp.elapsed() < self.ttl {
-                return Some(entry.response.clone());
-            } else {
-                data.remove(key);
+    /// Stores a response, replacing the one stored for the same `Vary` values, then evicts
+    /// the least recently used keys until the cache fits in its budget. Responses larger
+    /// than the budget are not stored.
+    pub fn store(&self, key: String, response: Arc<CachedResponse>) {
+        let size = key.len() + response.size();
+        if size > self.capacity {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        entries.tick += 1;
+        let tick = entries.tick;
+        let entry = entries.entries.entry(key.clone()).or_insert_with(|| Entry { variants: Vec::new(), size: 0, used: 0 });
+        entries.recency.remove(&entry.used);
+        entries.size -= entry.size;
+        entry.variants.retain(|stored| stored.vary != response.vary);
+        entry.variants.insert(0, response);
+        entry.size = key.len() + entry.variants.iter().map(|variant| variant.size()).sum::<usize>();
+        entry.used = tick;
+        entries.size += entry.size;
+        entries.recency.insert(tick, key);
+
+        while entries.size > self.capacity {
+            let Some(oldest) = entries.recency.first_key_value().map(|(_, key)| key.clone()) else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+    }
+
+    /// Drops every response stored for a key.
+    pub fn invalidate(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Drops the responses that can be neither served, even stale, nor revalidated anymore.
+    ///
+    /// # Returns
+    /// The number of responses dropped.
+    pub fn sweep(&self, now: SystemTime) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        let mut swept = 0;
+        let keys: Vec<String> = entries.entries.keys().cloned().collect();
+        for key in keys {
+            let Some(entry) = entries.entries.get_mut(&key) else {
+                continue;
+            };
+            let before = entry.variants.len();
+            entry.variants.retain(|response| !response.is_expired(now));
+            swept += before - entry.variants.len();
+            if entry.variants.is_empty() {
+                entries.remove(&key);
+                continue;
+            }
+            let size = key.len() + entry.variants.iter().map(|variant| variant.size()).sum::<usize>();
+            entries.size -= entry.size - size;
+            entry.size = size;
+        }
+        swept
+    }
+
+    /// Sweeps the cache every `period`, forever.
+    pub async fn run_sweeper(self, period: Duration) {
+        let mut ticks = interval(period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let swept = self.sweep(SystemTime::now());
+            if swept > 0 {
+                println!("Swept {} expired responses from the cache", swept);
             }
         }
-        None
     }
 
-    /// Cleans expired entries from the cache.
-    pub fn clean_expired(&self) {
-        let mut data = self.data.lock().unwrap();
-        let ttl = self.ttl;
-        data.retain(|_, entry| entry.timestamp.elapsed() < ttl);
+    /// Coalesces the requests fetching a response for a key from a backend.
+    ///
+    /// The first request gets a `FetchGuard` it holds until the response is stored, and the
+    /// requests arriving meanwhile get a `FetchWait` for it, after which they look the
+    /// cache up again.
+    pub fn fetch(&self, key: &str) -> Fetch {
+        let mut fetches = self.fetches.lock().unwrap();
+        if let Some(done) = fetches.get(key) {
+            return Fetch::Waiting(FetchWait(done.clone()));
+        }
+        let (sender, receiver) = watch::channel(());
+        fetches.insert(key.to_string(), receiver);
+        Fetch::First(FetchGuard { fetches: self.fetches.clone(), key: key.to_string(), _done: sender })
+    }
+
+    /// Returns the number of stored responses.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().entries.values().map(|entry| entry.variants.len()).sum()
+    }
+
+    /// Returns `true` if no response is stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the size of the stored responses and their keys, in bytes.
+    pub fn size(&self) -> usize {
+        self.entries.lock().unwrap().size
+    }
+}
+
+/// Whether a request fetches a response from a backend or waits for another request to.
+#[derive(Debug)]
+pub enum Fetch {
+    /// No other request is fetching a response for the key.
+    First(FetchGuard),
+    /// Another request is fetching a response for the key.
+    Waiting(FetchWait),
+}
+
+/// Held by the request fetching a response for a key; dropping it wakes the requests
+/// waiting for the response.
+#[derive(Debug)]
+pub struct FetchGuard {
+    fetches: Fetches,
+    key: String,
+    /// Closes the channel the waiting requests listen to when dropped.
+    _done: watch::Sender<()>,
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        self.fetches.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Lets a request wait for another request to fetch the response for its key.
+#[derive(Debug)]
+pub struct FetchWait(watch::Receiver<()>);
+
+impl FetchWait {
+    /// Waits until the other request is done, or for at most `timeout`.
+    pub async fn wait(mut self, timeout: Duration) {
+        // Nothing is ever sent: the channel closes when the fetching request is done
+        let _ = time::timeout(timeout, self.0.changed()).await;
+    }
+}
+
+/// Returns the key of the responses for a request host and path (with the query).
+pub fn cache_key(host: &str, path: &str) -> String {
+    format!("{}{}", host, path)
+}
+
+/// Returns `true` if a shared cache can store the response to a request (RFC 9111 §3).
+///
+/// # Parameters
+/// - `method`: The method of the request.
+/// - `request`: The headers of the request.
+/// - `status`: The status of the response.
+/// - `response`: The headers of the response.
+pub fn is_storable(method: &Method, request: &HeaderMap, status: StatusCode, response: &HeaderMap) -> bool {
+    let directives = CacheControl::parse(response);
+    let explicit = directives.s_maxage.is_some() || directives.max_age.is_some() || response.contains_key(EXPIRES);
+    *method == Method::GET
+        && !status.is_informational()
+        && status != StatusCode::PARTIAL_CONTENT
+        && status != StatusCode::NOT_MODIFIED
+        && !directives.no_store
+        && !directives.private
+        && !CacheControl::parse(request).no_store
+        && !response.contains_key(SET_COOKIE)
+        && !vary(response).iter().any(|name| name.as_str() == "*")
+        && (!request.contains_key(AUTHORIZATION)
+            || directives.public
+            || directives.s_maxage.is_some()
+            || directives.must_revalidate)
+        && (explicit || directives.public || HEURISTICALLY_CACHEABLE.contains(&status.as_u16()))
+}
+
+/// Returns `true` if a response with this status lets a stale response be served instead,
+/// per `stale-if-error` (RFC 5861 §4).
+pub fn is_error(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 500 | 502 | 503 | 504)
+}
+
+/// Returns `true` if a request with a method answered with a status invalidates the
+/// stored responses for its path (RFC 9111 §4.4).
+pub fn invalidates(method: &Method, status: StatusCode) -> bool {
+    !method.is_safe() && (status.is_success() || status.is_redirection())
+}
+
+/// Builds a `Cache-Status` header value from its parameters, such as `hit`.
+pub fn cache_status(parameters: &str) -> HeaderValue {
+    HeaderValue::from_str(&format!("{}; {}", CACHE_NAME, parameters)).unwrap_or(HeaderValue::from_static(CACHE_NAME))
+}
+
+/// Returns the freshness lifetime of a response (RFC 9111 §4.2.1).
+fn freshness_lifetime(status: StatusCode, headers: &HeaderMap, date: SystemTime) -> Duration {
+    let directives = CacheControl::parse(headers);
+    if directives.no_cache {
+        return Duration::ZERO;
+    }
+    if let Some(lifetime) = directives.s_maxage.or(directives.max_age) {
+        return lifetime;
+    }
+    if headers.contains_key(EXPIRES) {
+        // Invalid dates, such as `0`, mean the response has already expired
+        return http_date(headers, EXPIRES)
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or_default();
+    }
+    match (HEURISTICALLY_CACHEABLE.contains(&status.as_u16()), http_date(headers, LAST_MODIFIED)) {
+        (true, Some(modified)) => (date.duration_since(modified).unwrap_or_default() / 10).min(MAX_HEURISTIC_FRESHNESS),
+        _ => Duration::ZERO,
+    }
+}
+
+/// Returns the request headers named in the `Vary` headers of a response, `*` included.
+fn vary(headers: &HeaderMap) -> Vec<HeaderName> {
+    headers.get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect()
+}
+
+/// Returns the values of a header joined with commas, or `None` if it is absent.
+fn joined(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers.get_all(name).iter().filter_map(|value| value.to_str().ok()).map(str::trim).collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// Reads a header holding an HTTP date.
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+/// Compares two entity tags, ignoring whether they are weak (RFC 9110 §8.8.3.2).
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs.iter()
+            .map(|(name, value)| (HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    /// Stores a `200 OK` response with the given headers, received at `now`.
+    fn stored(cache: &Cache, request: &HeaderMap, response: &[(&str, &str)], now: SystemTime) -> Arc<CachedResponse> {
+        let response = Arc::new(CachedResponse::new(
+            request,
+            StatusCode::OK,
+            headers(response),
+            Bytes::from_static(b"hello"),
+            now,
+            now,
+        ));
+        cache.store(cache_key("example.com", "/"), response.clone());
+        response
+    }
+
+    #[test]
+    fn test_parses_cache_control() {
+        let directives = CacheControl::parse(&headers(&[
+            ("cache-control", "public, max-age=60"),
+            ("cache-control", "S-MAXAGE=\"120\", must-revalidate, community=\"UCI\""),
+            ("cache-control", "stale-while-revalidate=30, stale-if-error=600"),
+        ]));
+        assert_eq!(directives, CacheControl {
+            public: true,
+            must_revalidate: true,
+            max_age: Some(Duration::from_secs(60)),
+            s_maxage: Some(Duration::from_secs(120)),
+            stale_while_revalidate: Some(Duration::from_secs(30)),
+            stale_if_error: Some(Duration::from_secs(600)),
+            ..Default::default()
+        });
+
+        let directives = CacheControl::parse(&headers(&[("cache-control", "no-store, max-age=soon")]));
+        assert!(directives.no_store);
+        assert_eq!(directives.max_age, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_freshness_lifetime() {
+        let now = SystemTime::now();
+        let date = httpdate::fmt_http_date(now);
+        let lifetime = |status: u16, pairs: &[(&str, &str)]| {
+            let response = CachedResponse::new(&HeaderMap::new(), StatusCode::from_u16(status).unwrap(), headers(pairs), Bytes::new(), now, now);
+            response.freshness.as_secs()
+        };
+
+        assert_eq!(lifetime(200, &[("cache-control", "max-age=60, s-maxage=30")]), 30);
+        assert_eq!(lifetime(200, &[("cache-control", "max-age=60"), ("expires", "0")]), 60);
+        let expires = httpdate::fmt_http_date(now + Duration::from_secs(90));
+        assert_eq!(lifetime(200, &[("date", &date), ("expires", &expires)]), 90);
+        assert_eq!(lifetime(200, &[("date", &date), ("expires", "0")]), 0);
+        assert_eq!(lifetime(200, &[("cache-control", "no-cache, max-age=60")]), 0);
+        // A tenth of the time since the last modification, for statuses cacheable by default
+        let modified = httpdate::fmt_http_date(now - Duration::from_secs(1000));
+        assert_eq!(lifetime(200, &[("date", &date), ("last-modified", &modified)]), 100);
+        assert_eq!(lifetime(302, &[("date", &date), ("last-modified", &modified)]), 0);
+        assert_eq!(lifetime(200, &[]), 0);
+    }
+
+    #[test]
+    fn test_age_counts_the_age_header_and_the_time_in_cache() {
+        let now = SystemTime::now();
+        let date = httpdate::fmt_http_date(now - Duration::from_secs(10));
+        let response = CachedResponse::new(
+            &HeaderMap::new(),
+            StatusCode::OK,
+            headers(&[("date", &date), ("age", "30"), ("cache-control", "max-age=60")]),
+            Bytes::new(),
+            now - Duration::from_secs(2),
+            now,
+        );
+        // The age header and the response delay outweigh the date
+        assert_eq!(response.age(now).as_secs(), 32);
+        assert!(response.is_fresh(now + Duration::from_secs(27)));
+        assert!(!response.is_fresh(now + Duration::from_secs(28)));
+    }
+
+    #[test]
+    fn test_storable_responses() {
+        let get = Method::GET;
+        let none = HeaderMap::new();
+        let ok = StatusCode::OK;
+        let storable = |method: &Method, request: &HeaderMap, status: StatusCode, pairs: &[(&str, &str)]| {
+            is_storable(method, request, status, &headers(pairs))
+        };
+
+        assert!(storable(&get, &none, ok, &[("cache-control", "max-age=60")]));
+        assert!(storable(&get, &none, ok, &[]));
+        assert!(storable(&get, &none, StatusCode::FOUND, &[("expires", "0")]));
+        assert!(!storable(&get, &none, StatusCode::FOUND, &[]));
+        assert!(!storable(&Method::POST, &none, ok, &[("cache-control", "max-age=60")]));
+        assert!(!storable(&Method::HEAD, &none, ok, &[("cache-control", "max-age=60")]));
+        assert!(!storable(&get, &none, ok, &[("cache-control", "private, max-age=60")]));
+        assert!(!storable(&get, &none, ok, &[("cache-control", "no-store")]));
+        assert!(!storable(&get, &headers(&[("cache-control", "no-store")]), ok, &[]));
+        assert!(!storable(&get, &none, ok, &[("set-cookie", "session=1")]));
+        assert!(!storable(&get, &none, ok, &[("vary", "accept-encoding, *")]));
+        assert!(!storable(&get, &none, StatusCode::PARTIAL_CONTENT, &[("cache-control", "max-age=60")]));
+
+        let authorized = headers(&[("authorization", "Bearer token")]);
+        assert!(!storable(&get, &authorized, ok, &[("cache-control", "max-age=60")]));
+        assert!(storable(&get, &authorized, ok, &[("cache-control", "s-maxage=60")]));
+        assert!(storable(&get, &authorized, ok, &[("cache-control", "public, max-age=60")]));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let cache = Cache::default();
+        let key = cache_key("example.com", "/");
+        let now = SystemTime::now();
+        let none = HeaderMap::new();
+        assert!(matches!(cache.lookup(&key, &Method::GET, &none, now), Lookup::Miss { vary: false }));
+
+        stored(&cache, &none, &[("cache-control", "max-age=60")], now);
+        assert!(matches!(cache.lookup(&key, &Method::GET, &none, now), Lookup::Hit(_)));
+        assert!(matches!(cache.lookup(&key, &Method::HEAD, &none, now), Lookup::Hit(_)));
+        assert!(matches!(cache.lookup(&key, &Method::POST, &none, now), Lookup::Bypass));
+        assert!(matches!(cache.lookup(&key, &Method::GET, &headers(&[("range", "bytes=0-1")]), now), Lookup::Bypass));
+        assert!(matches!(cache.lookup(&key, &Method::GET, &none, now + Duration::from_secs(60)), Lookup::Stale(_)));
+
+        // Requests can ask for fresher responses
+        let later = now + Duration::from_secs(30);
+        let lookup = |pairs: &[(&str, &str)]| cache.lookup(&key, &Method::GET, &headers(pairs), later);
+        assert!(matches!(lookup(&[("cache-control", "no-cache")]), Lookup::Stale(_)));
+        assert!(matches!(lookup(&[("cache-control", "max-age=20")]), Lookup::Stale(_)));
+        assert!(matches!(lookup(&[("cache-control", "max-age=40")]), Lookup::Hit(_)));
+        assert!(matches!(lookup(&[("cache-control", "min-fresh=40")]), Lookup::Stale(_)));
+
+        cache.invalidate(&key);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_stores_one_response_per_vary_values() {
+        let cache = Cache::default();
+        let key = cache_key("example.com", "/");
+        let now = SystemTime::now();
+        let gzip = headers(&[("accept-encoding", "gzip")]);
+        let br = headers(&[("accept-encoding", "br")]);
+        let response = [("cache-control", "max-age=60"), ("vary", "Accept-Encoding")];
+
+        stored(&cache, &gzip, &response, now);
+        assert!(matches!(cache.lookup(&key, &Method::GET, &gzip, now), Lookup::Hit(_)));
+        assert!(matches!(cache.lookup(&key, &Method::GET, &br, now), Lookup::Miss { vary: true }));
+        assert!(matches!(cache.lookup(&key, &Method::GET, &HeaderMap::new(), now), Lookup::Miss { vary: true }));
+
+        stored(&cache, &br, &response, now);
+        stored(&cache, &br, &response, now);
+        assert!(matches!(cache.lookup(&key, &Method::GET, &br, now), Lookup::Hit(_)));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_revalidation() {
+        let cache = Cache::default();
+        let now = SystemTime::now();
+        let response = stored(&cache, &HeaderMap::new(), &[("cache-control", "no-cache"), ("etag", "\"v1\"")], now);
+        assert!(response.has_validator());
+        assert!(!response.is_fresh(now));
+
+        let mut request = headers(&[("if-none-match", "\"v0\"")]);
+        response.add_validators(&mut request);
+        assert_eq!(request.get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+
+        let later = now + Duration::from_secs(10);
+        let freshened = response.freshen(&headers(&[("cache-control", "max-age=60"), ("etag", "\"v1\"")]), later, later);
+        assert!(freshened.is_fresh(later + Duration::from_secs(59)));
+        assert_eq!(freshened.headers.get(CACHE_CONTROL).unwrap(), "max-age=60");
+        assert_eq!(freshened.body, response.body);
+    }
+
+    #[test]
+    fn test_responds_to_conditional_requests() {
+        let cache = Cache::default();
+        let now = SystemTime::now();
+        let modified = httpdate::fmt_http_date(now - Duration::from_secs(100));
+        let response = stored(&cache, &HeaderMap::new(), &[
+            ("cache-control", "max-age=60"),
+            ("etag", "W/\"v1\""),
+            ("last-modified", &modified),
+            ("content-type", "text/plain"),
+        ], now - Duration::from_secs(5));
+
+        let full = response.respond(&HeaderMap::new(), false, now, "hit");
+        assert_eq!(full.status(), StatusCode::OK);
+        assert_eq!(full.body(), &Bytes::from_static(b"hello"));
+        assert_eq!(full.headers().get(AGE).unwrap(), "5");
+        assert_eq!(full.headers().get(CACHE_STATUS).unwrap(), "flusso; hit");
+        assert!(response.respond(&HeaderMap::new(), true, now, "hit").body().is_empty());
+
+        let not_modified = response.respond(&headers(&[("if-none-match", "\"v0\", \"v1\"")]), false, now, "hit");
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+        assert!(not_modified.body().is_empty());
+        assert!(not_modified.headers().get("content-type").is_none());
+        assert_eq!(not_modified.headers().get(ETAG).unwrap(), "W/\"v1\"");
+        assert_eq!(response.respond(&headers(&[("if-none-match", "\"v2\"")]), false, now, "hit").status(), StatusCode::OK);
+
+        let since = |time: SystemTime| headers(&[("if-modified-since", &httpdate::fmt_http_date(time))]);
+        assert_eq!(response.respond(&since(now), false, now, "hit").status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.respond(&since(now - Duration::from_secs(200)), false, now, "hit").status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_serves_stale_responses_within_their_grace_period() {
+        let cache = Cache::default();
+        let key = cache_key("example.com", "/");
+        let now = SystemTime::now();
+        let none = HeaderMap::new();
+        let response = stored(&cache, &none, &[("cache-control", "max-age=60, stale-while-revalidate=30, stale-if-error=300")], now);
+        let lookup = |pairs: &[(&str, &str)], seconds: u64| cache.lookup(&key, &Method::GET, &headers(pairs), now + Duration::from_secs(seconds));
+
+        assert!(matches!(lookup(&[], 60), Lookup::StaleWhileRevalidate(_)));
+        assert!(matches!(lookup(&[], 89), Lookup::StaleWhileRevalidate(_)));
+        assert!(matches!(lookup(&[], 90), Lookup::Stale(_)));
+        // Requests asking for fresher responses are not served stale ones
+        assert!(matches!(lookup(&[("cache-control", "no-cache")], 60), Lookup::Stale(_)));
+        assert!(matches!(lookup(&[("cache-control", "max-age=60")], 70), Lookup::Stale(_)));
+        assert!(matches!(lookup(&[("cache-control", "min-fresh=1")], 70), Lookup::Stale(_)));
+
+        assert!(response.serves_stale_if_error(now + Duration::from_secs(359)));
+        assert!(!response.serves_stale_if_error(now + Duration::from_secs(360)));
+        assert_eq!(cache.sweep(now + Duration::from_secs(359)), 0);
+        assert_eq!(cache.sweep(now + Duration::from_secs(360)), 1);
+    }
+
+    #[test]
+    fn test_stale_grace_of_the_ingress() {
+        let now = SystemTime::now();
+        let response = |pairs: &[(&str, &str)]| {
+            CachedResponse::new(&HeaderMap::new(), StatusCode::OK, headers(pairs), Bytes::new(), now, now)
+                .with_stale_grace(Some(Duration::from_secs(100)))
+        };
+        let later = now + Duration::from_secs(150);
+
+        // The grace of the Ingress applies to the directives the response lacks
+        let grace = response(&[("cache-control", "max-age=60, stale-if-error=10")]);
+        assert_eq!(grace.stale_while_revalidate, Some(Duration::from_secs(100)));
+        assert!(!grace.serves_stale_if_error(later));
+        assert!(response(&[("cache-control", "max-age=60")]).serves_stale_if_error(later));
+        assert!(!response(&[("cache-control", "max-age=60, must-revalidate")]).serves_stale_if_error(later));
+        assert!(response(&[("cache-control", "no-cache")]).is_expired(now));
+    }
+
+    #[test]
+    fn test_evicts_the_least_recently_used_keys() {
+        let now = SystemTime::now();
+        let response = Arc::new(CachedResponse::new(&HeaderMap::new(), StatusCode::OK, HeaderMap::new(), Bytes::from(vec![0; 100]), now, now));
+        let size = cache_key("example.com", "/a").len() + response.size();
+        let cache = Cache::new(3 * size);
+        for path in ["/a", "/b", "/c"] {
+            cache.store(cache_key("example.com", path), response.clone());
+        }
+        assert_eq!(cache.size(), 3 * size);
+
+        // Looking up /a makes /b the least recently used key
+        cache.lookup(&cache_key("example.com", "/a"), &Method::GET, &HeaderMap::new(), now);
+        cache.store(cache_key("example.com", "/d"), response.clone());
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.size(), 3 * size);
+        let lookup = |path: &str| cache.lookup(&cache_key("example.com", path), &Method::GET, &HeaderMap::new(), now);
+        assert!(matches!(lookup("/b"), Lookup::Miss { vary: false }));
+        for path in ["/a", "/c", "/d"] {
+            assert!(!matches!(lookup(path), Lookup::Miss { .. }));
+        }
+
+        // Responses larger than the budget are not stored
+        let large = Arc::new(CachedResponse::new(&HeaderMap::new(), StatusCode::OK, HeaderMap::new(), Bytes::from(vec![0; 400]), now, now));
+        cache.store(cache_key("example.com", "/e"), large);
+        assert_eq!(cache.len(), 3);
+
+        cache.invalidate(&cache_key("example.com", "/a"));
+        assert_eq!(cache.size(), 2 * size);
+    }
+
+    #[test]
+    fn test_sweeps_responses_that_can_no_longer_be_used() {
+        let cache = Cache::default();
+        let now = SystemTime::now();
+        let fresh = stored(&cache, &headers(&[("accept", "text/html")]), &[("cache-control", "max-age=60"), ("vary", "accept")], now);
+        stored(&cache, &HeaderMap::new(), &[("cache-control", "max-age=30"), ("vary", "accept")], now);
+        let key = cache_key("example.com", "/revalidated");
+        let revalidated = Arc::new(CachedResponse::new(
+            &HeaderMap::new(),
+            StatusCode::OK,
+            headers(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]),
+            Bytes::new(),
+            now,
+            now,
+        ));
+        cache.store(key.clone(), revalidated.clone());
+        assert_eq!(cache.sweep(now), 0);
+
+        // The expired variant goes, while the response with a validator can be revalidated
+        assert_eq!(cache.sweep(now + Duration::from_secs(30)), 1);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.size(), cache_key("example.com", "/").len() + fresh.size() + key.len() + revalidated.size());
+        assert_eq!(cache.sweep(now + Duration::from_secs(60)), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_coalesces_fetches_of_a_key() {
+        let cache = Cache::default();
+        let Fetch::First(guard) = cache.fetch("example.com/") else {
+            panic!("no fetch is running");
+        };
+        let Fetch::Waiting(wait) = cache.fetch("example.com/") else {
+            panic!("the first fetch is running");
+        };
+        assert!(matches!(cache.fetch("example.com/other"), Fetch::First(_)));
+
+        let waiting = tokio::spawn(wait.wait(Duration::from_secs(60)));
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        assert!(matches!(cache.fetch("example.com/"), Fetch::First(_)));
+    }
+
+    #[test]
+    fn test_unsafe_methods_invalidate() {
+        assert!(invalidates(&Method::POST, StatusCode::CREATED));
+        assert!(invalidates(&Method::DELETE, StatusCode::SEE_OTHER));
+        assert!(!invalidates(&Method::POST, StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!invalidates(&Method::GET, StatusCode::OK));
     }
 }
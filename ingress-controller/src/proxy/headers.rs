@@ -0,0 +1,284 @@
+//! Header handling for the requests forwarded to backends and their responses.
+//!
+//! Hop-by-hop headers (RFC 7230, section 6.1) only describe a single connection, so they
+//! are removed from requests and responses going through the proxy, along with every
+//! header named in `Connection`.
+//!
+//! Forwarded requests describe the client connection in the `X-Forwarded-For`,
+//! `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Port` headers, and in the
+//! RFC 7239 `Forwarded` header. The values a client sends are only kept, and extended,
+//! when the client is a trusted proxy; they are replaced otherwise.
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, FORWARDED};
+use std::net::IpAddr;
+
+/// Headers that only apply to a single connection.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+pub const X_FORWARDED_HOST: &str = "x-forwarded-host";
+pub const X_FORWARDED_PORT: &str = "x-forwarded-port";
+
+/// Describes the connection a request was received on.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    /// Address of the client, if known.
+    pub addr: Option<IpAddr>,
+    /// Whether the request was received over TLS.
+    pub secure: bool,
+    /// The `Host` header sent by the client, with its port if any.
+    pub host: Option<String>,
+}
+
+impl ClientInfo {
+    /// Returns the scheme the request was received with.
+    pub fn proto(&self) -> &'static str {
+        if self.secure { "https" } else { "http" }
+    }
+
+    /// Returns the port the client connected to, as written in `Host` or implied by the scheme.
+    pub fn port(&self) -> u16 {
+        self.host.as_deref()
+            .and_then(|host| host.rsplit_once(':'))
+            .filter(|(name, _)| !name.ends_with(':'))
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or(if self.secure { 443 } else { 80 })
+    }
+}
+
+/// Networks of the proxies whose forwarding headers are trusted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies {
+    /// Network addresses and prefix lengths.
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    /// Parses a comma-separated list of IP addresses and CIDR networks.
+    ///
+    /// # Returns
+    /// The trusted networks, or an error naming the first invalid entry.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let networks = value.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| parse_network(entry).ok_or_else(|| format!("invalid network {}", entry)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { networks })
+    }
+
+    /// Returns `true` if an address belongs to one of the trusted networks.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        self.networks.iter().any(|(network, prefix)| match (network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        })
+    }
+}
+
+/// Parses an IP address or a CIDR network into its address and prefix length.
+fn parse_network(entry: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = match entry.split_once('/') {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (entry.parse::<IpAddr>().ok()?, None),
+    };
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    match prefix {
+        Some(prefix) if prefix > max_prefix => None,
+        prefix => Some((addr, prefix.unwrap_or(max_prefix))),
+    }
+}
+
+/// Removes the hop-by-hop headers, and every header listed in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers.get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Adds the forwarding headers describing the client connection to a request.
+///
+/// Forwarding headers from a trusted proxy are extended: the client address is appended
+/// to `X-Forwarded-For` and `Forwarded`, and the other `X-Forwarded-*` headers are kept
+/// if present. Forwarding headers from any other client are replaced.
+///
+/// # Parameters
+/// - `headers`: The headers of the request forwarded to the backend.
+/// - `client`: The connection the request was received on.
+/// - `trusted`: The proxies whose forwarding headers are kept.
+pub fn set_forwarding_headers(headers: &mut HeaderMap, client: &ClientInfo, trusted: &TrustedProxies) {
+    if !client.addr.is_some_and(|addr| trusted.contains(addr)) {
+        for name in [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST, X_FORWARDED_PORT, FORWARDED.as_str()] {
+            headers.remove(name);
+        }
+    }
+
+    let client_addr = client.addr.map(|addr| addr.to_canonical().to_string());
+    append_list(headers, HeaderName::from_static(X_FORWARDED_FOR), client_addr.as_deref().unwrap_or("unknown"));
+    set_if_absent(headers, X_FORWARDED_PROTO, client.proto());
+    if let Some(host) = &client.host {
+        set_if_absent(headers, X_FORWARDED_HOST, host);
+    }
+    set_if_absent(headers, X_FORWARDED_PORT, &client.port().to_string());
+
+    let mut element = format!("for={}", forwarded_node(client.addr));
+    if let Some(host) = &client.host {
+        element.push_str(&format!(";host={}", forwarded_value(host)));
+    }
+    element.push_str(&format!(";proto={}", client.proto()));
+    append_list(headers, FORWARDED, &element);
+}
+
+/// Appends an element to a comma-separated list header, merging repeated headers.
+fn append_list(headers: &mut HeaderMap, name: HeaderName, element: &str) {
+    let mut elements: Vec<&str> = headers.get_all(&name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    elements.push(element);
+    if let Ok(value) = HeaderValue::from_str(&elements.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+/// Sets a header unless the request already carries it.
+fn set_if_absent(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let (false, Ok(value)) = (headers.contains_key(name), HeaderValue::from_str(value)) {
+        headers.insert(name, value);
+    }
+}
+
+/// Formats a client address as a `Forwarded` node, bracketing and quoting IPv6 addresses.
+fn forwarded_node(addr: Option<IpAddr>) -> String {
+    match addr.map(|addr| addr.to_canonical()) {
+        Some(IpAddr::V6(addr)) => format!("\"[{}]\"", addr),
+        Some(addr) => addr.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Formats a `Forwarded` parameter value, quoting it unless it is a token.
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    match is_token {
+        true => value.to_string(),
+        false => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn client(addr: &str) -> ClientInfo {
+        ClientInfo {
+            addr: Some(addr.parse().unwrap()),
+            secure: true,
+            host: Some("example.com:8443".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_strips_hop_by_hop_headers() {
+        let mut headers = headers(&[
+            ("connection", "keep-alive, X-Custom"),
+            ("keep-alive", "timeout=5"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("x-custom", "dropped"),
+            ("accept", "text/html"),
+        ]);
+        strip_hop_by_hop(&mut headers);
+
+        let names: Vec<_> = headers.keys().map(HeaderName::as_str).collect();
+        assert_eq!(names, vec!["accept"]);
+    }
+
+    #[test]
+    fn test_replaces_forwarding_headers_from_untrusted_clients() {
+        let mut headers = headers(&[
+            ("x-forwarded-for", "10.9.9.9"),
+            ("x-forwarded-proto", "http"),
+            ("forwarded", "for=10.9.9.9"),
+        ]);
+        set_forwarding_headers(&mut headers, &client("203.0.113.7"), &TrustedProxies::default());
+
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "example.com:8443");
+        assert_eq!(headers["x-forwarded-port"], "8443");
+        assert_eq!(headers["forwarded"], "for=203.0.113.7;host=\"example.com:8443\";proto=https");
+    }
+
+    #[test]
+    fn test_extends_forwarding_headers_from_trusted_proxies() {
+        let mut headers = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "http"),
+            ("forwarded", "for=198.51.100.1;proto=http"),
+        ]);
+        let trusted = TrustedProxies::parse("10.0.0.0/8, 2001:db8::/32").unwrap();
+        set_forwarding_headers(&mut headers, &client("2001:db8::1"), &trusted);
+
+        assert_eq!(headers["x-forwarded-for"], "198.51.100.1, 10.0.0.2, 2001:db8::1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "example.com:8443");
+        assert_eq!(
+            headers["forwarded"],
+            "for=198.51.100.1;proto=http, for=\"[2001:db8::1]\";host=\"example.com:8443\";proto=https",
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let trusted = TrustedProxies::parse("10.0.0.0/8,192.168.1.1, fd00::/8").unwrap();
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("192.168.1.1".parse().unwrap()));
+        assert!(!trusted.contains("192.168.1.2".parse().unwrap()));
+        assert!(trusted.contains("fd12::1".parse().unwrap()));
+        assert!(!trusted.contains("2001:db8::1".parse().unwrap()));
+
+        assert!(TrustedProxies::parse("0.0.0.0/0").unwrap().contains("203.0.113.7".parse().unwrap()));
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::parse("proxy.local").is_err());
+    }
+}
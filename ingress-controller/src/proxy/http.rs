@@ -1,73 +1,606 @@
 //! HTTP Proxy module to forward client requests to backend servers.
 //!
-//! The `HttpProxy` struct uses a load balancer to select a backend and forwards
-//! requests to the chosen backend. It handles HTTP headers, body, and logs request details.
+//! The `HttpProxy` struct uses the routing table to find the backend pool for a request
+//! host and path, and forwards requests to a backend selected from that pool. It handles HTTP headers, body, and logs request details.
+//!
+//! Request bodies are streamed to the backend as they arrive rather than buffered, and
+//! are limited to a maximum size. Hop-by-hop headers are dropped and forwarding headers
+//! describing the client are added, as done in the `headers` module. WebSocket upgrade
+//! requests keep their upgrade headers, and are relayed with the `upgrade` module.
+//!
+//! Backends are reached over HTTP/1.1, or over cleartext HTTP/2 (h2c) for the Ingresses
+//! that select it, as needed by gRPC. Responses keep their trailers.
+//!
+//! Every request is labelled in its `RequestMetrics` with the route it was sent to, and
+//! failures to connect to backends are counted there.
+//!
+//! The outcome of every request is reported to the pool of its backend, which ejects
+//! backends refusing connections or answering with server errors too many times in a row.
+//! Requests are counted in flight to their backend until its response headers arrive,
+//! and the latency of successful ones is recorded, for the load balancing strategies.
+//!
+//! The Ingresses enabling caching have the `GET` requests they route answered from the
+//! shared `Cache` when it holds a fresh response, and the responses of their backends
+//! stored there when allowed, as done in the `cache` module. Stale responses within their
+//! grace period are served while a background request revalidates them, or when the
+//! backend fails.
 
-use reqwest::{Client, Response};
-use reqwest::header::HeaderMap;
+use reqwest::{Body, Client, Method, Response, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, SET_COOKIE, TE, UPGRADE};
 use std::error::Error;
-use super::load_balancer::LoadBalancer;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use super::affinity;
+use super::cache::{
+    cache_key, cache_status, invalidates, is_error, is_storable, Cache, CachedResponse, Fetch, FetchGuard, Lookup, CACHE_STATUS,
+    DEFAULT_CACHE_SIZE, FETCH_TIMEOUT, MAX_ENTRY_SIZE,
+};
+use super::headers::{set_forwarding_headers, strip_hop_by_hop, ClientInfo, TrustedProxies};
+use super::outlier::OutlierDetection;
+use super::load_balancer::LoadBalancer;
+use super::router::{IngressOptions, SharedRouter, UpstreamProtocol};
+use super::upgrade::{upgrade_protocol, UpgradeMetrics, DEFAULT_UPGRADE_IDLE_TIMEOUT};
+use crate::metrics::{Metrics, RequestMetrics, RouteLabels};
 use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http_body::Body as _;
+use http_body_util::{BodyDataStream, BodyExt};
+use log::{debug, warn};
+
+/// Maximum size of a request body when none is configured, in bytes.
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// A request body streamed to the backend.
+pub type BodyStream = BoxStream<'static, Result<Bytes, io::Error>>;
+
+/// Reasons a request could not be forwarded to a backend.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// No route nor default backend matches the request host and path.
+    NoRoute,
+    /// The backend pool serving the request has no available backend.
+    NoBackend(String),
+    /// The request body is larger than the maximum body size.
+    PayloadTooLarge(u64),
+    /// The request to the backend failed.
+    Upstream(reqwest::Error),
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::NoRoute => write!(f, "No route matches the request"),
+            ProxyError::NoBackend(backend) => write!(f, "No available backend in pool {}", backend),
+            ProxyError::PayloadTooLarge(limit) => write!(f, "Request body exceeds {} bytes", limit),
+            ProxyError::Upstream(e) => write!(f, "Backend request failed: {}", e),
+        }
+    }
+}
+
+impl Error for ProxyError {}
+
+/// Settings of the proxy applying to every request.
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    /// Maximum size of a request body, in bytes.
+    pub max_body_size: u64,
+    /// Proxies whose forwarding headers are kept.
+    pub trusted_proxies: TrustedProxies,
+    /// Time without traffic after which an upgraded connection is closed.
+    pub upgrade_idle_timeout: Duration,
+    /// Ejection of failing backends, unless disabled.
+    pub outlier_detection: Option<OutlierDetection>,
+    /// Most bytes of responses stored in the cache.
+    pub cache_size: usize,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            trusted_proxies: TrustedProxies::default(),
+            upgrade_idle_timeout: DEFAULT_UPGRADE_IDLE_TIMEOUT,
+            outlier_detection: Some(OutlierDetection::default()),
+            cache_size: DEFAULT_CACHE_SIZE,
+        }
+    }
+}
 
 /// An HTTP proxy that forwards requests to selected backend servers.
+///
+/// Clones share the connection pool to backends and the metrics.
+#[derive(Clone)]
 pub struct HttpProxy {
+    /// Client for HTTP/1.1 backends.
     client: Client,
-    load_balancer: Arc<LoadBalancer>,
+    /// Client for HTTP/2 backends, using prior knowledge.
+    http2_client: Client,
+    router: SharedRouter,
+    config: ProxyConfig,
+    /// Counters of the upgraded connections relayed by the proxy.
+    upgrades: Arc<UpgradeMetrics>,
+    /// Metrics of the proxied requests, exported to Prometheus.
+    metrics: Arc<Metrics>,
+    /// Responses stored for the Ingresses enabling caching.
+    cache: Cache,
 }
 
 impl HttpProxy {
-    /// Creates a new `HttpProxy` instance with a load balancer.
+    /// Creates a new `HttpProxy` instance with a routing table.
     ///
     /// # Parameters
-    /// - `load_balancer`: Shared `LoadBalancer` to manage backend selection.
-    pub fn new(load_balancer: Arc<LoadBalancer>) -> Self {
+    /// - `router`: Shared `Router` used to select the backend pool for each request.
+    /// - `config`: Settings applying to every request.
+    /// - `metrics`: Metrics the proxied requests are recorded into.
+    pub fn new(router: SharedRouter, config: ProxyConfig, metrics: Arc<Metrics>) -> Self {
         Self {
-            client: Client::new(),
-            load_balancer,
+            client: Client::builder().http1_only().build().expect("HTTP/1.1 client"),
+            http2_client: Client::builder().http2_prior_knowledge().build().expect("HTTP/2 client"),
+            router,
+            cache: Cache::new(config.cache_size),
+            config,
+            upgrades: Arc::new(UpgradeMetrics::default()),
+            metrics,
         }
     }
 
-    /// Forwards a full HTTP request to a backend selected by the load balancer.
+    /// Returns the settings applying to every request.
+    pub fn config(&self) -> &ProxyConfig {
+        &self.config
+    }
+
+    /// Returns the counters of the upgraded connections relayed by the proxy.
+    pub fn upgrade_metrics(&self) -> &Arc<UpgradeMetrics> {
+        &self.upgrades
+    }
+
+    /// Returns the metrics the proxied requests are recorded into.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Returns the responses stored for the Ingresses enabling caching.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Forwards an HTTP request to a backend selected from the pool routed for it, with
+    /// the load balancing strategy and session affinity of the Ingress routing it. The
+    /// affinity cookie pinning the client to its backend is added to the response if needed.
+    ///
+    /// The body is streamed to the backend. A body announced or found to be larger than
+    /// the maximum body size fails the request with `ProxyError::PayloadTooLarge`; the
+    /// response body is left for the caller to stream back.
+    ///
+    /// Hop-by-hop headers are removed and forwarding headers added, though `TE: trailers`
+    /// is kept for gRPC. The client's `Host` header (or the `:authority` of HTTP/2 requests)
+    /// is kept unless the Ingress routing the request disables it. Upgrade requests to
+    /// HTTP/1.1 backends keep `Connection: upgrade` and `Upgrade`, and a
+    /// `101 Switching Protocols` response is left for the caller to relay with `upgrade::tunnel`.
+    ///
+    /// Server errors and refused connections count towards ejecting the backend from its
+    /// pool, while other responses reset its count of errors.
+    ///
+    /// Requests of the Ingresses enabling caching are answered from the cache when it
+    /// holds a fresh response, without reaching a backend. Stale responses are revalidated
+    /// with the backend, and its responses stored when allowed. Requests for a response
+    /// another request is fetching wait for it rather than reaching the backend as well.
+    /// Stale responses within their grace period are served while revalidated in the
+    /// background, or instead of a failed backend response.
     ///
     /// # Parameters
-    /// - `path`: The path to forward the request to on the backend.
+    /// - `host`: The request host, without port, used to select the route.
+    /// - `path`: The path and query to forward the request to on the backend; the path
+    ///   selects the route.
     /// - `method`: The HTTP method for the request (e.g., GET, POST).
     /// - `headers`: The headers to include in the forwarded request.
-    /// - `body`: An optional body for the request.
+    /// - `body`: An optional body for the request, streamed to the backend.
+    /// - `client`: The connection the request was received on.
+    /// - `metrics`: Metrics of the request, labelled here with the route it is sent to.
     ///
     /// # Returns
-    /// A `Result` containing the `Response` from the backend or an error.
+    /// A `Result` containing the `Response` from the backend or a `ProxyError`.
+    #[allow(clippy::too_many_arguments)]
     pub async fn forward_request(
         &self,
+        host: &str,
         path: &str,
         method: reqwest::Method,
-        headers: HeaderMap,
-        body: Option<Bytes>,
-    ) -> Result<Response, Box<dyn Error>> {
-        println!("Selecting backend for request...");
-
-        if let Some(backend) = self.load_balancer.select_backend() {
-            let url = format!("http://{}{}", backend, path);
-            println!("Forwarding to URL: {}", url);
-            println!("HTTP Method: {:?}", method);
-            println!("Headers: {:?}", headers);
-
-            let mut request_builder = self.client.request(method, &url).headers(headers);
-
-            if let Some(b) = body {
-                request_builder = request_builder.body(b.clone());
-                println!("Request Body: {:?}", b);
+        mut headers: HeaderMap,
+        body: Option<BodyStream>,
+        client: &ClientInfo,
+        metrics: &mut RequestMetrics,
+    ) -> Result<Response, ProxyError> {
+        let route_path = path.split_once('?').map_or(path, |(route_path, _)| route_path);
+        let upstream = {
+            let router = self.router.load();
+            let target = router.target(host, route_path).ok_or(ProxyError::NoRoute)?;
+            metrics.routed(RouteLabels::new(target.ingress, target.route, target.backend));
+            let pool = router.get_pool(target.backend).ok_or_else(|| ProxyError::NoBackend(target.backend.to_string()))?;
+            Upstream { key: target.backend.to_string(), pool, options: target.options }
+        };
+
+        // The client's own headers tell which stored response answers the request
+        let key = cache_key(host, path);
+        let (cache, fetch) = match upstream.options.cache {
+            true => {
+                let (lookup, fetch) = self.lookup(&key, &method, &headers).await;
+                (Some((lookup, headers.clone())), fetch)
+            }
+            false => (None, None),
+        };
+        let requested = SystemTime::now();
+        match &cache {
+            Some((Lookup::Hit(cached), _)) => {
+                metrics.cache_result("hit");
+                return Ok(cached.respond(&headers, method == Method::HEAD, requested, "hit").into());
+            }
+            Some((Lookup::StaleWhileRevalidate(cached), _)) => {
+                // The request holding the fetch of the key revalidates for the others
+                if let Some(fetch) = fetch {
+                    let revalidation = self.clone().revalidate(upstream, key, path.to_string(), cached.clone(), headers.clone(), client.clone(), fetch);
+                    tokio::spawn(revalidation);
+                }
+                metrics.cache_result("stale");
+                return Ok(cached.respond(&headers, method == Method::HEAD, requested, "hit; detail=stale-while-revalidate").into());
+            }
+            Some((Lookup::Stale(cached), _)) => cached.add_validators(&mut headers),
+            _ => {}
+        }
+
+        let sent = self.send(&upstream, path, method.clone(), headers, body, client).await;
+        if let Err(ProxyError::Upstream(e)) = &sent {
+            if e.is_connect() {
+                metrics.connect_failed();
+            }
+        }
+        if let Some((Lookup::Stale(cached), request)) = &cache {
+            let parameters = match &sent {
+                Ok((response, _)) if is_error(response.status()) => {
+                    Some(format!("fwd=stale; fwd-status={}; detail=stale-if-error", response.status().as_u16()))
+                }
+                Err(ProxyError::NoBackend(_) | ProxyError::Upstream(_)) => Some("fwd=stale; detail=stale-if-error".to_string()),
+                _ => None,
+            };
+            let now = SystemTime::now();
+            if let Some(parameters) = parameters.filter(|_| cached.serves_stale_if_error(now)) {
+                metrics.cache_result("stale");
+                return Ok(cached.respond(request, method == Method::HEAD, now, &parameters).into());
+            }
+        }
+        let (response, set_cookie) = sent?;
+
+        let mut response = match cache {
+            Some((lookup, request)) => {
+                let stale_grace = upstream.options.cache_stale;
+                let (response, result) = self.cache_response(lookup, key, &method, &request, response, requested, stale_grace).await?;
+                metrics.cache_result(result);
+                response
+            }
+            None => response,
+        };
+        if let Some(set_cookie) = set_cookie {
+            response.headers_mut().append(SET_COOKIE, set_cookie);
+        }
+        Ok(response)
+    }
+
+    /// Sends a request to a backend selected from its pool, and reports the outcome to
+    /// the pool.
+    ///
+    /// # Parameters
+    /// - `upstream`: The pool the request is routed to and the options of its Ingress.
+    /// - `path`: The path and query to forward the request to on the backend.
+    /// - `method`, `headers`, `body`: The request, with the headers of the client.
+    /// - `client`: The connection the request was received on.
+    ///
+    /// # Returns
+    /// The response of the backend, and the affinity cookie to add to the response sent to
+    /// the client, if any.
+    async fn send(
+        &self,
+        upstream: &Upstream,
+        path: &str,
+        method: Method,
+        mut headers: HeaderMap,
+        body: Option<BodyStream>,
+        client: &ClientInfo,
+    ) -> Result<(Response, Option<HeaderValue>), ProxyError> {
+        let Upstream { key: pool_key, pool, options } = upstream;
+        let selection = affinity::select(pool, options.load_balancing, options.affinity.as_ref(), &headers, client)
+            .ok_or_else(|| ProxyError::NoBackend(pool_key.clone()))?;
+        let backend = selection.backend;
+
+        let upgrade = upgrade_protocol(&headers).filter(|_| options.upstream_protocol == UpstreamProtocol::Http1);
+        let trailers = headers.get_all(TE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"));
+        strip_hop_by_hop(&mut headers);
+        if let Some(protocol) = upgrade {
+            headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(UPGRADE, protocol);
+        }
+        if trailers {
+            headers.insert(TE, HeaderValue::from_static("trailers"));
+        }
+        set_forwarding_headers(&mut headers, client, &self.config.trusted_proxies);
+        // Without a Host header the backend address is sent instead
+        match (&client.host, options.preserve_host) {
+            (Some(host), true) if !headers.contains_key(HOST) => {
+                if let Ok(host) = HeaderValue::from_str(host) {
+                    headers.insert(HOST, host);
+                }
+            }
+            (_, false) => {
+                headers.remove(HOST);
+            }
+            _ => {}
+        }
+
+        let announced_length = headers.get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if announced_length.is_some_and(|length| length > self.config.max_body_size) {
+            return Err(ProxyError::PayloadTooLarge(self.config.max_body_size));
+        }
+
+        let url = format!("http://{}{}", backend, path);
+        debug!("Forwarding {} to {}", method, url);
+
+        let http_client = match options.upstream_protocol {
+            UpstreamProtocol::Http1 => &self.client,
+            UpstreamProtocol::Http2 => &self.http2_client,
+        };
+        let mut request_builder = http_client.request(method.clone(), &url).headers(headers);
+
+        let exceeded = Arc::new(AtomicBool::new(false));
+        if let Some(body) = body {
+            let body = limit_body(body, self.config.max_body_size, exceeded.clone());
+            request_builder = request_builder.body(Body::wrap_stream(body));
+        }
+
+        let in_flight = pool.start_request(backend);
+        let response = request_builder.send().await;
+        match &response {
+            Ok(resp) => debug!("Backend {} answered {}", backend, resp.status()),
+            Err(err) => debug!("Request to backend {} failed: {}", backend, err),
+        }
+
+        // Other errors, such as an oversized request body, tell nothing about the backend
+        let failed = match &response {
+            Ok(resp) => Some(resp.status().is_server_error()),
+            Err(e) => e.is_connect().then_some(true),
+        };
+
+        // Quick failures would make the backend look fast
+        if let (Some(false), Some(in_flight)) = (failed, in_flight) {
+            in_flight.completed();
+        }
+        if let Some(outlier_detection) = &self.config.outlier_detection {
+            match failed {
+                Some(false) => pool.report_success(backend),
+                Some(true) => {
+                    if let Some(duration) = pool.report_failure(backend, outlier_detection) {
+                        warn!("Ejecting backend {} of {} for {:?} after consecutive failures", backend, pool_key, duration);
+                    }
+                }
+                None => {}
+            }
+        }
+        let response = response.map_err(|e| match exceeded.load(Ordering::Relaxed) {
+            true => ProxyError::PayloadTooLarge(self.config.max_body_size),
+            false => ProxyError::Upstream(e),
+        })?;
+        Ok((response, selection.set_cookie))
+
+    }
+
+    /// Looks a request up in the cache. A `GET` request missing it while another request
+    /// fetches the same key waits for that request, then looks the cache up again.
+    ///
+    /// # Returns
+    /// What the cache holds for the request, and the guard to hold until the response
+    /// is stored if the request is to fetch it from a backend.
+    async fn lookup(&self, key: &str, method: &Method, headers: &HeaderMap) -> (Lookup, Option<FetchGuard>) {
+        let lookup = self.cache.lookup(key, method, headers, SystemTime::now());
+        match lookup {
+            Lookup::StaleWhileRevalidate(_) => match self.cache.fetch(key) {
+                Fetch::First(guard) => (lookup, Some(guard)),
+                Fetch::Waiting(_) => (lookup, None),
+            },
+            Lookup::Miss { .. } | Lookup::Stale(_) if *method == Method::GET => match self.cache.fetch(key) {
+                // The previous fetch may have just stored the response
+                Fetch::First(guard) => (self.cache.lookup(key, method, headers, SystemTime::now()), Some(guard)),
+                // Waiting only once keeps requests the response does not fit from queuing up
+                Fetch::Waiting(wait) => {
+                    wait.wait(FETCH_TIMEOUT).await;
+                    (self.cache.lookup(key, method, headers, SystemTime::now()), None)
+                }
+            },
+            _ => (lookup, None),
+        }
+    }
+
+    /// Revalidates a stale response served to a client, in the background, and stores the
+    /// response of the backend.
+    ///
+    /// # Parameters
+    /// - `upstream`: The pool the request is routed to and the options of its Ingress.
+    /// - `key`: The cache key of the request.
+    /// - `path`: The path and query of the request.
+    /// - `cached`: The stale response.
+    /// - `request`: The headers of the client request.
+    /// - `client`: The connection the request was received on.
+    /// - `_fetch`: The fetch of the key, released once the response is stored.
+    #[allow(clippy::too_many_arguments)]
+    async fn revalidate(
+        self,
+        upstream: Upstream,
+        key: String,
+        path: String,
+        cached: Arc<CachedResponse>,
+        request: HeaderMap,
+        client: ClientInfo,
+        _fetch: FetchGuard,
+    ) {
+        let mut headers = request.clone();
+        cached.add_validators(&mut headers);
+        let requested = SystemTime::now();
+        let revalidated = match self.send(&upstream, &path, Method::GET, headers, None, &client).await {
+            Ok((response, _)) => {
+                let lookup = Lookup::Stale(cached);
+                let stale_grace = upstream.options.cache_stale;
+                self.cache_response(lookup, key.clone(), &Method::GET, &request, response, requested, stale_grace).await
             }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = revalidated {
+            eprintln!("Failed to revalidate the cached response for {}: {}", key, e);
+        }
+    }
 
-            let response = request_builder.send().await;
-            match &response {
-                Ok(resp) => println!("Backend response: {:?}", resp),
-                Err(err) => println!("Error in backend response: {:?}", err),
+    /// Stores the response of a backend in the cache when allowed, or refreshes the stored
+    /// response the backend found still valid, and invalidates the stored responses for
+    /// the path of requests with unsafe methods.
+    ///
+    /// # Parameters
+    /// - `lookup`: What the cache held for the request.
+    /// - `key`: The cache key of the request.
+    /// - `method`, `request`: The method and headers of the client request.
+    /// - `response`: The response of the backend.
+    /// - `requested`: When the request was sent to the backend.
+    /// - `stale_grace`: Time the stored response can be served stale, unless it tells.
+    ///
+    /// # Returns
+    /// The response to send to the client, with its `Cache-Status` header, and how the
+    /// cache handled the request: `revalidated`, `bypass` or `miss`.
+    #[allow(clippy::too_many_arguments)]
+    async fn cache_response(
+        &self,
+        lookup: Lookup,
+        key: String,
+        method: &Method,
+        request: &HeaderMap,
+        response: Response,
+        requested: SystemTime,
+        stale_grace: Option<Duration>,
+    ) -> Result<(Response, &'static str), ProxyError> {
+        let received = SystemTime::now();
+        let head = *method == Method::HEAD;
+        let status = response.status();
+        let forwarded = match &lookup {
+            Lookup::Stale(cached) if status == StatusCode::NOT_MODIFIED && cached.has_validator() => {
+                let mut headers = response.headers().clone();
+                strip_hop_by_hop(&mut headers);
+                let cached = Arc::new(cached.freshen(&headers, requested, received).with_stale_grace(stale_grace));
+                self.cache.store(key, cached.clone());
+                return Ok((cached.respond(request, head, received, "fwd=stale; fwd-status=304").into(), "revalidated"));
+            }
+            Lookup::Bypass => {
+                if invalidates(method, status) {
+                    self.cache.invalidate(&key);
+                }
+                return Ok((with_cache_status(response, "fwd=bypass"), "bypass"));
             }
-            response.map_err(|e| e.into())
-        } else {
-            Err("No available backend".into())
+            Lookup::Miss { vary: false } => "uri-miss",
+            Lookup::Miss { vary: true } => "vary-miss",
+            _ => "stale",
+        };
+        let parameters = format!("fwd={}; fwd-status={}", forwarded, status.as_u16());
+        if !is_storable(method, request, status, response.headers()) {
+            return Ok((with_cache_status(response, &parameters), "miss"));
+        }
+
+        let (mut parts, body) = http::Response::<Body>::from(response).into_parts();
+        let body = match buffer(body, MAX_ENTRY_SIZE).await.map_err(ProxyError::Upstream)? {
+            Ok(body) => body,
+            Err(body) => return Ok((with_cache_status(http::Response::from_parts(parts, body).into(), &parameters), "miss")),
+        };
+        strip_hop_by_hop(&mut parts.headers);
+        let cached = CachedResponse::new(request, parts.status, parts.headers, body, requested, received);
+        let cached = Arc::new(cached.with_stale_grace(stale_grace));
+        // Responses that can be neither served nor revalidated are not worth keeping
+        if cached.is_expired(received) {
+            return Ok((cached.respond(request, head, received, &parameters).into(), "miss"));
+        }
+        self.cache.store(key, cached.clone());
+        Ok((cached.respond(request, head, received, &format!("{}; stored", parameters)).into(), "miss"))
+    }
+}
+
+/// The backend pool a request is routed to, and the options of the Ingress routing it.
+#[derive(Clone)]
+struct Upstream {
+    key: String,
+    pool: Arc<LoadBalancer>,
+    options: Arc<IngressOptions>,
+}
+
+/// Adds a `Cache-Status` header with the given parameters to a response.
+fn with_cache_status(mut response: Response, parameters: &str) -> Response {
+    response.headers_mut().insert(CACHE_STATUS, cache_status(parameters));
+    response
+}
+
+/// Reads a response body, unless it is larger than `limit` bytes.
+///
+/// # Returns
+/// The body, or a body streaming it again if it is larger than `limit`.
+async fn buffer(mut body: Body, limit: usize) -> Result<Result<Bytes, Body>, reqwest::Error> {
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(Err(body));
+    }
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    // Trailers, unusual in cacheable responses, are not kept
+    while let Some(frame) = body.frame().await {
+        let Ok(chunk) = frame?.into_data() else {
+            continue;
+        };
+        size += chunk.len();
+        chunks.push(chunk);
+        if size > limit {
+            let read = stream::iter(chunks.into_iter().map(Ok::<_, reqwest::Error>));
+            return Ok(Err(Body::wrap_stream(read.chain(BodyDataStream::new(body)))));
+        }
+    }
+    Ok(Ok(chunks.concat().into()))
+}
+
+/// Fails a body stream once more than `limit` bytes went through, flagging `exceeded`.
+fn limit_body(body: BodyStream, limit: u64, exceeded: Arc<AtomicBool>) -> BodyStream {
+    let mut received: u64 = 0;
+    body.map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        if received > limit {
+            exceeded.store(true, Ordering::Relaxed);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
         }
+        Ok(chunk)
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    #[tokio::test]
+    async fn test_limit_body_fails_once_the_limit_is_exceeded() {
+        let chunks = vec![Ok(Bytes::from_static(b"abc")), Ok(Bytes::from_static(b"de"))];
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let mut body = limit_body(stream::iter(chunks).boxed(), 4, exceeded.clone());
+
+        assert_eq!(body.next().await.unwrap().unwrap(), Bytes::from_static(b"abc"));
+        assert!(!exceeded.load(Ordering::Relaxed));
+        assert!(body.next().await.unwrap().is_err());
+        assert!(exceeded.load(Ordering::Relaxed));
     }
 }
@@ -1,18 +1,192 @@
 //! Load balancer module for distributing requests across backend services.
 //!
-//! The `LoadBalancer` struct manages a list of backend services and selects one
-//! based on a round-robin algorithm. It also supports adding and removing backends dynamically.
+//! The `LoadBalancer` struct manages a list of backend services and selects one with
+//! the load balancing strategy of the request, as implemented in the `strategy` module:
+//! round-robin by default. It also supports adding and removing backends dynamically.
+//!
+//! Backends can be marked down, for instance by active health checks, in which case
+//! they are not selected until they are marked up again. They are also skipped while
+//! ejected after failing too many requests in a row, as done in the `outlier` module.
+//!
+//! Requests with session affinity are sent to the backend named by their affinity cookie,
+//! or to the backend of their key on a consistent hash ring, as done in the `affinity` module.
+//!
+//! A pool can have a slow start window, during which backends added to it receive a share
+//! of the requests ramping linearly from `SLOW_START_MIN_SHARE` of their full share, so
+//! that they warm up their caches before taking their full load. Backends added to an empty
+//! pool, such as when the controller starts, receive their full share at once.
+//!
+//! The weight of a backend under weighted strategies comes from the weight of its zone,
+//! as reported by its EndpointSlice. Backends of unweighted zones, or without a zone,
+//! weigh 1.
+//!
+//! Selecting a backend takes no lock, so that requests handled by different threads do not
+//! wait for each other. The list of backends is an immutable snapshot, replaced as a whole
+//! when backends are added or removed, and the state of each backend (marked down, ejected,
+//! weight, requests in flight) is kept in atomics.
 
+use arc_swap::ArcSwap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use super::affinity::{backend_id, hash, HashRing};
+use super::outlier::{OutlierDetection, OutlierState};
+use super::strategy::{BackendStats, Backends, InFlight, LoadBalancing, Strategies};
+
+/// Share of the requests a backend receives when its slow start begins, relative to its
+/// full share.
+pub const SLOW_START_MIN_SHARE: f64 = 0.1;
+
+/// A backend server of a pool, along with its state.
+#[derive(Debug)]
+struct Backend {
+    addr: SocketAddr,
+    /// Relative share of the requests the backend receives under weighted strategies.
+    weight: AtomicU32,
+    /// Requests in flight to the backend and their latency.
+    stats: Arc<BackendStats>,
+    /// Set while the backend is marked down.
+    down: AtomicBool,
+    /// End of the current or last ejection, in nanoseconds since the pool was created.
+    ejected_until: AtomicU64,
+    /// Outcome of the recent requests to the backend.
+    outlier: Mutex<OutlierState>,
+    /// Set while the outcome of the recent requests has nothing to forget, so that
+    /// successes need not lock it.
+    clean: AtomicBool,
+    /// When the backend was added to a pool of other backends, in nanoseconds since the
+    /// pool was created, which starts its slow start.
+    added: Option<u64>,
+}
+
+impl Backend {
+    fn new(addr: SocketAddr, added: Option<u64>) -> Self {
+        Self {
+            addr,
+            weight: AtomicU32::new(1),
+            stats: Arc::default(),
+            down: AtomicBool::new(false),
+            ejected_until: AtomicU64::new(0),
+            outlier: Mutex::new(OutlierState::default()),
+            clean: AtomicBool::new(true),
+            added,
+        }
+    }
+
+    /// Returns `true` unless the backend is marked down or ejected.
+    fn is_available(&self, now: u64) -> bool {
+        !self.down.load(Ordering::Relaxed) && !self.is_ejected(now)
+    }
+
+    fn is_ejected(&self, now: u64) -> bool {
+        now < self.ejected_until.load(Ordering::Relaxed)
+    }
+
+    /// Returns the share of the requests the backend receives relative to its full share,
+    /// given the slow start window of its pool in nanoseconds.
+    fn share(&self, now: u64, slow_start: u64) -> f64 {
+        match self.added {
+            Some(added) if now.saturating_sub(added) < slow_start => {
+                let elapsed = (now - added) as f64 / slow_start as f64;
+                SLOW_START_MIN_SHARE + (1.0 - SLOW_START_MIN_SHARE) * elapsed
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+/// The backends of a pool at some point in time.
+#[derive(Debug, Default)]
+struct Snapshot {
+    backends: Vec<Arc<Backend>>,
+    /// Consistent hash ring of the backends, built on first use.
+    ring: OnceLock<HashRing>,
+}
+
+impl Snapshot {
+    fn new(backends: Vec<Arc<Backend>>) -> Self {
+        Self { backends, ring: OnceLock::new() }
+    }
+
+    fn get(&self, addr: &SocketAddr) -> Option<&Arc<Backend>> {
+        self.backends.iter().find(|backend| backend.addr == *addr)
+    }
+}
+
+/// The backends of a snapshot as seen by the strategies at some instant.
+///
+/// Backends in slow start are left out of a selection with a probability making up for
+/// the rest of their share. The draw is derived from `draw` and the backend index, so that
+/// a backend stays in or out for the whole selection.
+struct SnapshotBackends<'a> {
+    backends: &'a [Arc<Backend>],
+    now: u64,
+    /// Slow start window of the pool in nanoseconds, or 0 to leave no backend out.
+    slow_start: u64,
+    draw: u64,
+}
+
+impl Backends for SnapshotBackends<'_> {
+    fn count(&self) -> usize {
+        self.backends.len()
+    }
+
+    fn is_available(&self, index: usize) -> bool {
+        let backend = &self.backends[index];
+        if !backend.is_available(self.now) {
+            return false;
+        }
+        let share = backend.share(self.now, self.slow_start);
+        if share >= 1.0 {
+            return true;
+        }
+        // Uniform in [0, 1)
+        let draw = (hash(&(self.draw ^ index as u64).to_le_bytes()) >> 11) as f64 / (1u64 << 53) as f64;
+        draw < share
+    }
+
+    fn weight(&self, index: usize) -> u32 {
+        self.backends[index].weight.load(Ordering::Relaxed)
+    }
+
+    fn stats(&self, index: usize) -> &BackendStats {
+        &self.backends[index].stats
+    }
+}
 
-/// A load balancer that manages backend servers and distributes requests using round-robin.
-#[derive(Clone, Debug)]
+/// The zones of the backends of a pool and the weights of those zones.
+#[derive(Debug, Default)]
+struct ZoneWeights {
+    zones: HashMap<SocketAddr, String>,
+    weights: BTreeMap<String, u32>,
+}
+
+impl ZoneWeights {
+    /// Returns the weight of a backend, from the weight of its zone.
+    fn weight(&self, backend: &SocketAddr) -> u32 {
+        self.zones.get(backend).and_then(|zone| self.weights.get(zone)).copied().unwrap_or(1)
+    }
+}
+
+/// A load balancer that manages backend servers and distributes requests across them.
+#[derive(Debug)]
 pub struct LoadBalancer {
-    /// List of backend server addresses.
-    backends: Arc<Mutex<Vec<SocketAddr>>>,
-    /// Index for the next backend in the round-robin sequence.
-    current_index: Arc<Mutex<usize>>,
+    /// Current list of backend servers.
+    snapshot: ArcSwap<Snapshot>,
+    /// Held while replacing the list of backends, so that concurrent changes are not lost.
+    updating: Mutex<()>,
+    /// Load balancing strategies, along with their state such as round-robin positions.
+    strategies: Strategies,
+    /// Held while ejecting a backend, so that concurrent ejections respect the maximum share.
+    ejecting: Mutex<()>,
+    /// Slow start window of the backends added to the pool in nanoseconds, or 0 if none.
+    slow_start: AtomicU64,
+    /// Zones of the backends and weights of the zones, setting the weights of the backends.
+    zone_weights: Mutex<ZoneWeights>,
+    /// Origin of the ejection and addition times of the backends.
+    created: Instant,
 }
 
 impl LoadBalancer {
@@ -21,30 +195,224 @@ impl LoadBalancer {
     /// # Parameters
     /// - `backends`: A list of backend server addresses to initialize the load balancer.
     pub fn new(backends: Vec<SocketAddr>) -> Self {
+        let backends = backends.into_iter().map(|addr| Arc::new(Backend::new(addr, None))).collect();
         Self {
-            backends: Arc::new(Mutex::new(backends)),
-            current_index: Arc::new(Mutex::new(0)),
+            snapshot: ArcSwap::from_pointee(Snapshot::new(backends)),
+            updating: Mutex::new(()),
+            strategies: Strategies::default(),
+            ejecting: Mutex::new(()),
+            slow_start: AtomicU64::new(0),
+            zone_weights: Mutex::default(),
+            created: Instant::now(),
         }
     }
 
-    /// Selects the next backend using a round-robin strategy.
+    /// Selects the next backend using a round-robin strategy, skipping backends marked down
+    /// or ejected.
     ///
     /// # Returns
     /// An optional `SocketAddr` of the selected backend, or `None` if no backends are available.
     pub fn select_backend(&self) -> Option<SocketAddr> {
-        let backends = self.backends.lock().unwrap();
-        println!("Available backends: {:?}", *backends);
+        self.select(LoadBalancing::RoundRobin)
+    }
+
+    /// Selects a backend using a load balancing strategy, skipping backends marked down
+    /// or ejected.
+    ///
+    /// Backends in slow start are left out of some selections, unless no other backend
+    /// is available.
+    ///
+    /// # Parameters
+    /// - `load_balancing`: The strategy of the Ingress routing the request.
+    ///
+    /// # Returns
+    /// An optional `SocketAddr` of the selected backend, or `None` if no backends are available.
+    pub fn select(&self, load_balancing: LoadBalancing) -> Option<SocketAddr> {
+        let snapshot = self.snapshot.load();
+        let slow_start = self.slow_start.load(Ordering::Relaxed);
+        let mut backends = SnapshotBackends {
+            backends: &snapshot.backends,
+            now: self.now(),
+            slow_start,
+            draw: if slow_start > 0 { rand::random() } else { 0 },
+        };
+        let strategy = self.strategies.get(load_balancing);
+        let selected = strategy.select(&backends).or_else(|| {
+            backends.slow_start = 0;
+            strategy.select(&backends)
+        });
+        selected.map(|index| snapshot.backends[index].addr)
+    }
+
+    /// Selects the backend of a key on the consistent hash ring of the pool, skipping
+    /// backends marked down or ejected.
+    ///
+    /// # Parameters
+    /// - `key`: The hash of the key read from the request.
+    ///
+    /// # Returns
+    /// An optional `SocketAddr` of the selected backend, or `None` if no backends are available.
+    pub fn select_by_hash(&self, key: u64) -> Option<SocketAddr> {
+        let snapshot = self.snapshot.load();
+        let ring = snapshot.ring.get_or_init(|| {
+            let addrs: Vec<SocketAddr> = snapshot.backends.iter().map(|backend| backend.addr).collect();
+            HashRing::new(&addrs)
+        });
+        let now = self.now();
+        ring.get(key, |addr| snapshot.get(addr).is_some_and(|backend| backend.is_available(now)))
+    }
+
+    /// Selects the backend named by an affinity cookie, unless it left the pool, is marked
+    /// down or is ejected.
+    ///
+    /// # Parameters
+    /// - `id`: The identifier of the backend, as returned by `affinity::backend_id`.
+    pub fn select_by_id(&self, id: &str) -> Option<SocketAddr> {
+        let now = self.now();
+        self.snapshot.load().backends.iter()
+            .find(|backend| backend_id(&backend.addr) == id)
+            .filter(|backend| backend.is_available(now))
+            .map(|backend| backend.addr)
+    }
+
+    /// Counts a request in flight to a backend, for the strategies balancing requests in
+    /// flight and latency.
+    ///
+    /// # Returns
+    /// A guard to complete once the backend responded, or `None` if the backend left the pool.
+    pub fn start_request(&self, backend: SocketAddr) -> Option<InFlight> {
+        self.snapshot.load().get(&backend).map(|backend| backend.stats.start())
+    }
+
+    /// Sets the share of the requests a backend receives under weighted strategies, until
+    /// the zones of the backends or their weights change.
+    ///
+    /// # Returns
+    /// `true` if the backend belongs to the pool.
+    pub fn set_weight(&self, backend: SocketAddr, weight: u32) -> bool {
+        self.snapshot.load().get(&backend).map(|backend| backend.weight.store(weight, Ordering::Relaxed)).is_some()
+    }
+
+    /// Sets the zones of the backends, as reported by their EndpointSlices, and weighs
+    /// the backends by the weight of their zone.
+    ///
+    /// # Parameters
+    /// - `zones`: The zone of each backend reporting one.
+    pub fn set_zones(&self, zones: HashMap<SocketAddr, String>) {
+        let mut zone_weights = self.zone_weights.lock().unwrap();
+        zone_weights.zones = zones;
+        self.apply_zone_weights(&zone_weights);
+    }
+
+    /// Sets the weights of the zones, weighing the backends of each zone with it. Backends
+    /// of other zones, or without a zone, weigh 1.
+    pub fn set_zone_weights(&self, weights: BTreeMap<String, u32>) {
+        let mut zone_weights = self.zone_weights.lock().unwrap();
+        if zone_weights.weights != weights {
+            zone_weights.weights = weights;
+            self.apply_zone_weights(&zone_weights);
+        }
+    }
 
-        if backends.is_empty() {
-            println!("No backends available");
+    /// Returns the weights of the zones of the backends.
+    pub fn zone_weights(&self) -> BTreeMap<String, u32> {
+        self.zone_weights.lock().unwrap().weights.clone()
+    }
+
+    /// Sets the weight of every backend from the weight of its zone.
+    fn apply_zone_weights(&self, zone_weights: &ZoneWeights) {
+        for backend in &self.snapshot.load().backends {
+            backend.weight.store(zone_weights.weight(&backend.addr), Ordering::Relaxed);
+        }
+    }
+
+    /// Sets the slow start window of the backends added to the pool from now on, and of
+    /// those still in theirs.
+    ///
+    /// # Parameters
+    /// - `window`: The time for a new backend to ramp up to its full share, or `None` to
+    ///   have new backends receive their full share at once.
+    pub fn set_slow_start(&self, window: Option<Duration>) {
+        let nanos = window.map_or(0, |window| window.as_nanos().try_into().unwrap_or(u64::MAX));
+        self.slow_start.store(nanos, Ordering::Relaxed);
+    }
+
+    /// Returns the slow start window of the backends added to the pool, if any.
+    pub fn slow_start(&self) -> Option<Duration> {
+        Some(self.slow_start.load(Ordering::Relaxed)).filter(|nanos| *nanos > 0).map(Duration::from_nanos)
+    }
+
+    /// Marks a backend down, so that it is no longer selected.
+    ///
+    /// # Returns
+    /// `true` if the backend was up and belongs to the pool.
+    pub fn mark_down(&self, backend: SocketAddr) -> bool {
+        self.snapshot.load().get(&backend).is_some_and(|backend| !backend.down.swap(true, Ordering::Relaxed))
+    }
+
+    /// Marks a backend up, so that it is selected again.
+    ///
+    /// # Returns
+    /// `true` if the backend was down.
+    pub fn mark_up(&self, backend: SocketAddr) -> bool {
+        self.snapshot.load().get(&backend).is_some_and(|backend| backend.down.swap(false, Ordering::Relaxed))
+    }
+
+    /// Records a request a backend answered without a server error.
+    pub fn report_success(&self, backend: SocketAddr) {
+        let snapshot = self.snapshot.load();
+        let Some(backend) = snapshot.get(&backend) else {
+            return;
+        };
+        if backend.clean.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut state = backend.outlier.lock().unwrap();
+        state.success(Instant::now());
+        backend.clean.store(state.is_clean(), Ordering::Relaxed);
+    }
+
+    /// Records a request a backend failed, by refusing the connection or with a server error,
+    /// ejecting the backend once it failed too many in a row.
+    ///
+    /// # Parameters
+    /// - `backend`: The backend that failed the request.
+    /// - `config`: The outlier detection settings.
+    ///
+    /// # Returns
+    /// How long the backend is ejected for, if this failure ejected it. A backend is not
+    /// ejected if that would eject more than the maximum share of the pool.
+    pub fn report_failure(&self, backend: SocketAddr, config: &OutlierDetection) -> Option<Duration> {
+        let snapshot = self.snapshot.load();
+        let backend = snapshot.get(&backend)?;
+        let mut state = backend.outlier.lock().unwrap();
+        backend.clean.store(false, Ordering::Relaxed);
+        let now = Instant::now();
+        if !state.failure(config, now) {
             return None;
         }
 
-        let mut index = self.current_index.lock().unwrap();
-        let backend = backends[*index];
-        *index = (*index + 1) % backends.len();
-        println!("Selected backend: {}", backend);
-        Some(backend)
+        let _ejecting = self.ejecting.lock().unwrap();
+        let since_created = self.since_created(now);
+        let ejected = snapshot.backends.iter().filter(|backend| backend.is_ejected(since_created)).count();
+        if !config.allows(ejected + 1, snapshot.backends.len()) {
+            return None;
+        }
+        let duration = state.eject(config, now);
+        let until = since_created.saturating_add(duration.as_nanos().try_into().unwrap_or(u64::MAX));
+        backend.ejected_until.store(until, Ordering::Relaxed);
+        Some(duration)
+    }
+
+    /// Returns `true` while a backend is ejected after failing requests.
+    pub fn is_ejected(&self, backend: &SocketAddr) -> bool {
+        let now = self.now();
+        self.snapshot.load().get(backend).is_some_and(|backend| backend.is_ejected(now))
+    }
+
+    /// Returns `true` unless the backend is marked down.
+    pub fn is_up(&self, backend: &SocketAddr) -> bool {
+        !self.snapshot.load().get(backend).is_some_and(|backend| backend.down.load(Ordering::Relaxed))
     }
 
     /// Adds a backend to the list if it is not already present.
@@ -52,10 +420,16 @@ impl LoadBalancer {
     /// # Parameters
     /// - `backend`: The address of the backend to add.
     pub fn add_backend(&self, backend: SocketAddr) {
-        let mut backends = self.backends.lock().unwrap();
-        if !backends.contains(&backend) {
+        let _updating = self.updating.lock().unwrap();
+        let snapshot = self.snapshot.load();
+        if snapshot.get(&backend).is_none() {
             println!("Adding backend: {}", backend);
-            backends.push(backend);
+            let added = (!snapshot.backends.is_empty()).then(|| self.now());
+            let mut backends = snapshot.backends.clone();
+            let new_backend = Backend::new(backend, added);
+            new_backend.weight.store(self.zone_weights.lock().unwrap().weight(&backend), Ordering::Relaxed);
+            backends.push(Arc::new(new_backend));
+            self.snapshot.store(Arc::new(Snapshot::new(backends)));
         } else {
             println!("Backend already exists: {}", backend);
         }
@@ -66,13 +440,212 @@ impl LoadBalancer {
     /// # Parameters
     /// - `backend`: The address of the backend to remove.
     pub fn remove_backend(&self, backend: &SocketAddr) {
-        let mut backends = self.backends.lock().unwrap();
-        backends.retain(|&b| b != *backend);
+        let _updating = self.updating.lock().unwrap();
+        let snapshot = self.snapshot.load();
+        let backends = snapshot.backends.iter().filter(|b| b.addr != *backend).cloned().collect();
+        self.snapshot.store(Arc::new(Snapshot::new(backends)));
+    }
+
+    /// Replaces the whole list of backends.
+    ///
+    /// # Parameters
+    /// - `backends`: The new list of backend server addresses.
+    ///
+    /// Backends that remain in the list keep their weight and statistics, and keep being
+    /// marked down or ejected if they were. New backends start their slow start, unless
+    /// the pool was empty, and weigh as much as their zone.
+    pub fn set_backends(&self, backends: Vec<SocketAddr>) {
+        let _updating = self.updating.lock().unwrap();
+        let snapshot = self.snapshot.load();
+        let added = (!snapshot.backends.is_empty()).then(|| self.now());
+        let zone_weights = self.zone_weights.lock().unwrap();
+        let backends = backends.into_iter()
+            .map(|addr| {
+                snapshot.get(&addr).cloned().unwrap_or_else(|| {
+                    let backend = Backend::new(addr, added);
+                    backend.weight.store(zone_weights.weight(&addr), Ordering::Relaxed);
+                    Arc::new(backend)
+                })
+            })
+            .collect();
+        self.snapshot.store(Arc::new(Snapshot::new(backends)));
     }
 
     /// Returns a list of current backend addresses.
     pub fn get_backends(&self) -> Vec<SocketAddr> {
-        let backends = self.backends.lock().unwrap();
-        backends.clone()
+        self.snapshot.load().backends.iter().map(|backend| backend.addr).collect()
+    }
+
+    /// Returns the current time, in nanoseconds since the pool was created.
+    fn now(&self) -> u64 {
+        self.since_created(Instant::now())
+    }
+
+    fn since_created(&self, instant: Instant) -> u64 {
+        instant.duration_since(self.created).as_nanos().try_into().unwrap_or(u64::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_select_backend_skips_backends_marked_down() {
+        let pool = LoadBalancer::new(vec![addr(1), addr(2), addr(3)]);
+        assert!(pool.mark_down(addr(2)));
+        assert!(!pool.mark_down(addr(2)));
+        assert!(!pool.mark_down(addr(4)));
+
+        let selected: Vec<_> = (0..4).filter_map(|_| pool.select_backend()).collect();
+        assert_eq!(selected, vec![addr(1), addr(3), addr(1), addr(3)]);
+
+        pool.mark_down(addr(1));
+        pool.mark_down(addr(3));
+        assert_eq!(pool.select_backend(), None);
+
+        assert!(pool.mark_up(addr(2)));
+        assert_eq!(pool.select_backend(), Some(addr(2)));
+
+        // Backends leaving the pool forget their state
+        pool.set_backends(vec![addr(1), addr(2)]);
+        pool.set_backends(vec![addr(1), addr(2), addr(3)]);
+        assert!(!pool.is_up(&addr(1)));
+        assert!(pool.is_up(&addr(3)));
+    }
+
+    #[test]
+    fn test_ejects_failing_backends_up_to_the_max_ejection_percent() {
+        let config = OutlierDetection { consecutive_errors: 2, max_ejection_percent: 50, ..Default::default() };
+        let pool = LoadBalancer::new(vec![addr(1), addr(2), addr(3), addr(4)]);
+
+        assert_eq!(pool.report_failure(addr(1), &config), None);
+        pool.report_success(addr(1));
+        assert_eq!(pool.report_failure(addr(1), &config), None);
+        assert_eq!(pool.report_failure(addr(1), &config), Some(Duration::from_secs(30)));
+        assert!(pool.is_ejected(&addr(1)));
+        assert_eq!(pool.report_failure(addr(5), &config), None);
+
+        let selected: Vec<_> = (0..3).filter_map(|_| pool.select_backend()).collect();
+        assert_eq!(selected, vec![addr(2), addr(3), addr(4)]);
+
+        // Two out of four backends can be ejected at most
+        for backend in [addr(2), addr(3)] {
+            pool.report_failure(backend, &config);
+            pool.report_failure(backend, &config);
+        }
+        assert!(pool.is_ejected(&addr(2)));
+        assert!(!pool.is_ejected(&addr(3)));
+        let mut selected: Vec<_> = (0..2).filter_map(|_| pool.select_backend()).collect();
+        selected.sort();
+        assert_eq!(selected, vec![addr(3), addr(4)]);
+
+        // Backends leaving the pool forget their ejection
+        pool.set_backends(vec![addr(3), addr(4)]);
+        pool.set_backends(vec![addr(1), addr(2), addr(3), addr(4)]);
+        assert!(!pool.is_ejected(&addr(1)));
+    }
+
+    #[test]
+    fn test_select_with_strategy() {
+        let pool = LoadBalancer::new(vec![addr(1), addr(2), addr(3)]);
+        assert!(pool.set_weight(addr(1), 2));
+        assert!(!pool.set_weight(addr(4), 2));
+        pool.mark_down(addr(3));
+
+        let selected: Vec<_> = (0..6).filter_map(|_| pool.select(LoadBalancing::WeightedRoundRobin)).collect();
+        assert_eq!(selected, vec![addr(1), addr(1), addr(2), addr(1), addr(1), addr(2)]);
+
+        // Requests in flight steer least-requests away, until they end
+        let in_flight = pool.start_request(addr(1)).unwrap();
+        assert_eq!(pool.select(LoadBalancing::LeastRequests), Some(addr(2)));
+        drop(in_flight);
+        assert!(pool.start_request(addr(4)).is_none());
+
+        // Backends remaining in the pool keep their weight
+        pool.set_backends(vec![addr(1), addr(5)]);
+        let selected: Vec<_> = (0..3).filter_map(|_| pool.select(LoadBalancing::WeightedRoundRobin)).collect();
+        assert_eq!(selected.iter().filter(|backend| **backend == addr(1)).count(), 2);
+    }
+
+    #[test]
+    fn test_zone_weights() {
+        let pool = LoadBalancer::new(vec![addr(1), addr(2)]);
+        pool.set_zones(HashMap::from([(addr(1), "zone-a".to_string()), (addr(3), "zone-a".to_string())]));
+        pool.set_zone_weights(BTreeMap::from([("zone-a".to_string(), 2)]));
+
+        let selected: Vec<_> = (0..3).filter_map(|_| pool.select(LoadBalancing::WeightedRoundRobin)).collect();
+        assert_eq!(selected.iter().filter(|backend| **backend == addr(1)).count(), 2);
+
+        // Backends added to a weighted zone weigh as much as it
+        pool.set_backends(vec![addr(2), addr(3)]);
+        let selected: Vec<_> = (0..3).filter_map(|_| pool.select(LoadBalancing::WeightedRoundRobin)).collect();
+        assert_eq!(selected.iter().filter(|backend| **backend == addr(3)).count(), 2);
+
+        pool.set_zone_weights(BTreeMap::new());
+        let selected: Vec<_> = (0..2).filter_map(|_| pool.select(LoadBalancing::WeightedRoundRobin)).collect();
+        assert_eq!(selected.iter().filter(|backend| **backend == addr(3)).count(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_selection_and_updates() {
+        let pool = Arc::new(LoadBalancer::new(vec![addr(1), addr(2), addr(3), addr(4)]));
+        let selections: Vec<_> = (0..16)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || (0..1_000).filter_map(|_| pool.select_backend()).collect::<Vec<_>>())
+            })
+            .collect();
+        let selected: Vec<SocketAddr> = selections.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+
+        // Round-robin positions are shared by every thread
+        for backend in [addr(1), addr(2), addr(3), addr(4)] {
+            assert_eq!(selected.iter().filter(|selected| **selected == backend).count(), 4_000);
+        }
+
+        // Changes made from several threads are all kept
+        let adders: Vec<_> = (10..26)
+            .map(|port| {
+                let pool = pool.clone();
+                std::thread::spawn(move || pool.add_backend(addr(port)))
+            })
+            .collect();
+        for adder in adders {
+            adder.join().unwrap();
+        }
+        assert_eq!(pool.get_backends().len(), 20);
+    }
+
+    #[test]
+    fn test_slow_start_ramps_up_new_backends() {
+        let backend = Backend::new(addr(1), Some(1_000));
+        assert_eq!(backend.share(1_000, 0), 1.0);
+        assert_eq!(backend.share(1_000, 1_000), SLOW_START_MIN_SHARE);
+        assert!((backend.share(1_500, 1_000) - 0.55).abs() < 1e-9);
+        assert_eq!(backend.share(2_000, 1_000), 1.0);
+        assert_eq!(Backend::new(addr(1), None).share(0, 1_000), 1.0);
+
+        let pool = LoadBalancer::new(Vec::new());
+        pool.set_slow_start(Some(Duration::from_secs(3600)));
+        // The first backend of a pool takes its full share at once
+        pool.add_backend(addr(1));
+        pool.add_backend(addr(2));
+        let selected: Vec<_> = (0..10_000).filter_map(|_| pool.select_backend()).collect();
+        let new = selected.iter().filter(|selected| **selected == addr(2)).count();
+        // Half of the requests while in, which is a tenth of the time
+        assert!((300..800).contains(&new), "{} requests to the new backend", new);
+
+        // A backend in slow start is still selected when no other backend is available
+        pool.mark_down(addr(1));
+        assert!((0..100).all(|_| pool.select_backend() == Some(addr(2))));
+
+        pool.set_slow_start(None);
+        pool.mark_up(addr(1));
+        let selected: Vec<_> = (0..4).filter_map(|_| pool.select_backend()).collect();
+        assert_eq!(selected.iter().filter(|selected| **selected == addr(2)).count(), 2);
     }
 }
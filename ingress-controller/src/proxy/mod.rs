@@ -1,12 +1,18 @@
 // Módulo del proxy principal
 // src/proxy/mod.rs
 
+pub mod affinity;
 pub mod cache;
+pub mod headers;
 pub mod router;
+pub mod server;
+pub mod upgrade;
 pub mod http;
 pub mod load_balancer;
+pub mod outlier;
+pub mod strategy;
 
 pub use cache::Cache;
 pub use router::Router;
-pub use http::HttpProxy;
+pub use http::{HttpProxy, ProxyConfig, ProxyError};
 pub use load_balancer::LoadBalancer;
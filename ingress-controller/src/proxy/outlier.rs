@@ -0,0 +1,173 @@
+//! Outlier detection module ejecting failing backends from their pool for a while.
+//!
+//! The proxy reports the outcome of every request to the pool of its backend. A backend
+//! answering with a 5xx status or refusing connections `consecutive_errors` times in a row
+//! is ejected: it receives no request for the base ejection time, doubled at each new
+//! ejection up to `MAX_EJECTION_TIME`. The count of ejections is forgotten once the backend
+//! stayed in the pool for as long as its last ejection lasted.
+//!
+//! At most `max_ejection_percent` of the backends of a pool are ejected at a time, so that
+//! ejections alone never leave a pool empty.
+
+use std::time::{Duration, Instant};
+
+/// Consecutive errors ejecting a backend, when none is configured.
+pub const DEFAULT_CONSECUTIVE_ERRORS: u32 = 5;
+
+/// Time a backend is ejected for the first time, when none is configured.
+pub const DEFAULT_BASE_EJECTION_TIME: Duration = Duration::from_secs(30);
+
+/// Share of the backends of a pool that can be ejected at a time, when none is configured.
+pub const DEFAULT_MAX_EJECTION_PERCENT: u8 = 50;
+
+/// Longest time a backend is ejected for, unless the base ejection time is longer.
+pub const MAX_EJECTION_TIME: Duration = Duration::from_secs(300);
+
+/// Settings of the outlier detection, applying to every backend pool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutlierDetection {
+    /// Errors in a row ejecting a backend.
+    pub consecutive_errors: u32,
+    /// Time a backend is ejected for the first time.
+    pub base_ejection_time: Duration,
+    /// Share of the backends of a pool that can be ejected at a time, in percent.
+    pub max_ejection_percent: u8,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_errors: DEFAULT_CONSECUTIVE_ERRORS,
+            base_ejection_time: DEFAULT_BASE_EJECTION_TIME,
+            max_ejection_percent: DEFAULT_MAX_EJECTION_PERCENT,
+        }
+    }
+}
+
+impl OutlierDetection {
+    /// Returns how long a backend ejected `ejections` times before is ejected for.
+    pub fn ejection_time(&self, ejections: u32) -> Duration {
+        self.base_ejection_time
+            .saturating_mul(1 << ejections.min(16))
+            .min(MAX_EJECTION_TIME.max(self.base_ejection_time))
+    }
+
+    /// Returns `true` if a pool of `backends` backends can have `ejected` of them ejected.
+    pub fn allows(&self, ejected: usize, backends: usize) -> bool {
+        ejected * 100 <= backends * usize::from(self.max_ejection_percent)
+    }
+}
+
+/// Outcome of the recent requests to a backend.
+#[derive(Clone, Debug, Default)]
+pub struct OutlierState {
+    /// Errors in a row since the last success.
+    consecutive_errors: u32,
+    /// Ejections since the count was last forgotten.
+    ejections: u32,
+    /// End of the current or last ejection.
+    ejected_until: Option<Instant>,
+    /// Length of the last ejection.
+    last_ejection: Duration,
+}
+
+impl OutlierState {
+    /// Returns `true` while the backend is ejected.
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+
+    /// Returns `true` if the backend has no error nor ejection to forget.
+    pub fn is_clean(&self) -> bool {
+        self.consecutive_errors == 0 && self.ejections == 0
+    }
+
+    /// Records a successful request.
+    pub fn success(&mut self, now: Instant) {
+        self.consecutive_errors = 0;
+        if self.ejected_until.is_some_and(|until| now >= until + self.last_ejection) {
+            self.ejections = 0;
+        }
+    }
+
+    /// Records a failed request.
+    ///
+    /// # Returns
+    /// `true` if the backend reached the consecutive errors ejecting it. Failures of
+    /// requests sent before an ejection are not counted while it lasts.
+    pub fn failure(&mut self, config: &OutlierDetection, now: Instant) -> bool {
+        if self.is_ejected(now) {
+            return false;
+        }
+        self.consecutive_errors += 1;
+        self.consecutive_errors >= config.consecutive_errors
+    }
+
+    /// Ejects the backend, starting its count of errors over.
+    ///
+    /// # Returns
+    /// How long the backend is ejected for.
+    pub fn eject(&mut self, config: &OutlierDetection, now: Instant) -> Duration {
+        let duration = config.ejection_time(self.ejections);
+        self.ejections += 1;
+        self.consecutive_errors = 0;
+        self.ejected_until = Some(now + duration);
+        self.last_ejection = duration;
+        duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ejection_time_doubles_up_to_the_maximum() {
+        let config = OutlierDetection { base_ejection_time: Duration::from_secs(30), ..Default::default() };
+        let times: Vec<u64> = (0..5).map(|ejections| config.ejection_time(ejections).as_secs()).collect();
+        assert_eq!(times, vec![30, 60, 120, 240, 300]);
+        assert_eq!(config.ejection_time(u32::MAX), MAX_EJECTION_TIME);
+
+        let config = OutlierDetection { base_ejection_time: Duration::from_secs(600), ..Default::default() };
+        assert_eq!(config.ejection_time(3), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn test_max_ejection_percent() {
+        let config = OutlierDetection { max_ejection_percent: 50, ..Default::default() };
+        assert!(!config.allows(1, 1));
+        assert!(config.allows(1, 2));
+        assert!(!config.allows(2, 3));
+        assert!(config.allows(5, 10));
+    }
+
+    #[test]
+    fn test_state_ejects_after_consecutive_errors() {
+        let config = OutlierDetection { consecutive_errors: 2, ..Default::default() };
+        let now = Instant::now();
+        let mut state = OutlierState::default();
+
+        assert!(!state.failure(&config, now));
+        state.success(now);
+        assert!(!state.failure(&config, now));
+        assert!(state.failure(&config, now));
+        assert_eq!(state.eject(&config, now), Duration::from_secs(30));
+        assert!(state.is_ejected(now + Duration::from_secs(29)));
+        // Requests sent before the ejection do not extend it
+        assert!(!state.failure(&config, now));
+        assert!(!state.failure(&config, now));
+
+        // Failing again right after coming back doubles the ejection
+        let back = now + Duration::from_secs(30);
+        assert!(!state.is_ejected(back));
+        state.failure(&config, back);
+        state.failure(&config, back);
+        assert_eq!(state.eject(&config, back), Duration::from_secs(60));
+
+        // Staying in the pool as long as the last ejection lasted forgets the ejections
+        state.success(back + Duration::from_secs(120));
+        state.failure(&config, back + Duration::from_secs(120));
+        state.failure(&config, back + Duration::from_secs(120));
+        assert_eq!(state.eject(&config, back + Duration::from_secs(120)), Duration::from_secs(30));
+    }
+}
@@ -1,60 +1,680 @@
 //! Router module for managing request routing to backend servers.
 //!
-//! The `Router` struct stores route mappings, associating paths with backend servers.
+//! The `Router` struct stores the routing table built from Ingress rules. Routes are
+//! grouped by host, and every route points at a backend pool (a `LoadBalancer`) shared
+//! by all the routes that reference the same Kubernetes service.
+//!
+//! Paths are matched following the Ingress `pathType` rules: `Exact` paths match the
+//! request path verbatim, `Prefix` paths match element-wise on `/`-separated segments,
+//! the path matching the most segments wins and `Exact` is preferred over `Prefix` on ties.
+//!
+//! Requests matching no route are sent to the `spec.defaultBackend` of an Ingress, or
+//! else to the default backend of the controller, if any.
+//!
+//! Each Ingress also carries `IngressOptions`, set from its annotations, that tune how
+//! the requests it routes are proxied.
+//!
+//! The proxy reads the routing table through a `SharedRouter` without taking any lock:
+//! changes are made to a copy of the table, which then replaces it atomically.
 
-use std::collections::HashMap;
+use arc_swap::{ArcSwap, Guard};
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use super::affinity::SessionAffinity;
+use super::load_balancer::LoadBalancer;
+use super::strategy::LoadBalancing;
+use crate::health_check::HealthCheckConfig;
 
-/// Represents a route with a path and its associated backend server address.
-#[derive(Clone, Debug)]
+/// Routing table shared between the ingress processor, which updates it, and the proxy.
+///
+/// Readers get the current version of the table, which never changes once published;
+/// updates publish a modified copy. Clones share the same table.
+#[derive(Clone, Debug, Default)]
+pub struct SharedRouter {
+    inner: Arc<SharedRouterInner>,
+}
+
+#[derive(Debug, Default)]
+struct SharedRouterInner {
+    current: ArcSwap<Router>,
+    /// Held while updating the table, so that concurrent updates are not lost.
+    updating: Mutex<()>,
+}
+
+impl SharedRouter {
+    /// Shares a routing table.
+    pub fn new(router: Router) -> Self {
+        Self {
+            inner: Arc::new(SharedRouterInner {
+                current: ArcSwap::from_pointee(router),
+                updating: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Returns the current version of the routing table.
+    ///
+    /// The returned guard is meant to be short-lived, and not held across `.await` points.
+    pub fn load(&self) -> Guard<Arc<Router>> {
+        self.inner.current.load()
+    }
+
+    /// Changes the routing table, publishing the changed copy once `change` returns.
+    ///
+    /// # Returns
+    /// The value returned by `change`.
+    pub fn update<R>(&self, change: impl FnOnce(&mut Router) -> R) -> R {
+        let _updating = self.inner.updating.lock().unwrap();
+        let mut router = Router::clone(&self.inner.current.load());
+        let result = change(&mut router);
+        self.inner.current.store(Arc::new(router));
+        result
+    }
+}
+
+/// How a route path is matched against request paths, as in the Ingress `pathType` field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathType {
+    /// Matches the request path exactly, case-sensitively.
+    Exact,
+    /// Matches request paths whose leading `/`-separated elements equal the route path's.
+    Prefix,
+    /// Left to the controller; flusso handles it as `Prefix`.
+    ImplementationSpecific,
+}
+
+impl PathType {
+    /// Returns `true` if the route path matches the request path.
+    pub fn matches(&self, route_path: &str, request_path: &str) -> bool {
+        match self {
+            PathType::Exact => route_path == request_path,
+            PathType::Prefix | PathType::ImplementationSpecific => {
+                let mut request_segments = segments(request_path);
+                segments(route_path).all(|segment| request_segments.next() == Some(segment))
+            }
+        }
+    }
+}
+
+impl From<&str> for PathType {
+    /// Parses a `pathType` value, treating unknown values as `ImplementationSpecific`.
+    fn from(value: &str) -> Self {
+        match value {
+            "Exact" => PathType::Exact,
+            "Prefix" => PathType::Prefix,
+            _ => PathType::ImplementationSpecific,
+        }
+    }
+}
+
+/// Splits a path into its non-empty `/`-separated elements.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Represents a route declared by an Ingress rule path.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Route {
+    /// The Ingress that declared the route, as `namespace/name`.
+    pub ingress: String,
+    /// The host of the rule, or `None` if the rule applies to every host.
+    pub host: Option<String>,
     pub path: String,
-    pub backend: SocketAddr,
+    pub path_type: PathType,
+    /// Key of the backend pool serving the route, as `namespace/service:port`.
+    pub backend: String,
+}
+
+/// Protocol spoken to the backends of an Ingress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    /// HTTP/1.1, which can be upgraded, for instance to WebSocket.
+    Http1,
+    /// HTTP/2 over cleartext with prior knowledge (h2c), as used by gRPC.
+    Http2,
 }
 
-/// A router that maps paths to backend servers.
-#[derive(Default)]
+/// Proxy settings of an Ingress, set from its annotations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IngressOptions {
+    /// Sends the client's `Host` header to backends, instead of the backend address.
+    pub preserve_host: bool,
+    /// Protocol spoken to the backends.
+    pub upstream_protocol: UpstreamProtocol,
+    /// Active health check of the backends, if enabled.
+    pub health_check: Option<HealthCheckConfig>,
+    /// Strategy choosing the backend of each request.
+    pub load_balancing: LoadBalancing,
+    /// How the requests of a client are kept on the same backend, if they are.
+    pub affinity: Option<SessionAffinity>,
+    /// Time for backends added to the pools of the Ingress to ramp up to their full share
+    /// of the requests, if they do.
+    pub slow_start: Option<Duration>,
+    /// Answers requests from the shared cache of responses, and stores responses there.
+    pub cache: bool,
+    /// Time cached responses can be served stale while revalidated or when the backend
+    /// fails, unless their `Cache-Control` header tells.
+    pub cache_stale: Option<Duration>,
+    /// Weight of the backends of each zone, for the weighted round robin strategy.
+    /// Backends of other zones weigh `1`.
+    pub zone_weights: BTreeMap<String, u32>,
+}
+
+/// Options of the routes without an Ingress, such as the controller default backend.
+static DEFAULT_OPTIONS: IngressOptions = IngressOptions {
+    preserve_host: true,
+    upstream_protocol: UpstreamProtocol::Http1,
+    health_check: None,
+    load_balancing: LoadBalancing::RoundRobin,
+    affinity: None,
+    slow_start: None,
+    cache: false,
+    cache_stale: None,
+    zone_weights: BTreeMap::new(),
+};
+
+impl Default for IngressOptions {
+    fn default() -> Self {
+        DEFAULT_OPTIONS.clone()
+    }
+}
+
+/// Where a request is sent, as found by `Router::target`.
+#[derive(Clone, Debug)]
+pub struct Target<'a> {
+    /// Key of the backend pool serving the request.
+    pub backend: &'a str,
+    /// Options of the Ingress routing the request, shared with the router.
+    pub options: Arc<IngressOptions>,
+    /// The Ingress routing the request, unless it goes to the controller default backend.
+    pub ingress: Option<&'a str>,
+    /// The matching route, unless the request goes to a default backend.
+    pub route: Option<&'a Route>,
+}
+
+/// A router that maps hosts and paths to backend pools.
+///
+/// Copies share their backend pools.
+#[derive(Clone, Default, Debug)]
 pub struct Router {
-    routes: HashMap<String, Route>,
+    /// Routes for rules with a host, keyed by host (wildcard hosts keep their `*.` prefix).
+    hosts: HashMap<String, Vec<Route>>,
+    /// Routes for rules without a host.
+    any_host: Vec<Route>,
+    /// Backend keys of the Ingress default backends, keyed by Ingress, in insertion order.
+    ingress_defaults: Vec<(String, String)>,
+    /// Backend key of the controller-wide default backend.
+    default_backend: Option<String>,
+    /// Options of the Ingresses that differ from the defaults, keyed by Ingress.
+    options: HashMap<String, Arc<IngressOptions>>,
+    /// Options of the routes whose Ingress sets none, shared by their targets.
+    default_options: Arc<IngressOptions>,
+    /// Backend pools keyed by backend key.
+    pools: HashMap<String, Arc<LoadBalancer>>,
 }
 
 impl Router {
     /// Creates a new, empty router.
     pub fn new() -> Self {
         Self {
-            routes: HashMap::new(),
+            hosts: HashMap::new(),
+            any_host: Vec::new(),
+            ingress_defaults: Vec::new(),
+            default_backend: None,
+            options: HashMap::new(),
+            default_options: Arc::default(),
+            pools: HashMap::new(),
+        }
+    }
+
+    /// Adds a route, creating its backend pool if it does not exist yet.
+    pub fn add_route(&mut self, route: Route) {
+        self.pool(&route.backend);
+        match &route.host {
+            Some(host) => self.hosts.entry(host.to_lowercase()).or_default().push(route),
+            None => self.any_host.push(route),
+        }
+    }
+
+    /// Replaces every route and the default backend declared by an Ingress.
+    ///
+    /// # Parameters
+    /// - `ingress`: The Ingress, as `namespace/name`.
+    /// - `routes`: The routes declared by its rules.
+    /// - `default_backend`: Backend key of its `spec.defaultBackend`, if any.
+    pub fn set_ingress_routes(&mut self, ingress: &str, routes: Vec<Route>, default_backend: Option<String>) {
+        self.retain_routes(|route| route.ingress != ingress);
+        for route in routes {
+            self.add_route(route);
+        }
+
+        // An Ingress keeps its place among default backends when updated
+        match (self.ingress_defaults.iter().position(|(name, _)| name == ingress), default_backend) {
+            (Some(index), Some(backend)) => {
+                self.pool(&backend);
+                self.ingress_defaults[index].1 = backend;
+            }
+            (Some(index), None) => {
+                self.ingress_defaults.remove(index);
+            }
+            (None, Some(backend)) => {
+                self.pool(&backend);
+                self.ingress_defaults.push((ingress.to_string(), backend));
+            }
+            (None, None) => {}
+        }
+        self.prune_pools();
+        self.apply_pool_options();
+    }
+
+    /// Removes every route declared by an Ingress, along with pools no longer referenced.
+    pub fn remove_ingress(&mut self, ingress: &str) {
+        self.retain_routes(|route| route.ingress != ingress);
+        self.ingress_defaults.retain(|(name, _)| name != ingress);
+        self.options.remove(ingress);
+        self.prune_pools();
+        self.apply_pool_options();
+    }
+
+    /// Sets the proxy options of the routes declared by an Ingress.
+    pub fn set_ingress_options(&mut self, ingress: &str, options: IngressOptions) {
+        if options == DEFAULT_OPTIONS {
+            self.options.remove(ingress);
+        } else {
+            self.options.insert(ingress.to_string(), Arc::new(options));
         }
+        self.apply_pool_options();
+    }
+
+    /// Sets the controller-wide default backend, used when no Ingress has a default backend.
+    pub fn set_default_backend(&mut self, backend: Option<String>) {
+        if let Some(backend) = &backend {
+            self.pool(backend);
+        }
+        self.default_backend = backend;
+        self.prune_pools();
+    }
+
+    /// Returns the backend pool for a backend key, creating an empty one if needed.
+    pub fn pool(&mut self, backend: &str) -> Arc<LoadBalancer> {
+        self.pools
+            .entry(backend.to_string())
+            .or_insert_with(|| Arc::new(LoadBalancer::new(Vec::new())))
+            .clone()
+    }
+
+    /// Returns the backend pool for a backend key, if any route references it.
+    pub fn get_pool(&self, backend: &str) -> Option<Arc<LoadBalancer>> {
+        self.pools.get(backend).cloned()
+    }
+
+    /// Finds the route matching a request host and path.
+    ///
+    /// Routes for the exact host are tried first, then routes for the matching wildcard
+    /// host, and finally routes from rules without a host. Within each of them the path
+    /// matching the most segments wins, preferring `Exact` over `Prefix` on ties.
+    pub fn route(&self, host: &str, path: &str) -> Option<&Route> {
+        let host = host.to_lowercase();
+        let wildcard = host.split_once('.').map(|(_, domain)| format!("*.{}", domain));
+
+        let candidates = [
+            self.hosts.get(&host),
+            wildcard.as_ref().and_then(|wildcard| self.hosts.get(wildcard)),
+            Some(&self.any_host),
+        ];
+
+        candidates
+            .into_iter()
+            .flatten()
+            .find_map(|routes| best_match(routes, path))
+    }
+
+    /// Returns the key of the backend pool serving a request host and path.
+    ///
+    /// The matching route is used if any, then the default backend of the first Ingress
+    /// declaring one, and finally the controller-wide default backend.
+    pub fn backend_for(&self, host: &str, path: &str) -> Option<&str> {
+        self.target(host, path).map(|target| target.backend)
+    }
+
+    /// Returns the key of the backend pool serving a request host and path, along with
+    /// the Ingress and route that sent it there and the options of that Ingress.
+    pub fn target(&self, host: &str, path: &str) -> Option<Target<'_>> {
+        let route = self.route(host, path);
+        let (ingress, backend) = match route {
+            Some(route) => (Some(route.ingress.as_str()), &route.backend),
+            None => match self.ingress_defaults.first() {
+                Some((ingress, backend)) => (Some(ingress.as_str()), backend),
+                None => (None, self.default_backend.as_ref()?),
+            },
+        };
+        let options = ingress.and_then(|ingress| self.options.get(ingress)).unwrap_or(&self.default_options).clone();
+        Some(Target { backend, options, ingress, route })
     }
 
-    /// Adds a route with a specified path and backend server address.
-    pub fn add_route(&mut self, path: String, backend: SocketAddr) {
-        let route = Route { path: path.clone(), backend };
-        self.routes.insert(path, route);
+    /// Retrieves a backend address for a request host and path from the matching pool,
+    /// using the load balancing strategy of the Ingress routing it.
+    pub fn get_backend(&self, host: &str, path: &str) -> Option<SocketAddr> {
+        let target = self.target(host, path)?;
+        self.pools.get(target.backend)?.select(target.options.load_balancing)
     }
 
-    /// Retrieves the backend address for a given request path.
-    pub fn get_backend(&self, request_path: &str) -> Option<SocketAddr> {
-        for (path, route) in &self.routes {
-            if request_path.starts_with(path) {
-                return Some(route.backend);
+    /// Returns the backend pools whose backends are actively health checked, along with
+    /// their backend key and health check.
+    ///
+    /// A pool routed by several Ingresses enabling a health check uses the check of the
+    /// first of them by name.
+    pub fn health_checks(&self) -> Vec<(String, Arc<LoadBalancer>, HealthCheckConfig)> {
+        self.pool_options(|options| options.health_check.as_ref())
+            .into_iter()
+            .filter_map(|(backend, check)| {
+                let pool = self.pools.get(backend)?;
+                Some((backend.to_string(), pool.clone(), check.clone()))
+            })
+            .collect()
+    }
+
+    /// Returns an option set by Ingresses for the backend pools they route to, keyed by
+    /// backend key.
+    ///
+    /// A pool routed by several Ingresses setting the option uses the option of the first
+    /// of them by name.
+    fn pool_options<'a, T>(&'a self, option: impl Fn(&'a IngressOptions) -> Option<&'a T>) -> BTreeMap<&'a str, &'a T> {
+        let mut firsts: BTreeMap<&str, (&str, &T)> = BTreeMap::new();
+        let referenced = self.routes()
+            .map(|route| (route.ingress.as_str(), route.backend.as_str()))
+            .chain(self.ingress_defaults.iter().map(|(ingress, backend)| (ingress.as_str(), backend.as_str())));
+        for (ingress, backend) in referenced {
+            let Some(value) = self.options.get(ingress).map(Arc::as_ref).and_then(&option) else {
+                continue;
+            };
+            match firsts.get(backend) {
+                Some((first, _)) if *first <= ingress => {}
+                _ => {
+                    firsts.insert(backend, (ingress, value));
+                }
             }
         }
-        None
+        firsts.into_iter().map(|(backend, (_, value))| (backend, value)).collect()
+    }
+
+    /// Sets the slow start window and zone weights of every backend pool from the options
+    /// of the Ingresses routing to it.
+    fn apply_pool_options(&self) {
+        let slow_starts = self.pool_options(|options| options.slow_start.as_ref());
+        let zone_weights = self.pool_options(|options| Some(&options.zone_weights).filter(|weights| !weights.is_empty()));
+        for (backend, pool) in &self.pools {
+            pool.set_slow_start(slow_starts.get(backend.as_str()).map(|window| **window));
+            pool.set_zone_weights(zone_weights.get(backend.as_str()).map(|weights| (*weights).clone()).unwrap_or_default());
+        }
+    }
+
+    /// Returns every backend pool along with its backend key.
+    pub fn pools(&self) -> impl Iterator<Item = (&str, &Arc<LoadBalancer>)> {
+        self.pools.iter().map(|(backend, pool)| (backend.as_str(), pool))
+    }
+
+    /// Returns every route in the routing table.
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.hosts.values().flatten().chain(self.any_host.iter())
+    }
+
+    /// Keeps only the routes matching a predicate.
+    fn retain_routes<F: Fn(&Route) -> bool>(&mut self, keep: F) {
+        for routes in self.hosts.values_mut() {
+            routes.retain(&keep);
+        }
+        self.hosts.retain(|_, routes| !routes.is_empty());
+        self.any_host.retain(&keep);
+    }
+
+    /// Drops backend pools that no route or default backend references anymore.
+    fn prune_pools(&mut self) {
+        let referenced: Vec<String> = self.routes()
+            .map(|route| route.backend.clone())
+            .chain(self.ingress_defaults.iter().map(|(_, backend)| backend.clone()))
+            .chain(self.default_backend.clone())
+            .collect();
+        self.pools.retain(|backend, _| referenced.contains(backend));
     }
 }
 
+/// Returns the most specific route matching a request path.
+///
+/// Among routes of the same precedence the first one added wins.
+fn best_match<'a>(routes: &'a [Route], path: &str) -> Option<&'a Route> {
+    let precedence = |route: &Route| (segments(&route.path).count(), route.path_type == PathType::Exact);
+
+    routes
+        .iter()
+        .filter(|route| route.path_type.matches(&route.path, path))
+        .fold(None, |best: Option<&Route>, route| match best {
+            Some(best) if precedence(best) >= precedence(route) => Some(best),
+            _ => Some(route),
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn route(ingress: &str, host: Option<&str>, path: &str, backend: &str) -> Route {
+        Route {
+            ingress: ingress.to_string(),
+            host: host.map(str::to_string),
+            path: path.to_string(),
+            path_type: PathType::Prefix,
+            backend: backend.to_string(),
+        }
+    }
+
+    /// Builds a router with one route per `(path type, path)` pair, using the path as backend.
+    fn router_with(paths: &[(PathType, &str)]) -> Router {
+        let mut router = Router::new();
+        for (index, (path_type, path)) in paths.iter().enumerate() {
+            router.add_route(Route {
+                ingress: "default/conformance".to_string(),
+                host: None,
+                path: path.to_string(),
+                path_type: *path_type,
+                backend: format!("{:?} {} #{}", path_type, path, index),
+            });
+        }
+        router
+    }
+
+    /// A conformance case: the routes' `(path type, path)`, the request path and the
+    /// index of the route expected to match.
+    type Case = (&'static [(PathType, &'static str)], &'static str, Option<usize>);
+
+    /// Path matching cases from the Kubernetes Ingress documentation and conformance suite.
+    #[test]
+    fn test_path_type_conformance() {
+        use PathType::{Exact, Prefix};
+
+        let cases: &[Case] = &[
+            (&[(Prefix, "/")], "/", Some(0)),
+            (&[(Prefix, "/")], "/any/path", Some(0)),
+            (&[(Exact, "/foo")], "/foo", Some(0)),
+            (&[(Exact, "/foo")], "/bar", None),
+            (&[(Exact, "/foo")], "/foo/", None),
+            (&[(Exact, "/foo/")], "/foo", None),
+            (&[(Exact, "/foo")], "/FOO", None),
+            (&[(Prefix, "/foo")], "/foo", Some(0)),
+            (&[(Prefix, "/foo")], "/foo/", Some(0)),
+            (&[(Prefix, "/foo/")], "/foo", Some(0)),
+            (&[(Prefix, "/foo/")], "/foo/", Some(0)),
+            (&[(Exact, "/foo"), (Prefix, "/foo/")], "/foo", Some(0)),
+            (&[(Prefix, "/foo/"), (Exact, "/foo")], "/foo", Some(1)),
+            (&[(Prefix, "/aaa/bb")], "/aaa/bbb", None),
+            (&[(Prefix, "/aaa/bbb")], "/aaa/bbb", Some(0)),
+            (&[(Prefix, "/aaa/bbb/")], "/aaa/bbb", Some(0)),
+            (&[(Prefix, "/aaa/bbb")], "/aaa/bbb/", Some(0)),
+            (&[(Prefix, "/aaa/bbb")], "/aaa/bbb/ccc", Some(0)),
+            (&[(Prefix, "/aaa/bbb")], "/aaa/bbbxyz", None),
+            (&[(Prefix, "/"), (Prefix, "/aaa")], "/aaa/ccc", Some(1)),
+            (&[(Prefix, "/aaa"), (Prefix, "/")], "/aaa/ccc", Some(0)),
+            (&[(Prefix, "/"), (Prefix, "/aaa"), (Prefix, "/aaa/bbb")], "/aaa/bbb", Some(2)),
+            (&[(Prefix, "/"), (Prefix, "/aaa"), (Prefix, "/aaa/bbb")], "/ccc", Some(0)),
+            (&[(Prefix, "/aaa")], "/ccc", None),
+            (&[(Prefix, "/foo"), (Exact, "/foo")], "/foo", Some(1)),
+            (&[(Exact, "/foo"), (Prefix, "/foo")], "/foo", Some(0)),
+            (&[(Prefix, "/foo"), (Exact, "/foo")], "/foo/bar", Some(0)),
+            (&[(Exact, "/foo/bar"), (Prefix, "/foo")], "/foo/bar", Some(0)),
+            (&[(PathType::ImplementationSpecific, "/aaa")], "/aaa/bbb", Some(0)),
+        ];
+
+        for (paths, request_path, expected) in cases {
+            let router = router_with(paths);
+            let expected = expected.map(|index| {
+                let (path_type, path) = paths[index];
+                format!("{:?} {} #{}", path_type, path, index)
+            });
+            let actual = router.route("example.com", request_path).map(|route| route.backend.clone());
+            assert_eq!(actual, expected, "paths {:?}, request {}", paths, request_path);
+        }
+    }
+
     #[test]
     fn test_add_and_retrieve_route() {
         let mut router = Router::new();
         let backend_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
 
-        router.add_route("/api".to_string(), backend_addr);
+        router.add_route(route("default/api", None, "/api", "default/api:80"));
+        router.pool("default/api:80").add_backend(backend_addr);
+
+        assert_eq!(router.get_backend("example.com", "/api/v1"), Some(backend_addr));
+        assert_eq!(router.get_backend("example.com", "/not_found"), None);
+    }
+
+    #[test]
+    fn test_routes_by_host() {
+        let mut router = Router::new();
+        router.add_route(route("default/a", Some("a.example.com"), "/", "default/a:80"));
+        router.add_route(route("default/b", Some("*.example.com"), "/", "default/b:80"));
+        router.add_route(route("default/c", None, "/", "default/c:80"));
+
+        assert_eq!(router.route("A.example.com", "/").unwrap().backend, "default/a:80");
+        assert_eq!(router.route("b.example.com", "/").unwrap().backend, "default/b:80");
+        assert_eq!(router.route("x.y.example.com", "/").unwrap().backend, "default/c:80");
+        assert_eq!(router.route("other.org", "/").unwrap().backend, "default/c:80");
+    }
+
+    #[test]
+    fn test_replace_and_remove_ingress_routes() {
+        let mut router = Router::new();
+        router.add_route(route("default/a", Some("a.example.com"), "/", "default/a:80"));
+        router.add_route(route("default/b", Some("b.example.com"), "/", "default/b:80"));
+
+        router.set_ingress_routes(
+            "default/a",
+            vec![route("default/a", Some("a.example.com"), "/v2", "default/a2:80")],
+            None,
+        );
+        assert!(router.route("a.example.com", "/").is_none());
+        assert_eq!(router.route("a.example.com", "/v2").unwrap().backend, "default/a2:80");
+        assert!(router.get_pool("default/a:80").is_none());
+
+        router.remove_ingress("default/b");
+        assert!(router.route("b.example.com", "/").is_none());
+        assert!(router.get_pool("default/b:80").is_none());
+        assert!(router.get_pool("default/a2:80").is_some());
+    }
+
+    #[test]
+    fn test_default_backends() {
+        let mut router = Router::new();
+        router.add_route(route("default/a", Some("a.example.com"), "/api", "default/a:80"));
+        assert_eq!(router.backend_for("a.example.com", "/"), None);
+
+        router.set_default_backend(Some("flusso/fallback:80".to_string()));
+        assert_eq!(router.backend_for("a.example.com", "/"), Some("flusso/fallback:80"));
+
+        // Ingress default backends take precedence, the first one declared winning
+        router.set_ingress_routes("default/b", Vec::new(), Some("default/b:80".to_string()));
+        router.set_ingress_routes("default/c", Vec::new(), Some("default/c:80".to_string()));
+        router.set_ingress_routes("default/b", Vec::new(), Some("default/b:8080".to_string()));
+        assert_eq!(router.backend_for("other.org", "/"), Some("default/b:8080"));
+        assert_eq!(router.backend_for("a.example.com", "/api"), Some("default/a:80"));
+        assert!(router.get_pool("default/b:80").is_none());
+
+        router.remove_ingress("default/b");
+        assert_eq!(router.backend_for("other.org", "/"), Some("default/c:80"));
+        router.set_ingress_routes("default/c", Vec::new(), None);
+        assert_eq!(router.backend_for("other.org", "/"), Some("flusso/fallback:80"));
+        assert!(router.get_pool("default/c:80").is_none());
+    }
+
+    #[test]
+    fn test_ingress_options() {
+        let mut router = Router::new();
+        router.set_ingress_routes("default/a", vec![route("default/a", None, "/a", "default/a:80")], None);
+        router.set_ingress_routes("default/b", vec![route("default/b", None, "/b", "default/b:80")], None);
+        let options = IngressOptions { preserve_host: false, ..Default::default() };
+        router.set_ingress_options("default/a", options.clone());
+
+        assert_eq!(*router.target("example.com", "/a").unwrap().options, options);
+        assert_eq!(*router.target("example.com", "/b").unwrap().options, IngressOptions::default());
+
+        router.remove_ingress("default/a");
+        router.set_ingress_routes("default/a", vec![route("default/a", None, "/a", "default/a:80")], None);
+        assert_eq!(*router.target("example.com", "/a").unwrap().options, IngressOptions::default());
+    }
+
+    #[test]
+    fn test_health_checks() {
+        let mut router = Router::new();
+        router.set_ingress_routes("default/b", vec![route("default/b", None, "/b", "default/shared:80")], None);
+        router.set_ingress_routes("default/a", vec![route("default/a", None, "/a", "default/shared:80")], None);
+        router.set_ingress_routes("default/c", vec![route("default/c", None, "/c", "default/c:80")], Some("default/d:80".to_string()));
+        assert!(router.health_checks().is_empty());
+
+        let check = |path: &str| HealthCheckConfig { path: path.to_string(), ..Default::default() };
+        router.set_ingress_options("default/b", IngressOptions { health_check: Some(check("/b")), ..Default::default() });
+        router.set_ingress_options("default/a", IngressOptions { health_check: Some(check("/a")), ..Default::default() });
+        router.set_ingress_options("default/c", IngressOptions { health_check: Some(check("/c")), ..Default::default() });
+
+        let checks: Vec<_> = router.health_checks()
+            .into_iter()
+            .map(|(backend, _, check)| (backend, check.path))
+            .collect();
+        assert_eq!(checks, vec![
+            ("default/c:80".to_string(), "/c".to_string()),
+            ("default/d:80".to_string(), "/c".to_string()),
+            ("default/shared:80".to_string(), "/a".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_slow_start_of_pools() {
+        let mut router = Router::new();
+        router.set_ingress_routes("default/b", vec![route("default/b", None, "/b", "default/shared:80")], None);
+        router.set_ingress_routes("default/a", vec![route("default/a", None, "/a", "default/shared:80")], None);
+        let slow_start = |seconds| IngressOptions { slow_start: Some(Duration::from_secs(seconds)), ..Default::default() };
+        router.set_ingress_options("default/b", slow_start(60));
+        assert_eq!(router.get_pool("default/shared:80").unwrap().slow_start(), Some(Duration::from_secs(60)));
+
+        router.set_ingress_options("default/a", slow_start(30));
+        assert_eq!(router.get_pool("default/shared:80").unwrap().slow_start(), Some(Duration::from_secs(30)));
+
+        router.remove_ingress("default/a");
+        assert_eq!(router.get_pool("default/shared:80").unwrap().slow_start(), Some(Duration::from_secs(60)));
+
+        router.set_ingress_options("default/b", IngressOptions::default());
+        assert_eq!(router.get_pool("default/shared:80").unwrap().slow_start(), None);
+    }
+
+    #[test]
+    fn test_zone_weights_of_pools() {
+        let mut router = Router::new();
+        router.set_ingress_routes("default/a", vec![route("default/a", None, "/a", "default/shared:80")], None);
+        let weights = BTreeMap::from([("zone-a".to_string(), 3)]);
+        router.set_ingress_options("default/a", IngressOptions { zone_weights: weights.clone(), ..Default::default() });
+        assert_eq!(router.get_pool("default/shared:80").unwrap().zone_weights(), weights);
 
-        assert_eq!(router.get_backend("/api/v1"), Some(backend_addr));
-        assert_eq!(router.get_backend("/not_found"), None);
+        router.set_ingress_options("default/a", IngressOptions::default());
+        assert!(router.get_pool("default/shared:80").unwrap().zone_weights().is_empty());
     }
 }
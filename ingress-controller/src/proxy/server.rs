@@ -0,0 +1,886 @@
+//! Server module accepting client connections for the proxy.
+//!
+//! Every listener serves HTTP/1.1 and HTTP/2: cleartext connections opening with the
+//! HTTP/2 connection preface are served as h2c with prior knowledge, and TLS connections
+//! negotiate the protocol through ALPN. Requests are forwarded with `HttpProxy`, and the
+//! responses of backends are streamed back along with their trailers, as gRPC requires.
+//!
+//! The metrics of each request are recorded once its response body was sent, or once its
+//! upgraded connection was closed.
+//!
+//! On shutdown, listeners stop accepting connections and the open ones are closed once
+//! their in-flight requests are answered, waiting at most a grace period.
+
+use super::headers::{strip_hop_by_hop, ClientInfo};
+use super::http::{BodyStream, HttpProxy, ProxyError};
+use super::upgrade::{tunnel, upgrade_protocol};
+use crate::metrics::RequestMetrics;
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt};
+use http::header::{HeaderValue, CONNECTION, CONTENT_TYPE, HOST, UPGRADE};
+use http::{Request, Response, StatusCode, Version};
+use http_body::Body as _;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyDataStream, BodyExt, Empty, Full};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use log::debug;
+use std::convert::Infallible;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+/// Body of the responses sent to clients.
+pub type ResponseBody = UnsyncBoxBody<Bytes, Box<dyn Error + Send + Sync>>;
+
+/// Page served for requests that match no route when no default backend is configured.
+const NOT_FOUND_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>404 Not Found</title></head>
+<body>
+<h1>404 Not Found</h1>
+<p>No Ingress rule matches this host and path.</p>
+<hr><p>flusso</p>
+</body>
+</html>
+";
+
+/// Longest time open connections are given to finish their requests on shutdown.
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(25);
+
+/// Accepts connections on a listener and serves the requests they carry, until shutdown.
+///
+/// Once `shutdown` is set, the listener is closed and the open connections are closed
+/// after answering their in-flight requests, or dropped after `SHUTDOWN_GRACE_PERIOD`.
+///
+/// # Parameters
+/// - `listener`: The listener to accept connections on.
+/// - `tls`: Acceptor terminating TLS on each connection, for HTTPS listeners.
+/// - `proxy`: The proxy forwarding requests to backends.
+/// - `shutdown`: Flag set when the controller shuts down.
+pub async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    proxy: Arc<HttpProxy>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let connections = GracefulShutdown::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Ok(_) = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let proxy = proxy.clone();
+        let watcher = connections.watcher();
+        tokio::spawn(async move {
+            match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, peer, true, proxy, watcher).await,
+                    Err(e) => eprintln!("TLS handshake with {} failed: {}", peer, e),
+                },
+                None => serve_connection(stream, peer, false, proxy, watcher).await,
+            }
+        });
+    }
+
+    let addr = listener.local_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    drop(listener);
+    println!("Stopped accepting connections on {}, draining open connections", addr);
+    if timeout(SHUTDOWN_GRACE_PERIOD, connections.shutdown()).await.is_err() {
+        eprintln!("Dropping the connections on {} still open after {:?}", addr, SHUTDOWN_GRACE_PERIOD);
+    }
+}
+
+/// Serves the requests of a client connection, over HTTP/1.1 or HTTP/2, closing it
+/// gracefully once `watcher` tells the listener shuts down.
+async fn serve_connection<S>(stream: S, peer: SocketAddr, secure: bool, proxy: Arc<HttpProxy>, watcher: Watcher)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |request| {
+        let proxy = proxy.clone();
+        async move { Ok::<_, Infallible>(handle(request, peer, secure, &proxy).await) }
+    });
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+    if let Err(e) = watcher.watch(connection).await {
+        eprintln!("Error serving connection from {}: {}", peer, e);
+    }
+}
+
+/// Forwards a client request to its backend.
+///
+/// # Parameters
+/// - `request`: The request received from the client.
+/// - `peer`: The address of the client.
+/// - `secure`: Whether the request was received over TLS.
+/// - `proxy`: The proxy forwarding requests to backends.
+///
+/// # Returns
+/// The response streamed from the backend (or relaying the connection once upgraded, for
+/// instance to a WebSocket), a 404 page if no route matches, a 413 if the request body is
+/// too large, a 503 if the matching pool has no backend, or a 502 if the backend request fails.
+pub async fn handle(mut request: Request<Incoming>, peer: SocketAddr, secure: bool, proxy: &HttpProxy) -> Response<ResponseBody> {
+    // HTTP/2 requests carry the host in the `:authority` pseudo-header
+    let host_header = request.headers()
+        .get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| request.uri().authority().map(|authority| authority.to_string()));
+    let host = host_without_port(host_header.as_deref().unwrap_or_default()).to_lowercase();
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str()).to_string();
+    let client = ClientInfo {
+        addr: Some(peer.ip()),
+        secure,
+        host: host_header,
+    };
+
+    // The client side of an upgraded connection is handed over once the response is sent
+    let upgrade = upgrade_protocol(request.headers()).map(|_| hyper::upgrade::on(&mut request));
+    let (parts, body) = request.into_parts();
+    let body = (upgrade.is_none() && !body.is_end_stream()).then(|| request_stream(body));
+
+    debug!("Forwarding request to host: {}, path: {}", host, path);
+
+    let mut metrics = proxy.metrics().start_request(parts.method.as_str());
+    let forwarded = proxy.forward_request(&host, &path, parts.method, parts.headers, body, &client, &mut metrics).await;
+    let response = match forwarded {
+        Ok(response) if response.status() == StatusCode::SWITCHING_PROTOCOLS => match upgrade {
+            Some(upgrade) => return relay_upgrade(response, upgrade, proxy, format!("{}{}", host, path), metrics).await,
+            None => {
+                eprintln!("Backend for {}{} switched protocols without an upgrade request", host, path);
+                text_response(StatusCode::BAD_GATEWAY, "Bad Gateway")
+            }
+        },
+        Ok(response) => to_response(response),
+        Err(ProxyError::NoRoute) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header(CONTENT_TYPE, "text/html")
+            .body(full(NOT_FOUND_PAGE))
+            .unwrap(),
+        Err(e @ ProxyError::PayloadTooLarge(_)) => {
+            eprintln!("Rejecting request to {}{}: {}", host, path, e);
+            text_response(StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large")
+        }
+        Err(e @ ProxyError::NoBackend(_)) => {
+            eprintln!("Error forwarding request to {}{}: {}", host, path, e);
+            text_response(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable")
+        }
+        Err(e @ ProxyError::Upstream(_)) => {
+            eprintln!("Error forwarding request to {}{}: {}", host, path, e);
+            text_response(StatusCode::BAD_GATEWAY, "Bad Gateway")
+        }
+    };
+    observe(response, metrics)
+}
+
+/// Records the metrics of a request once its response body was sent to the client.
+fn observe(response: Response<ResponseBody>, mut metrics: RequestMetrics) -> Response<ResponseBody> {
+    metrics.set_status(response.status().as_u16());
+    response.map(|body| {
+        body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                metrics.add_response_bytes(data.len() as u64);
+            }
+            frame
+        })
+        .boxed_unsync()
+    })
+}
+
+/// Turns the body of a client request into a stream of chunks sent to the backend.
+fn request_stream(body: Incoming) -> BodyStream {
+    BodyDataStream::new(body).map_err(io::Error::other).boxed()
+}
+
+/// Builds the response sent to the client from a backend response.
+///
+/// The status, every end-to-end header (including repeated ones such as `Set-Cookie`), the
+/// raw body bytes and the trailers are kept, the body being streamed to the client as the
+/// backend sends it.
+fn to_response(response: reqwest::Response) -> Response<ResponseBody> {
+    let (mut parts, body) = http::Response::<reqwest::Body>::from(response).into_parts();
+    strip_hop_by_hop(&mut parts.headers);
+    // The version spoken to the client is the one of its own connection
+    parts.version = Version::default();
+    Response::from_parts(parts, body.map_err(Into::into).boxed_unsync())
+}
+
+/// Relays a connection the backend agreed to upgrade, answering the client with the
+/// backend's `101 Switching Protocols` response.
+///
+/// The connection is relayed in a background task until either side closes it or it stays
+/// idle for the configured timeout; its statistics are logged and the metrics of the
+/// request recorded when it closes.
+async fn relay_upgrade(
+    response: reqwest::Response,
+    upgrade: OnUpgrade,
+    proxy: &HttpProxy,
+    target: String,
+    mut metrics: RequestMetrics,
+) -> Response<ResponseBody> {
+    let mut headers = response.headers().clone();
+    let protocol = headers.get(UPGRADE).cloned();
+    strip_hop_by_hop(&mut headers);
+
+    let backend = match response.upgrade().await {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("Failed to upgrade the connection to {}: {}", target, e);
+            return observe(text_response(StatusCode::BAD_GATEWAY, "Bad Gateway"), metrics);
+        }
+    };
+
+    metrics.set_status(StatusCode::SWITCHING_PROTOCOLS.as_u16());
+    let idle_timeout = proxy.config().upgrade_idle_timeout;
+    let upgrades = proxy.upgrade_metrics().clone();
+    tokio::spawn(async move {
+        let client = match upgrade.await {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Failed to upgrade the client connection to {}: {}", target, e);
+                return;
+            }
+        };
+        let stats = tunnel(TokioIo::new(client), backend, idle_timeout, upgrades).await;
+        metrics.add_response_bytes(stats.bytes_from_backend);
+        debug!(
+            "Upgraded connection to {} closed ({:?}) after {:?}: {} bytes from client, {} bytes from backend",
+            target, stats.reason, stats.duration, stats.bytes_from_client, stats.bytes_from_backend,
+        );
+    });
+
+    let mut response = Response::new(Empty::new().map_err(|never| match never {}).boxed_unsync());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    *response.headers_mut() = headers;
+    response.headers_mut().insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    response.headers_mut().insert(UPGRADE, protocol.unwrap_or(HeaderValue::from_static("websocket")));
+    response
+}
+
+/// Builds a plain text response.
+fn text_response(status: StatusCode, text: &'static str) -> Response<ResponseBody> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(full(text))
+        .unwrap()
+}
+
+/// Builds a response body from bytes.
+fn full(body: impl Into<Bytes>) -> ResponseBody {
+    Full::new(body.into()).map_err(|never| match never {}).boxed_unsync()
+}
+
+/// Strips the port from a host, taking care of bracketed IPv6 literals.
+fn host_without_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.ends_with(':') && port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::proxy::affinity::{backend_id, AffinityCookie, SessionAffinity};
+    use crate::proxy::cache::{cache_key, CachedResponse};
+    use crate::proxy::http::ProxyConfig;
+    use crate::proxy::outlier::OutlierDetection;
+    use crate::proxy::router::{IngressOptions, PathType, Route, Router, SharedRouter, UpstreamProtocol};
+    use crate::tls::{sni::SniResolver, TlsConfig};
+    use rustls::pki_types::PrivateKeyDer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    /// Starts a backend answering every request with a fixed raw HTTP response, and
+    /// reporting the head of each request it receives.
+    async fn backend(response: &'static [u8]) -> (SocketAddr, UnboundedReceiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buffer = vec![0; 4096];
+                let read = socket.read(&mut buffer).await.unwrap_or_default();
+                let _ = tx.send(buffer[..read].to_vec());
+                let _ = socket.write_all(response).await;
+            }
+        });
+        (addr, rx)
+    }
+
+    /// Builds a routing table sending every request to a single backend.
+    fn router_to(addr: SocketAddr) -> SharedRouter {
+        let mut router = Router::new();
+        router.add_route(Route {
+            ingress: "default/test".to_string(),
+            host: None,
+            path: "/".to_string(),
+            path_type: PathType::Prefix,
+            backend: "default/test:80".to_string(),
+        });
+        router.pool("default/test:80").set_backends(vec![addr]);
+        SharedRouter::new(router)
+    }
+
+    /// Starts a cleartext proxy listener.
+    async fn start_proxy(proxy: Arc<HttpProxy>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, None, proxy, watch::channel(false).1));
+        addr
+    }
+
+    /// Sends a raw request on a new connection, returning everything read until it is closed.
+    async fn raw_request(addr: SocketAddr, request: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[tokio::test]
+    async fn test_forwards_methods_headers_and_binary_bodies() {
+        let (addr, mut requests) = backend(
+            b"HTTP/1.1 201 Created\r\nContent-Type: application/octet-stream\r\n\
+              Set-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Length: 4\r\nConnection: close\r\n\r\n\x00\xff\x1f\x8b",
+        ).await;
+        let metrics = Arc::new(Metrics::new());
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router_to(addr), ProxyConfig::default(), metrics.clone()))).await;
+
+        let response = raw_request(
+            proxy,
+            b"PATCH /upload?name=a%20b HTTP/1.1\r\nHost: example.com\r\nAccept: text/html\r\n\
+              Accept: application/json\r\nX-Latin: caf\xe9\r\nContent-Length: 4\r\nConnection: close\r\n\r\n\x89PNG",
+        ).await;
+
+        assert!(response.starts_with(b"HTTP/1.1 201 Created\r\n"));
+        assert!(contains(&response, b"set-cookie: a=1\r\nset-cookie: b=2\r\n"));
+        assert!(response.ends_with(b"\r\n\r\n\x00\xff\x1f\x8b"));
+        let head = requests.recv().await.unwrap();
+        assert!(head.starts_with(b"PATCH /upload?name=a%20b HTTP/1.1\r\n"));
+        assert!(contains(&head, b"accept: text/html\r\naccept: application/json\r\n"));
+        assert!(contains(&head, b"x-latin: caf\xe9\r\n"));
+        let encoded = metrics.encode(&Router::new()).unwrap();
+        let route = r#"backend="default/test:80",host="",ingress="test",namespace="default",path="/""#;
+        assert!(encoded.contains(
+            "flusso_requests_total{backend=\"default/test:80\",host=\"\",ingress=\"test\",method=\"PATCH\",\
+             namespace=\"default\",path=\"/\",status=\"201\"} 1\n",
+        ));
+        assert!(encoded.contains(&format!("flusso_response_size_bytes_sum{{{}}} 4\n", route)));
+    }
+
+    #[tokio::test]
+    async fn test_keeps_the_content_length_of_head_responses() {
+        let (addr, _requests) = backend(b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\nConnection: close\r\n\r\n").await;
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router_to(addr), ProxyConfig::default(), Arc::default()))).await;
+
+        let response = raw_request(proxy, b"HEAD / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(contains(&response, b"content-length: 1234\r\n"));
+        assert!(response.ends_with(b"\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_request_bodies_over_the_limit() {
+        let (addr, mut requests) = backend(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+        let config = ProxyConfig { max_body_size: 4, ..Default::default() };
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router_to(addr), config, Arc::default()))).await;
+
+        let response = raw_request(
+            proxy,
+            b"POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\nConnection: close\r\n\r\n12345",
+        ).await;
+
+        assert!(response.starts_with(b"HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ejects_backends_answering_server_errors() {
+        let (failing, mut failing_requests) = backend(
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ).await;
+        let (working, mut working_requests) = backend(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+        let router = router_to(failing);
+        router.load().get_pool("default/test:80").unwrap().set_backends(vec![failing, working]);
+        let outlier_detection = OutlierDetection { consecutive_errors: 2, ..Default::default() };
+        let config = ProxyConfig { outlier_detection: Some(outlier_detection), ..Default::default() };
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router, config, Arc::default()))).await;
+
+        let mut statuses = Vec::new();
+        for _ in 0..6 {
+            let response = raw_request(proxy, b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+            statuses.push(String::from_utf8_lossy(&response[9..12]).to_string());
+        }
+
+        assert_eq!(statuses, vec!["500", "200", "500", "200", "200", "200"]);
+        let mut failed = 0;
+        while failing_requests.try_recv().is_ok() {
+            failed += 1;
+        }
+        assert_eq!(failed, 2);
+        assert!(working_requests.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_keeps_clients_on_their_backend_with_an_affinity_cookie() {
+        let (first, _first_requests) = backend(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nfirst").await;
+        let (second, _second_requests) = backend(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\nsecond").await;
+        let router = router_to(first);
+        router.load().get_pool("default/test:80").unwrap().set_backends(vec![first, second]);
+        let affinity = SessionAffinity::Cookie(AffinityCookie::default());
+        let options = IngressOptions { affinity: Some(affinity), ..Default::default() };
+        router.update(|router| router.set_ingress_options("default/test", options));
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router, ProxyConfig::default(), Arc::default()))).await;
+
+        let response = raw_request(proxy, b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+        let response = String::from_utf8(response).unwrap();
+        let cookie = format!("FLUSSO_AFFINITY={}", backend_id(&first));
+        assert!(response.contains(&format!("set-cookie: {}; Path=/; HttpOnly\r\n", cookie)));
+        assert!(response.ends_with("first"));
+
+        // Requests carrying the cookie stay on the first backend, where round-robin would alternate
+        for _ in 0..3 {
+            let request = format!("GET / HTTP/1.1\r\nHost: example.com\r\nCookie: {}\r\nConnection: close\r\n\r\n", cookie);
+            let response = String::from_utf8(raw_request(proxy, request.as_bytes()).await).unwrap();
+            assert!(!response.contains("set-cookie"));
+            assert!(response.ends_with("first"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_answers_from_the_cache_of_caching_ingresses() {
+        let (addr, mut requests) = backend(
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nETag: \"v1\"\r\nContent-Length: 6\r\nConnection: close\r\n\r\ncached",
+        ).await;
+        let router = router_to(addr);
+        let metrics = Arc::new(Metrics::new());
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router.clone(), ProxyConfig::default(), metrics.clone()))).await;
+        let get = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+
+        // Without the annotation nothing is stored
+        let response = raw_request(proxy, get).await;
+        assert!(!contains(&response, b"cache-status"));
+        requests.recv().await.unwrap();
+
+        let options = IngressOptions { cache: true, ..Default::default() };
+        router.update(|router| router.set_ingress_options("default/test", options));
+        let response = raw_request(proxy, get).await;
+        assert!(contains(&response, b"cache-status: flusso; fwd=uri-miss; fwd-status=200; stored\r\n"));
+        assert!(response.ends_with(b"cached"));
+        requests.recv().await.unwrap();
+
+        let response = raw_request(proxy, get).await;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(contains(&response, b"cache-status: flusso; hit\r\n"));
+        assert!(contains(&response, b"age: 0\r\n"));
+        assert!(response.ends_with(b"cached"));
+        let response = raw_request(proxy, b"GET / HTTP/1.1\r\nHost: example.com\r\nIf-None-Match: \"v1\"\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 304 Not Modified\r\n"));
+        assert!(requests.try_recv().is_err());
+
+        // Unsafe methods reach the backend and invalidate the stored response
+        let response = raw_request(proxy, b"DELETE / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+        assert!(contains(&response, b"cache-status: flusso; fwd=bypass\r\n"));
+        requests.recv().await.unwrap();
+        let response = raw_request(proxy, get).await;
+        assert!(contains(&response, b"fwd=uri-miss"));
+
+        let encoded = metrics.encode(&Router::new()).unwrap();
+        let route = r#"backend="default/test:80",host="",ingress="test",namespace="default",path="/""#;
+        for (result, count) in [("hit", 2), ("miss", 2), ("bypass", 1)] {
+            assert!(encoded.contains(&format!("flusso_cache_requests_total{{{},result=\"{}\"}} {}\n", route, result, count)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revalidates_stale_responses() {
+        let (addr, mut requests) = backend(
+            b"HTTP/1.1 304 Not Modified\r\nCache-Control: max-age=60\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n",
+        ).await;
+        let router = router_to(addr);
+        let options = IngressOptions { cache: true, ..Default::default() };
+        router.update(|router| router.set_ingress_options("default/test", options));
+        let http_proxy = Arc::new(HttpProxy::new(router, ProxyConfig::default(), Arc::default()));
+        let now = std::time::SystemTime::now();
+        let stale = CachedResponse::new(
+            &Default::default(),
+            StatusCode::OK,
+            [(http::header::CACHE_CONTROL, HeaderValue::from_static("no-cache")), (http::header::ETAG, HeaderValue::from_static("\"v1\""))]
+                .into_iter()
+                .collect(),
+            Bytes::from_static(b"stored"),
+            now,
+            now,
+        );
+        http_proxy.cache().store(cache_key("example.com", "/"), Arc::new(stale));
+        let proxy = start_proxy(http_proxy).await;
+        let get = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+
+        let response = raw_request(proxy, get).await;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(contains(&response, b"cache-status: flusso; fwd=stale; fwd-status=304\r\n"));
+        assert!(contains(&response, b"content-length: 6\r\n"));
+        assert!(response.ends_with(b"\r\n\r\nstored"));
+        let head = String::from_utf8(requests.recv().await.unwrap()).unwrap().to_lowercase();
+        assert!(head.contains("if-none-match: \"v1\"\r\n"));
+
+        // The headers of the 304 response made it fresh again
+        let response = raw_request(proxy, get).await;
+        assert!(contains(&response, b"cache-status: flusso; hit\r\n"));
+        assert!(contains(&response, b"cache-control: max-age=60\r\n"));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serves_stale_responses_while_revalidating_and_on_errors() {
+        let (addr, mut requests) = backend(
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndown",
+        ).await;
+        let router = router_to(addr);
+        let options = IngressOptions { cache: true, cache_stale: Some(std::time::Duration::from_secs(60)), ..Default::default() };
+        router.update(|router| router.set_ingress_options("default/test", options));
+        let http_proxy = Arc::new(HttpProxy::new(router, ProxyConfig::default(), Arc::default()));
+        let store = |pairs: [(http::HeaderName, &'static str); 2], path: &str| {
+            let received = std::time::SystemTime::now() - std::time::Duration::from_secs(10);
+            let stale = CachedResponse::new(
+                &Default::default(),
+                StatusCode::OK,
+                pairs.into_iter().map(|(name, value)| (name, HeaderValue::from_static(value))).collect(),
+                Bytes::from_static(b"stored"),
+                received,
+                received,
+            );
+            http_proxy.cache().store(cache_key("example.com", path), Arc::new(stale.with_stale_grace(Some(std::time::Duration::from_secs(60)))));
+        };
+        store([(http::header::CACHE_CONTROL, "max-age=5"), (http::header::ETAG, "\"v1\"")], "/");
+        store([(http::header::CACHE_CONTROL, "max-age=5, stale-while-revalidate=0"), (http::header::ETAG, "\"v1\"")], "/error");
+        let proxy = start_proxy(http_proxy.clone()).await;
+
+        // Served at once, while the backend is asked in the background
+        let response = raw_request(proxy, b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+        assert!(contains(&response, b"cache-status: flusso; hit; detail=stale-while-revalidate\r\n"));
+        assert!(response.ends_with(b"stored"));
+        let head = String::from_utf8(requests.recv().await.unwrap()).unwrap().to_lowercase();
+        assert!(head.contains("if-none-match: \"v1\"\r\n"));
+
+        // Served instead of the server error of the backend
+        let response = raw_request(proxy, b"GET /error HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(contains(&response, b"cache-status: flusso; fwd=stale; fwd-status=503; detail=stale-if-error\r\n"));
+        assert!(response.ends_with(b"stored"));
+        requests.recv().await.unwrap();
+
+        // The error of the revalidation left the stale response in place
+        assert_eq!(http_proxy.cache().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalesces_concurrent_misses() {
+        // A slow backend, so that every request arrives while the first one is fetched
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut requests) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buffer = vec![0; 4096];
+                let read = socket.read(&mut buffer).await.unwrap_or_default();
+                let _ = tx.send(buffer[..read].to_vec());
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                let _ = socket.write_all(b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 6\r\nConnection: close\r\n\r\ncached").await;
+            }
+        });
+        let router = router_to(addr);
+        let options = IngressOptions { cache: true, ..Default::default() };
+        router.update(|router| router.set_ingress_options("default/test", options));
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router, ProxyConfig::default(), Arc::default()))).await;
+        let get = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n";
+
+        let responses = futures_util::future::join_all((0..5).map(|_| raw_request(proxy, get))).await;
+        assert!(responses.iter().all(|response| response.ends_with(b"cached")));
+        let hits = responses.iter().filter(|response| contains(response, b"cache-status: flusso; hit\r\n")).count();
+        assert_eq!(hits, 4);
+        requests.recv().await.unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rewrites_hop_by_hop_forwarding_and_host_headers() {
+        let (addr, mut requests) = backend(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nKeep-Alive: timeout=5\r\nConnection: close\r\n\r\n",
+        ).await;
+        let router = router_to(addr);
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router.clone(), ProxyConfig::default(), Arc::default()))).await;
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close, x-secret\r\n\
+                        X-Secret: hop\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n";
+
+        let response = raw_request(proxy, request).await;
+        assert!(!contains(&response.to_ascii_lowercase(), b"keep-alive"));
+        let head = String::from_utf8(requests.recv().await.unwrap()).unwrap().to_lowercase();
+        assert!(head.contains("host: example.com\r\n"));
+        assert!(head.contains("x-forwarded-for: 127.0.0.1\r\n"));
+        assert!(head.contains("forwarded: for=127.0.0.1;host=example.com;proto=http\r\n"));
+        assert!(!head.contains("x-secret"));
+
+        // The backend address is sent as Host when the Ingress does not preserve it
+        let options = IngressOptions { preserve_host: false, ..Default::default() };
+        router.update(|router| router.set_ingress_options("default/test", options));
+        raw_request(proxy, request).await;
+        let head = String::from_utf8(requests.recv().await.unwrap()).unwrap().to_lowercase();
+        assert!(head.contains(&format!("host: {}\r\n", addr)));
+    }
+
+    #[tokio::test]
+    async fn test_relays_websocket_upgrades() {
+        // Accepts the upgrade, then echoes everything until the client is done
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(socket.read_u8().await.unwrap());
+            }
+            socket.write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                  Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+            ).await.unwrap();
+            let (mut reader, mut writer) = socket.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            String::from_utf8(head).unwrap().to_lowercase()
+        });
+
+        let http_proxy = Arc::new(HttpProxy::new(router_to(addr), ProxyConfig::default(), Arc::default()));
+        let proxy = start_proxy(http_proxy.clone()).await;
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(
+            b"GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        ).await.unwrap();
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap().to_lowercase();
+        assert!(head.starts_with("http/1.1 101 switching protocols\r\n"));
+        assert!(head.contains("upgrade: websocket\r\n"));
+        assert!(head.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo=\r\n"));
+
+        for message in [&b"one"[..], b"two"] {
+            stream.write_all(message).await.unwrap();
+            let mut echoed = vec![0; message.len()];
+            stream.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, message);
+        }
+        stream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+
+        let head = backend.await.unwrap();
+        assert!(head.contains("connection: upgrade\r\n"));
+        assert!(head.contains("upgrade: websocket\r\n"));
+        let metrics = http_proxy.upgrade_metrics().snapshot();
+        assert_eq!((metrics.total, metrics.bytes_from_client, metrics.bytes_from_backend), (1, 6, 6));
+    }
+
+    #[tokio::test]
+    async fn test_drains_connections_on_shutdown() {
+        let (addr, _requests) = backend(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let http_proxy = Arc::new(HttpProxy::new(router_to(addr), ProxyConfig::default(), Arc::default()));
+        let server = tokio::spawn(serve(listener, None, http_proxy, shutdown_rx));
+
+        // A kept-alive connection is closed once the listener shuts down
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\nok") {
+            let mut buffer = [0; 1024];
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0);
+            response.extend_from_slice(&buffer[..read]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        shutdown.send_replace(true);
+        timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert_eq!(stream.read(&mut [0; 1024]).await.unwrap(), 0);
+        assert!(TcpStream::connect(proxy).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_negotiates_http2_over_tls() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let (addr, mut requests) = backend(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await;
+
+        let generated = rcgen::generate_simple_self_signed(vec!["flusso.test".to_string()]).unwrap();
+        let key = PrivateKeyDer::try_from(generated.key_pair.serialize_der()).unwrap();
+        let sni = Arc::new(SniResolver::new());
+        sni.set_fallback(vec![generated.cert.der().clone()], &key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let tls = TlsAcceptor::from(TlsConfig::new(sni).config);
+        let http_proxy = Arc::new(HttpProxy::new(router_to(addr), ProxyConfig::default(), Arc::default()));
+        tokio::spawn(serve(listener, Some(tls), http_proxy, watch::channel(false).1));
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(generated.cert.der()).unwrap())
+            .resolve("flusso.test", proxy)
+            .build()
+            .unwrap();
+        let response = client.get(format!("https://flusso.test:{}/", proxy.port())).send().await.unwrap();
+
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.text().await.unwrap(), "ok");
+        let head = String::from_utf8(requests.recv().await.unwrap()).unwrap().to_lowercase();
+        assert!(head.starts_with("get / http/1.1\r\n"));
+        assert!(head.contains(&format!("host: flusso.test:{}\r\n", proxy.port())));
+        assert!(head.contains("x-forwarded-proto: https\r\n"));
+    }
+
+    /// A gRPC echo service, written out as `tonic-build` would generate it.
+    mod echo {
+        use std::convert::Infallible;
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+        use tonic::body::BoxBody;
+        use tonic::codec::ProstCodec;
+        use tonic::server::{Grpc, NamedService, UnaryService};
+        use tonic::{Request, Response, Status};
+
+        pub const UNARY_ECHO: &str = "/echo.Echo/UnaryEcho";
+
+        #[derive(Clone, PartialEq, prost::Message)]
+        pub struct EchoMessage {
+            #[prost(string, tag = "1")]
+            pub message: String,
+        }
+
+        type BoxFuture<T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send + 'static>>;
+
+        /// Echoes messages back, failing with `NOT_FOUND` for empty ones.
+        #[derive(Clone)]
+        pub struct EchoServer;
+
+        struct UnaryEcho;
+
+        impl UnaryService<EchoMessage> for UnaryEcho {
+            type Response = EchoMessage;
+            type Future = BoxFuture<Response<EchoMessage>, Status>;
+
+            fn call(&mut self, request: Request<EchoMessage>) -> Self::Future {
+                Box::pin(async move {
+                    let message = request.into_inner();
+                    match message.message.is_empty() {
+                        true => Err(Status::not_found("nothing to echo")),
+                        false => Ok(Response::new(message)),
+                    }
+                })
+            }
+        }
+
+        impl<B> tower_service::Service<http::Request<B>> for EchoServer
+        where
+            B: http_body::Body + Send + 'static,
+            B::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + 'static,
+        {
+            type Response = http::Response<BoxBody>;
+            type Error = Infallible;
+            type Future = BoxFuture<Self::Response, Self::Error>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, request: http::Request<B>) -> Self::Future {
+                Box::pin(async move {
+                    match request.uri().path() {
+                        UNARY_ECHO => Ok(Grpc::new(ProstCodec::default()).unary(UnaryEcho, request).await),
+                        _ => Ok(Status::unimplemented("unknown method").into_http()),
+                    }
+                })
+            }
+        }
+
+        impl NamedService for EchoServer {
+            const NAME: &'static str = "echo.Echo";
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxies_grpc_with_trailers() {
+        use echo::{EchoMessage, EchoServer, UNARY_ECHO};
+        use tonic::codec::ProstCodec;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = futures_util::stream::unfold(listener, |listener| async move {
+            Some((listener.accept().await.map(|(stream, _)| stream), listener))
+        });
+        tokio::spawn(tonic::transport::Server::builder().add_service(EchoServer).serve_with_incoming(incoming));
+
+        let router = router_to(addr);
+        let options = IngressOptions { upstream_protocol: UpstreamProtocol::Http2, ..Default::default() };
+        router.update(|router| router.set_ingress_options("default/test", options));
+        let proxy = start_proxy(Arc::new(HttpProxy::new(router, ProxyConfig::default(), Arc::default()))).await;
+
+        let channel = tonic::transport::Channel::from_shared(format!("http://{}", proxy))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = tonic::client::Grpc::new(channel);
+        let echo = |message: &str| {
+            let request = tonic::Request::new(EchoMessage { message: message.to_string() });
+            let path = http::uri::PathAndQuery::from_static(UNARY_ECHO);
+            let mut client = client.clone();
+            async move {
+                client.ready().await.unwrap();
+                client.unary::<_, EchoMessage, _>(request, path, ProstCodec::default()).await
+            }
+        };
+
+        let response = echo("hello").await.unwrap();
+        assert_eq!(response.into_inner().message, "hello");
+
+        // The status travels in the trailers
+        let status = echo("").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(status.message(), "nothing to echo");
+        client.ready().await.unwrap();
+    }
+}
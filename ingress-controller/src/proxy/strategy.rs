@@ -0,0 +1,416 @@
+//! Load balancing strategies choosing which backend of a pool serves a request.
+//!
+//! A `LoadBalancer` hands the backends available for a request, along with their weight
+//! and statistics, to the strategy chosen by the Ingress routing the request:
+//! - `RoundRobin` sends requests to the backends in turn.
+//! - `WeightedRoundRobin` sends requests to the backends in turn, as many to each as its weight.
+//! - `LeastRequests` sends requests to the backend with the fewest requests in flight.
+//! - `PowerOfTwoChoices` draws two backends at random and keeps the one with the lowest
+//!   latency, as an exponentially weighted moving average (EWMA), times its requests in flight.
+//! - `Random` draws a backend at random.
+//!
+//! The statistics of a backend are kept in its `BackendStats`, which the proxy updates
+//! through the `InFlight` guard it holds while waiting for the backend response.
+
+use rand::Rng;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Weight of the latest latency in the moving average of a backend.
+const EWMA_WEIGHT: f64 = 0.3;
+
+/// Load balancing strategy of the requests routed by an Ingress.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoadBalancing {
+    /// Backends in turn.
+    #[default]
+    RoundRobin,
+    /// Backends in turn, as many requests to each as its weight.
+    WeightedRoundRobin,
+    /// Backend with the fewest requests in flight.
+    LeastRequests,
+    /// Best of two random backends, by latency and requests in flight.
+    Ewma,
+    /// Random backend.
+    Random,
+}
+
+/// Chooses the backend serving a request among the available backends of a pool.
+pub trait Strategy: fmt::Debug + Send + Sync {
+    /// Returns the index of the chosen backend, or `None` if no backend is available.
+    fn select(&self, backends: &dyn Backends) -> Option<usize>;
+}
+
+/// The backends of a pool as seen by the strategies, including unavailable ones.
+pub trait Backends {
+    /// Returns the count of backends.
+    fn count(&self) -> usize;
+    /// Returns `true` unless a backend is marked down or ejected.
+    fn is_available(&self, index: usize) -> bool;
+    /// Returns the relative share of the requests a backend receives under weighted strategies.
+    fn weight(&self, index: usize) -> u32;
+    /// Returns the requests in flight to a backend and their latency.
+    fn stats(&self, index: usize) -> &BackendStats;
+}
+
+/// Returns the indices of the available backends.
+fn available(backends: &dyn Backends) -> impl Iterator<Item = usize> + '_ {
+    (0..backends.count()).filter(|index| backends.is_available(*index))
+}
+
+/// Requests in flight to a backend and their latency.
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    /// Requests waiting for the backend response.
+    active: AtomicUsize,
+    /// Moving average of the response latency in microseconds, as `f64` bits; zero until
+    /// the first response.
+    ewma: AtomicU64,
+}
+
+impl BackendStats {
+    /// Returns the count of requests waiting for the backend response.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Returns the moving average of the response latency, or zero before the first response.
+    pub fn latency(&self) -> Duration {
+        Duration::from_secs_f64(self.ewma_micros() / 1_000_000.0)
+    }
+
+    /// Counts a request in flight until the returned guard is dropped.
+    pub fn start(self: &Arc<Self>) -> InFlight {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        InFlight { stats: self.clone(), started: Instant::now() }
+    }
+
+    /// Adds the latency of a response to the moving average.
+    fn record(&self, latency: Duration) {
+        let sample = latency.as_secs_f64() * 1_000_000.0;
+        let _ = self.ewma.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            let average = f64::from_bits(bits);
+            let average = match average == 0.0 {
+                true => sample,
+                false => average + EWMA_WEIGHT * (sample - average),
+            };
+            Some(average.to_bits())
+        });
+    }
+
+    fn ewma_micros(&self) -> f64 {
+        f64::from_bits(self.ewma.load(Ordering::Relaxed))
+    }
+}
+
+/// A request in flight to a backend, counted in its `BackendStats` until dropped.
+#[derive(Debug)]
+pub struct InFlight {
+    stats: Arc<BackendStats>,
+    started: Instant,
+}
+
+impl InFlight {
+    /// Records the latency of the backend response, ending the request.
+    ///
+    /// Requests dropped without completing, such as failed ones, leave the latency as is.
+    pub fn completed(self) {
+        self.stats.record(self.started.elapsed());
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The strategies of a pool, holding the state of those that need one.
+#[derive(Debug, Default)]
+pub struct Strategies {
+    round_robin: RoundRobin,
+    weighted_round_robin: WeightedRoundRobin,
+}
+
+impl Strategies {
+    /// Returns the strategy implementing a kind of load balancing.
+    pub fn get(&self, load_balancing: LoadBalancing) -> &dyn Strategy {
+        match load_balancing {
+            LoadBalancing::RoundRobin => &self.round_robin,
+            LoadBalancing::WeightedRoundRobin => &self.weighted_round_robin,
+            LoadBalancing::LeastRequests => &LeastRequests,
+            LoadBalancing::Ewma => &PowerOfTwoChoices,
+            LoadBalancing::Random => &Random,
+        }
+    }
+}
+
+/// Sends requests to the backends in turn.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl Strategy for RoundRobin {
+    fn select(&self, backends: &dyn Backends) -> Option<usize> {
+        let count = available(backends).count();
+        if count == 0 {
+            return None;
+        }
+        available(backends).nth(self.next.fetch_add(1, Ordering::Relaxed) % count)
+    }
+}
+
+/// Sends requests to the backends in turn, as many in a row to each as its weight.
+///
+/// Backends all weighing zero are sent requests in turn.
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobin {
+    next: AtomicUsize,
+}
+
+impl Strategy for WeightedRoundRobin {
+    fn select(&self, backends: &dyn Backends) -> Option<usize> {
+        let (count, total) = available(backends)
+            .fold((0, 0), |(count, total), index| (count + 1, total + u64::from(backends.weight(index))));
+        if count == 0 {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed) as u64;
+        if total == 0 {
+            return available(backends).nth((next % count) as usize);
+        }
+
+        let mut position = next % total;
+        available(backends).find(|index| match position.checked_sub(u64::from(backends.weight(*index))) {
+            Some(rest) => {
+                position = rest;
+                false
+            }
+            None => true,
+        })
+    }
+}
+
+/// Sends requests to the backend with the fewest requests in flight, drawing one at
+/// random among ties.
+#[derive(Debug, Default)]
+pub struct LeastRequests;
+
+impl Strategy for LeastRequests {
+    fn select(&self, backends: &dyn Backends) -> Option<usize> {
+        let mut rng = rand::thread_rng();
+        let (mut best, mut fewest, mut ties) = (None, usize::MAX, 0);
+        for index in available(backends) {
+            let active = backends.stats(index).active();
+            if active < fewest {
+                (best, fewest, ties) = (Some(index), active, 1);
+            } else if active == fewest {
+                // Keeps each of the tied backends with the same probability
+                ties += 1;
+                if rng.gen_range(0..ties) == 0 {
+                    best = Some(index);
+                }
+            }
+        }
+        best
+    }
+}
+
+/// Draws two backends at random and sends the request to the one with the lowest latency
+/// times requests in flight.
+///
+/// Backends without a response yet count no latency, so that new backends are tried soon.
+#[derive(Debug, Default)]
+pub struct PowerOfTwoChoices;
+
+impl Strategy for PowerOfTwoChoices {
+    fn select(&self, backends: &dyn Backends) -> Option<usize> {
+        let count = available(backends).count();
+        if count <= 1 {
+            return available(backends).next();
+        }
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..count);
+        let second = (first + rng.gen_range(1..count)) % count;
+        let first = available(backends).nth(first)?;
+        let second = available(backends).nth(second)?;
+        let cost = |index: usize| {
+            let stats = backends.stats(index);
+            (stats.ewma_micros() + 1.0) * (stats.active() + 1) as f64
+        };
+        match cost(first) <= cost(second) {
+            true => Some(first),
+            false => Some(second),
+        }
+    }
+}
+
+/// Draws a backend at random.
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl Strategy for Random {
+    fn select(&self, backends: &dyn Backends) -> Option<usize> {
+        let count = available(backends).count();
+        if count == 0 {
+            return None;
+        }
+        available(backends).nth(rand::thread_rng().gen_range(0..count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUESTS: usize = 10_000;
+
+    /// Backends of the given weights and statistics, available unless their weight is `None`.
+    struct TestBackends<'a>(Vec<(Option<u32>, &'a BackendStats)>);
+
+    impl Backends for TestBackends<'_> {
+        fn count(&self) -> usize {
+            self.0.len()
+        }
+
+        fn is_available(&self, index: usize) -> bool {
+            self.0[index].0.is_some()
+        }
+
+        fn weight(&self, index: usize) -> u32 {
+            self.0[index].0.unwrap_or_default()
+        }
+
+        fn stats(&self, index: usize) -> &BackendStats {
+            self.0[index].1
+        }
+    }
+
+    /// Counts the requests a strategy sends to backends of the given weights and statistics.
+    fn distribution(strategy: &dyn Strategy, backends: &[(u32, &BackendStats)]) -> Vec<usize> {
+        let backends = TestBackends(backends.iter().map(|(weight, stats)| (Some(*weight), *stats)).collect());
+        let mut counts = vec![0; backends.count()];
+        for _ in 0..REQUESTS {
+            counts[strategy.select(&backends).unwrap()] += 1;
+        }
+        counts
+    }
+
+    /// Asserts that every count is within 10% of its expected share of the requests.
+    fn assert_shares(counts: &[usize], shares: &[f64]) {
+        for (count, share) in counts.iter().zip(shares) {
+            let expected = REQUESTS as f64 * share;
+            assert!((*count as f64 - expected).abs() <= expected * 0.1, "{:?} is not close to {:?}", counts, shares);
+        }
+    }
+
+    fn stats(active: usize, latency: Option<Duration>) -> BackendStats {
+        let stats = BackendStats::default();
+        stats.active.store(active, Ordering::Relaxed);
+        if let Some(latency) = latency {
+            stats.record(latency);
+        }
+        stats
+    }
+
+    #[test]
+    fn test_round_robin_distributes_evenly() {
+        let idle = BackendStats::default();
+        let counts = distribution(&RoundRobin::default(), &[(1, &idle), (5, &idle), (1, &idle), (1, &idle)]);
+        assert_eq!(counts, vec![REQUESTS / 4; 4]);
+    }
+
+    #[test]
+    fn test_strategies_skip_unavailable_backends() {
+        let idle = BackendStats::default();
+        let backends = TestBackends(vec![(None, &idle), (Some(1), &idle), (None, &idle), (Some(1), &idle)]);
+        let strategy = RoundRobin::default();
+        let selected: Vec<_> = (0..4).filter_map(|_| strategy.select(&backends)).collect();
+        assert_eq!(selected, vec![1, 3, 1, 3]);
+
+        let strategies = Strategies::default();
+        for load_balancing in [
+            LoadBalancing::RoundRobin,
+            LoadBalancing::WeightedRoundRobin,
+            LoadBalancing::LeastRequests,
+            LoadBalancing::Ewma,
+            LoadBalancing::Random,
+        ] {
+            let strategy = strategies.get(load_balancing);
+            assert!((0..100).all(|_| matches!(strategy.select(&backends), Some(1 | 3))));
+            assert_eq!(strategy.select(&TestBackends(vec![(None, &idle)])), None);
+        }
+    }
+
+    #[test]
+    fn test_weighted_round_robin_follows_weights() {
+        let idle = BackendStats::default();
+        let strategy = WeightedRoundRobin::default();
+        let backends = TestBackends(vec![(Some(3), &idle), (Some(0), &idle), (Some(1), &idle)]);
+        let selected: Vec<_> = (0..8).filter_map(|_| strategy.select(&backends)).collect();
+        assert_eq!(selected, vec![0, 0, 0, 2, 0, 0, 0, 2]);
+
+        let counts = distribution(&strategy, &[(2, &idle), (3, &idle), (5, &idle)]);
+        assert_eq!(counts, vec![2_000, 3_000, 5_000]);
+        let counts = distribution(&strategy, &[(0, &idle), (0, &idle)]);
+        assert_eq!(counts, vec![5_000, 5_000]);
+    }
+
+    #[test]
+    fn test_least_requests_avoids_busy_backends() {
+        let (idle, busy) = (stats(0, None), stats(3, None));
+        let counts = distribution(&LeastRequests, &[(1, &idle), (1, &busy), (1, &idle)]);
+        assert_eq!(counts[1], 0);
+        assert_shares(&counts, &[0.5, 0.0, 0.5]);
+
+        let (busy, busier) = (stats(2, None), stats(4, None));
+        assert_eq!(distribution(&LeastRequests, &[(1, &busier), (1, &busy)]), vec![0, REQUESTS]);
+    }
+
+    #[test]
+    fn test_power_of_two_choices_prefers_fast_backends() {
+        let fast = stats(0, Some(Duration::from_millis(5)));
+        let slow = stats(0, Some(Duration::from_millis(50)));
+        // The slow backend only wins when drawn twice, which the strategy never does
+        let counts = distribution(&PowerOfTwoChoices, &[(1, &fast), (1, &slow)]);
+        assert_eq!(counts, vec![REQUESTS, 0]);
+
+        // Among three backends, the slowest is never chosen and the fastest always is when drawn
+        let medium = stats(0, Some(Duration::from_millis(20)));
+        let counts = distribution(&PowerOfTwoChoices, &[(1, &fast), (1, &medium), (1, &slow)]);
+        assert_eq!(counts[2], 0);
+        assert_shares(&counts, &[2.0 / 3.0, 1.0 / 3.0, 0.0]);
+
+        // Requests in flight weigh on the choice as much as latency
+        let loaded = stats(20, Some(Duration::from_millis(5)));
+        let counts = distribution(&PowerOfTwoChoices, &[(1, &loaded), (1, &slow)]);
+        assert_eq!(counts, vec![0, REQUESTS]);
+    }
+
+    #[test]
+    fn test_random_distributes_evenly() {
+        let idle = BackendStats::default();
+        let counts = distribution(&Random, &[(1, &idle), (1, &idle), (1, &idle)]);
+        assert_shares(&counts, &[1.0 / 3.0; 3]);
+    }
+
+    #[test]
+    fn test_in_flight_requests_and_latency() {
+        let stats = Arc::new(BackendStats::default());
+        let first = stats.start();
+        let second = stats.start();
+        assert_eq!(stats.active(), 2);
+        drop(first);
+        second.completed();
+        assert_eq!(stats.active(), 0);
+        assert!(stats.latency() > Duration::ZERO);
+
+        let stats = BackendStats::default();
+        stats.record(Duration::from_millis(100));
+        stats.record(Duration::from_millis(200));
+        assert!((stats.latency().as_secs_f64() - 0.130).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,226 @@
+//! Upgrade module relaying upgraded connections, such as WebSockets, to backends.
+//!
+//! An upgrade request is forwarded with its `Connection: upgrade` and `Upgrade` headers.
+//! Once the backend answers `101 Switching Protocols`, the bytes of both connections are
+//! copied in each direction until either side closes or the connection stays idle for
+//! longer than the idle timeout. `UpgradeMetrics` counts the relayed connections and bytes.
+
+use reqwest::header::{HeaderMap, HeaderValue, CONNECTION, UPGRADE};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Idle time after which an upgraded connection is closed, when none is configured.
+pub const DEFAULT_UPGRADE_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Size of the buffers bytes are read into, in each direction.
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Returns the protocol a request asks to upgrade to, if it is one that can be relayed.
+///
+/// Upgrades to `h2c` are not relayed, as HTTP/2 is served directly; the `Upgrade` header
+/// is dropped from such requests.
+pub fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let connection_upgrade = headers.get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("upgrade"));
+    let protocol = headers.get(UPGRADE)?;
+    let h2c = protocol.to_str().is_ok_and(|value| value.trim().eq_ignore_ascii_case("h2c"));
+    (connection_upgrade && !h2c).then(|| protocol.clone())
+}
+
+/// Counters of the upgraded connections relayed by the proxy.
+#[derive(Debug, Default)]
+pub struct UpgradeMetrics {
+    /// Connections being relayed.
+    active: AtomicU64,
+    /// Connections relayed since startup.
+    total: AtomicU64,
+    /// Connections closed after reaching the idle timeout.
+    idle_timeouts: AtomicU64,
+    /// Bytes sent by clients to backends.
+    bytes_from_client: AtomicU64,
+    /// Bytes sent by backends to clients.
+    bytes_from_backend: AtomicU64,
+}
+
+/// A point-in-time copy of `UpgradeMetrics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UpgradeMetricsSnapshot {
+    pub active: u64,
+    pub total: u64,
+    pub idle_timeouts: u64,
+    pub bytes_from_client: u64,
+    pub bytes_from_backend: u64,
+}
+
+impl UpgradeMetrics {
+    /// Returns the current value of every counter.
+    pub fn snapshot(&self) -> UpgradeMetricsSnapshot {
+        UpgradeMetricsSnapshot {
+            active: self.active.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            idle_timeouts: self.idle_timeouts.load(Ordering::Relaxed),
+            bytes_from_client: self.bytes_from_client.load(Ordering::Relaxed),
+            bytes_from_backend: self.bytes_from_backend.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Why an upgraded connection was closed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseReason {
+    /// The backend closed the connection.
+    BackendClosed,
+    /// The client went away before the backend closed the connection.
+    ClientClosed,
+    /// No byte went through in either direction for the idle timeout.
+    IdleTimeout,
+    /// Reading from or writing to the backend failed.
+    BackendError,
+}
+
+/// Statistics of a single upgraded connection, reported when it closes.
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    pub bytes_from_client: u64,
+    pub bytes_from_backend: u64,
+    pub duration: Duration,
+    pub reason: CloseReason,
+}
+
+/// Relays an upgraded connection between a client and a backend.
+///
+/// # Parameters
+/// - `client`: The upgraded connection to the client.
+/// - `backend`: The upgraded connection to the backend.
+/// - `idle_timeout`: Time without traffic in either direction after which the connection is closed.
+/// - `metrics`: Counters updated as the connection is relayed.
+///
+/// # Returns
+/// The statistics of the connection, once it is closed.
+pub async fn tunnel<C, B>(client: C, backend: B, idle_timeout: Duration, metrics: Arc<UpgradeMetrics>) -> ConnectionStats
+where
+    C: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    metrics.active.fetch_add(1, Ordering::Relaxed);
+    metrics.total.fetch_add(1, Ordering::Relaxed);
+    let started = Instant::now();
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut backend_read, mut backend_write) = tokio::io::split(backend);
+    let mut client_buffer = vec![0; READ_BUFFER_SIZE];
+    let mut backend_buffer = vec![0; READ_BUFFER_SIZE];
+    let mut client_open = true;
+    let mut stats = ConnectionStats {
+        bytes_from_client: 0,
+        bytes_from_backend: 0,
+        duration: Duration::ZERO,
+        reason: CloseReason::BackendClosed,
+    };
+
+    stats.reason = loop {
+        tokio::select! {
+            read = client_read.read(&mut client_buffer), if client_open => match read {
+                Ok(read) if read > 0 => {
+                    if backend_write.write_all(&client_buffer[..read]).await.is_err() {
+                        break CloseReason::BackendError;
+                    }
+                    stats.bytes_from_client += read as u64;
+                    metrics.bytes_from_client.fetch_add(read as u64, Ordering::Relaxed);
+                }
+                // The client is done sending: let the backend finish its side
+                Ok(_) => {
+                    client_open = false;
+                    let _ = backend_write.shutdown().await;
+                }
+                Err(_) => break CloseReason::ClientClosed,
+            },
+            read = backend_read.read(&mut backend_buffer) => match read {
+                Ok(0) => break CloseReason::BackendClosed,
+                Ok(read) => {
+                    if client_write.write_all(&backend_buffer[..read]).await.is_err() {
+                        break CloseReason::ClientClosed;
+                    }
+                    stats.bytes_from_backend += read as u64;
+                    metrics.bytes_from_backend.fetch_add(read as u64, Ordering::Relaxed);
+                }
+                Err(_) => break CloseReason::BackendError,
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                metrics.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+                break CloseReason::IdleTimeout;
+            }
+        }
+    };
+    let _ = client_write.shutdown().await;
+
+    stats.duration = started.elapsed();
+    metrics.active.fetch_sub(1, Ordering::Relaxed);
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_protocol() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        assert_eq!(upgrade_protocol(&headers), Some(HeaderValue::from_static("websocket")));
+
+        headers.insert(UPGRADE, HeaderValue::from_static("h2c"));
+        assert_eq!(upgrade_protocol(&headers), None);
+
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+        assert_eq!(upgrade_protocol(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_relays_both_directions() {
+        let (client, mut client_peer) = tokio::io::duplex(1024);
+        let (backend, mut backend_peer) = tokio::io::duplex(1024);
+        let metrics = Arc::new(UpgradeMetrics::default());
+        let relay = tokio::spawn(tunnel(client, backend, Duration::from_secs(5), metrics.clone()));
+
+        client_peer.write_all(b"ping").await.unwrap();
+        client_peer.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        backend_peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+        backend_peer.write_all(b"pong!").await.unwrap();
+        drop(backend_peer);
+
+        let mut received = Vec::new();
+        client_peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"pong!");
+        let stats = relay.await.unwrap();
+        assert_eq!(stats.reason, CloseReason::BackendClosed);
+        assert_eq!((stats.bytes_from_client, stats.bytes_from_backend), (4, 5));
+        assert_eq!(metrics.snapshot(), UpgradeMetricsSnapshot {
+            active: 0,
+            total: 1,
+            idle_timeouts: 0,
+            bytes_from_client: 4,
+            bytes_from_backend: 5,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_closes_idle_connections() {
+        let (client, _client_peer) = tokio::io::duplex(1024);
+        let (backend, _backend_peer) = tokio::io::duplex(1024);
+        let metrics = Arc::new(UpgradeMetrics::default());
+
+        let stats = tunnel(client, backend, Duration::from_millis(20), metrics.clone()).await;
+        assert_eq!(stats.reason, CloseReason::IdleTimeout);
+        assert_eq!(metrics.snapshot().idle_timeouts, 1);
+        assert_eq!(metrics.snapshot().active, 0);
+    }
+}
@@ -1,7 +1,8 @@
-use actix_web::{web::Data, middleware::Logger, App, HttpResponse, HttpServer};
+pub mod sni;
+
 use std::sync::Arc;
 use rustls::ServerConfig;
-use rustls::pki_types::{CertificateDer as Certificate, PrivateKeyDer as PrivateKey};
+use sni::SniResolver;
 
 #[derive(Clone)]
 pub struct TlsConfig {
@@ -9,31 +10,17 @@ pub struct TlsConfig {
 }
 
 impl TlsConfig {
-    pub fn new(cert: Certificate<'static>, key: PrivateKey<'static>) -> Self {
-        let config = ServerConfig::builder()
+    /// Builds the configuration of the HTTPS listener, picking certificates by SNI and
+    /// offering HTTP/2 and HTTP/1.1.
+    pub fn new(resolver: Arc<SniResolver>) -> Self {
+        let mut config = ServerConfig::builder()
             .with_no_client_auth()
-            .with_single_cert(vec![cert], key)
-            .expect("TLS configuration failed");
+            .with_cert_resolver(resolver);
+        // Clients pick HTTP/2 or HTTP/1.1 through ALPN
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
         TlsConfig {
             config: Arc::new(config),
         }
     }
 }
-
-// Configuración del servidor HTTPS
-pub async fn configure_https(tls_config: Arc<TlsConfig>) -> std::io::Result<()> {
-    HttpServer::new(move || {
-        // No se especifica el tipo explícito de retorno
-        App::new()
-            .wrap(Logger::default()) // Añadir Logger como middleware
-            .app_data(Data::new(tls_config.clone()))
-            .route("/", actix_web::web::get().to(|| async {
-                HttpResponse::Ok().body("Hello, HTTPS!")
-            }))
-    })
-    .bind(("0.0.0.0", 8443))?
-    .run()
-    .await
-}
-
@@ -0,0 +1,289 @@
+//! SNI module selecting the certificate presented for each TLS handshake.
+//!
+//! The `SniResolver` struct keeps the certificates of the `kubernetes.io/tls` Secrets and
+//! the `spec.tls` entries of every Ingress, and picks the certificate for the hostname the
+//! client asked for: an exact host first, then a wildcard host (`*.example.com`), then an
+//! entry without hosts, and finally the certificate configured from files. Certificates are
+//! looked up on every handshake, so Secret and Ingress updates apply to new connections
+//! right away.
+
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::api::networking::v1::IngressTLS;
+use rustls::crypto::aws_lc_rs::sign::any_supported_type;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+
+/// Type of the Secrets holding a TLS certificate and its private key.
+pub const TLS_SECRET_TYPE: &str = "kubernetes.io/tls";
+
+/// A `spec.tls` entry of an Ingress.
+#[derive(Clone, Debug, PartialEq)]
+struct TlsEntry {
+    /// Hosts covered by the certificate, lowercased; empty to cover any host.
+    hosts: Vec<String>,
+    /// The Secret holding the certificate, as `namespace/name`.
+    secret: String,
+}
+
+/// Resolves the certificate to present for the hostname sent in the TLS client hello.
+#[derive(Debug, Default)]
+pub struct SniResolver {
+    /// Certificates of the `kubernetes.io/tls` Secrets, keyed by `namespace/name`.
+    secrets: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    /// The `spec.tls` entries of every Ingress, keyed by `namespace/name`.
+    ingresses: RwLock<BTreeMap<String, Vec<TlsEntry>>>,
+    /// Certificate presented when no Ingress covers the requested hostname.
+    fallback: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl SniResolver {
+    /// Creates a resolver without certificates.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the certificate presented when no Ingress covers the requested hostname.
+    pub fn set_fallback(&self, certs: Vec<CertificateDer<'static>>, key: &PrivateKeyDer<'static>) -> Result<(), Box<dyn Error>> {
+        let key = CertifiedKey::new(certs, any_supported_type(key)?);
+        *self.fallback.write().unwrap() = Some(Arc::new(key));
+        Ok(())
+    }
+
+    /// Records the certificate of an added or updated `kubernetes.io/tls` Secret.
+    ///
+    /// # Returns
+    /// An error if the Secret does not hold a valid certificate and key, in which case
+    /// any previous certificate of the Secret is forgotten.
+    pub fn apply_secret(&self, secret: &Secret) -> Result<(), Box<dyn Error>> {
+        let key = secret_key(secret);
+        match certified_key(secret) {
+            Ok(certified_key) => {
+                self.secrets.write().unwrap().insert(key, Arc::new(certified_key));
+                Ok(())
+            }
+            Err(e) => {
+                self.secrets.write().unwrap().remove(&key);
+                Err(e)
+            }
+        }
+    }
+
+    /// Forgets the certificate of a deleted Secret.
+    pub fn delete_secret(&self, secret: &Secret) {
+        self.secrets.write().unwrap().remove(&secret_key(secret));
+    }
+
+    /// Replaces every known Secret, e.g. after the watch re-listed them.
+    pub fn reset_secrets<'a>(&self, secrets: impl IntoIterator<Item = &'a Secret>) {
+        self.secrets.write().unwrap().clear();
+        for secret in secrets {
+            if let Err(e) = self.apply_secret(secret) {
+                eprintln!("Ignoring TLS secret {}: {}", secret_key(secret), e);
+            }
+        }
+    }
+
+    /// Returns `true` if a valid certificate is known for a Secret (`namespace/name`).
+    pub fn has_secret(&self, secret: &str) -> bool {
+        self.secrets.read().unwrap().contains_key(secret)
+    }
+
+    /// Replaces the `spec.tls` entries of an Ingress.
+    ///
+    /// # Parameters
+    /// - `ingress`: The Ingress, as `namespace/name`.
+    /// - `namespace`: Namespace of the Ingress, where its Secrets live.
+    /// - `tls`: Its `spec.tls` entries.
+    ///
+    /// # Returns
+    /// The `namespace/name` keys of the Secrets referenced by the entries.
+    pub fn set_ingress_tls(&self, ingress: &str, namespace: &str, tls: &[IngressTLS]) -> Vec<String> {
+        let entries: Vec<TlsEntry> = tls.iter()
+            .filter_map(|entry| {
+                let secret = entry.secret_name.as_ref().filter(|name| !name.is_empty())?;
+                Some(TlsEntry {
+                    hosts: entry.hosts.iter().flatten().map(|host| host.to_lowercase()).collect(),
+                    secret: format!("{}/{}", namespace, secret),
+                })
+            })
+            .collect();
+        let secrets = entries.iter().map(|entry| entry.secret.clone()).collect();
+
+        let mut ingresses = self.ingresses.write().unwrap();
+        if entries.is_empty() {
+            ingresses.remove(ingress);
+        } else {
+            ingresses.insert(ingress.to_string(), entries);
+        }
+        secrets
+    }
+
+    /// Forgets the `spec.tls` entries of an Ingress (`namespace/name`).
+    pub fn remove_ingress(&self, ingress: &str) {
+        self.ingresses.write().unwrap().remove(ingress);
+    }
+
+    /// Finds the certificate for a hostname, or the fallback one if no Ingress covers it.
+    ///
+    /// Among Ingresses covering the same hostname, the first one by `namespace/name` whose
+    /// Secret holds a valid certificate wins.
+    pub fn resolve_host(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let host = server_name.map(str::to_lowercase);
+        let wildcard = host.as_ref()
+            .and_then(|host| host.split_once('.'))
+            .map(|(_, domain)| format!("*.{}", domain));
+
+        let ingresses = self.ingresses.read().unwrap();
+        let secrets = self.secrets.read().unwrap();
+        let find = |covers: &dyn Fn(&TlsEntry) -> bool| {
+            ingresses.values()
+                .flatten()
+                .filter(|entry| covers(entry))
+                .find_map(|entry| secrets.get(&entry.secret).cloned())
+        };
+
+        host.as_ref().and_then(|host| find(&|entry| entry.hosts.contains(host)))
+            .or_else(|| wildcard.as_ref().and_then(|wildcard| find(&|entry| entry.hosts.contains(wildcard))))
+            .or_else(|| find(&|entry| entry.hosts.is_empty()))
+            .or_else(|| self.fallback.read().unwrap().clone())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certified_key = self.resolve_host(client_hello.server_name());
+        if certified_key.is_none() {
+            eprintln!("No certificate for TLS server name {:?}", client_hello.server_name());
+        }
+        certified_key
+    }
+}
+
+/// Returns the `namespace/name` key of a Secret.
+fn secret_key(secret: &Secret) -> String {
+    format!(
+        "{}/{}",
+        secret.metadata.namespace.clone().unwrap_or_default(),
+        secret.metadata.name.clone().unwrap_or_default()
+    )
+}
+
+/// Builds the certificate chain and signing key held by a `kubernetes.io/tls` Secret.
+fn certified_key(secret: &Secret) -> Result<CertifiedKey, Box<dyn Error>> {
+    if secret.type_.as_deref() != Some(TLS_SECRET_TYPE) {
+        return Err(format!("secret is not of type {}", TLS_SECRET_TYPE).into());
+    }
+    let data = secret.data.as_ref().ok_or("secret has no data")?;
+    let cert_pem = data.get("tls.crt").ok_or("secret has no tls.crt")?;
+    let key_pem = data.get("tls.key").ok_or("secret has no tls.key")?;
+
+    let certs = rustls_pemfile::certs(&mut cert_pem.0.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err("tls.crt holds no certificate".into());
+    }
+    let key = rustls_pemfile::private_key(&mut key_pem.0.as_slice())?.ok_or("tls.key holds no private key")?;
+
+    Ok(CertifiedKey::new(certs, any_supported_type(&key)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::ByteString;
+
+    /// Builds a TLS Secret with a self-signed certificate for a hostname.
+    fn secret(name: &str, host: &str) -> Secret {
+        let generated = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+        let mut secret = Secret {
+            type_: Some(TLS_SECRET_TYPE.to_string()),
+            data: Some(BTreeMap::from([
+                ("tls.crt".to_string(), ByteString(generated.cert.pem().into_bytes())),
+                ("tls.key".to_string(), ByteString(generated.key_pair.serialize_pem().into_bytes())),
+            ])),
+            ..Default::default()
+        };
+        secret.metadata.name = Some(name.to_string());
+        secret.metadata.namespace = Some("default".to_string());
+        secret
+    }
+
+    fn tls(hosts: &[&str], secret_name: &str) -> IngressTLS {
+        IngressTLS {
+            hosts: Some(hosts.iter().map(|host| host.to_string()).collect()),
+            secret_name: Some(secret_name.to_string()),
+        }
+    }
+
+    /// Returns the DER certificate presented for a hostname.
+    fn presented(resolver: &SniResolver, server_name: Option<&str>) -> Option<CertificateDer<'static>> {
+        resolver.resolve_host(server_name).map(|key| key.cert[0].clone())
+    }
+
+    fn cert_of(resolver: &SniResolver, secret: &str) -> Option<CertificateDer<'static>> {
+        resolver.secrets.read().unwrap().get(secret).map(|key| key.cert[0].clone())
+    }
+
+    #[test]
+    fn test_resolves_exact_then_wildcard_hosts() {
+        let resolver = SniResolver::new();
+        resolver.apply_secret(&secret("app", "app.example.com")).unwrap();
+        resolver.apply_secret(&secret("wildcard", "*.example.com")).unwrap();
+        resolver.set_ingress_tls("default/app", "default", &[tls(&["app.example.com"], "app")]);
+        let secrets = resolver.set_ingress_tls("default/all", "default", &[tls(&["*.example.com"], "wildcard")]);
+        assert_eq!(secrets, vec!["default/wildcard".to_string()]);
+
+        let app = cert_of(&resolver, "default/app");
+        let wildcard = cert_of(&resolver, "default/wildcard");
+        assert_eq!(presented(&resolver, Some("APP.example.com")), app);
+        assert_eq!(presented(&resolver, Some("api.example.com")), wildcard);
+        // Wildcards cover a single label
+        assert_eq!(presented(&resolver, Some("a.b.example.com")), None);
+        assert_eq!(presented(&resolver, None), None);
+    }
+
+    #[test]
+    fn test_falls_back_to_hostless_entries_and_fallback() {
+        let resolver = SniResolver::new();
+        let fallback = rcgen::generate_simple_self_signed(vec!["flusso".to_string()]).unwrap();
+        let fallback_key = PrivateKeyDer::try_from(fallback.key_pair.serialize_der()).unwrap();
+        resolver.set_fallback(vec![fallback.cert.der().clone()], &fallback_key).unwrap();
+        assert_eq!(presented(&resolver, Some("other.org")), Some(fallback.cert.der().clone()));
+
+        resolver.apply_secret(&secret("default-cert", "example.com")).unwrap();
+        resolver.set_ingress_tls("default/catch-all", "default", &[tls(&[], "default-cert")]);
+        assert_eq!(presented(&resolver, Some("other.org")), cert_of(&resolver, "default/default-cert"));
+
+        resolver.remove_ingress("default/catch-all");
+        assert_eq!(presented(&resolver, Some("other.org")), Some(fallback.cert.der().clone()));
+    }
+
+    #[test]
+    fn test_secret_updates_apply_live() {
+        let resolver = SniResolver::new();
+        resolver.set_ingress_tls("default/app", "default", &[tls(&["app.example.com"], "app")]);
+        assert!(!resolver.has_secret("default/app"));
+        assert_eq!(presented(&resolver, Some("app.example.com")), None);
+
+        let first = secret("app", "app.example.com");
+        resolver.apply_secret(&first).unwrap();
+        let first_cert = cert_of(&resolver, "default/app");
+        assert_eq!(presented(&resolver, Some("app.example.com")), first_cert);
+
+        // A renewed certificate replaces the previous one
+        resolver.apply_secret(&secret("app", "app.example.com")).unwrap();
+        assert_ne!(presented(&resolver, Some("app.example.com")), first_cert);
+
+        let mut invalid = secret("app", "app.example.com");
+        invalid.data.as_mut().unwrap().remove("tls.key");
+        assert!(resolver.apply_secret(&invalid).is_err());
+        assert_eq!(presented(&resolver, Some("app.example.com")), None);
+
+        resolver.apply_secret(&first).unwrap();
+        resolver.delete_secret(&first);
+        assert!(!resolver.has_secret("default/app"));
+    }
+}